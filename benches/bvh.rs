@@ -0,0 +1,105 @@
+//! Compares tracing rays through scenes of different sizes with and without a BVH.
+//! Run with `cargo bench --bench bvh`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use rust_workshop::bvh::Bvh;
+use rust_workshop::geom::{Ray, Sphere, Transform};
+use rust_workshop::material::Material;
+use rust_workshop::vec::Vec3;
+
+const SCENE_SIZES: [usize; 4] = [10, 100, 1000, 10000];
+const RAYS: usize = 1024;
+
+/// Scatter spheres through a cube using a fixed sequence, so every run traces the same scene
+fn spheres(count: usize) -> Vec<Sphere> {
+    let side = (count as f32).cbrt().ceil() as usize;
+    (0..count)
+        .map(|i| {
+            let (x, y, z) = (i % side, (i / side) % side, i / (side * side));
+            Sphere {
+                center: Vec3::new(x as f32, y as f32, 10.0 + z as f32),
+                radius: 0.2 + 0.2 * ((i * 7919) % 100) as f32 / 100.0,
+                material: Material::default(),
+                transform: Transform::default(),
+            }
+        })
+        .collect()
+}
+
+/// Rays fanning out from the origin towards the spheres
+fn rays(count: usize) -> Vec<Ray> {
+    (0..count)
+        .map(|i| {
+            let angle = i as f32 * 2.399_963;
+            let spread = (i as f32 / count as f32).sqrt();
+            let direction = Vec3::new(spread * angle.cos(), spread * angle.sin(), 1.0);
+            Ray {
+                origin: Vec3::new(0.0, 0.0, 0.0),
+                direction: direction.normalize(),
+            }
+        })
+        .collect()
+}
+
+fn closest_hit(c: &mut Criterion) {
+    let rays = rays(RAYS);
+    let mut group = c.benchmark_group("closest_hit");
+    for size in SCENE_SIZES {
+        let spheres = spheres(size);
+        let bvh = Bvh::new(&spheres);
+        group.bench_with_input(BenchmarkId::new("linear", size), &spheres, |b, spheres| {
+            b.iter(|| {
+                for ray in &rays {
+                    black_box(ray.closest_hit(spheres));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("bvh", size), &spheres, |b, spheres| {
+            b.iter(|| {
+                for ray in &rays {
+                    black_box(bvh.closest_hit(ray, spheres));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn hits_any(c: &mut Criterion) {
+    let rays = rays(RAYS);
+    let mut group = c.benchmark_group("hits_any");
+    for size in SCENE_SIZES {
+        let spheres = spheres(size);
+        let bvh = Bvh::new(&spheres);
+        group.bench_with_input(BenchmarkId::new("linear", size), &spheres, |b, spheres| {
+            b.iter(|| {
+                for ray in &rays {
+                    black_box(ray.hits_any(spheres, f32::INFINITY));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("bvh", size), &spheres, |b, spheres| {
+            b.iter(|| {
+                for ray in &rays {
+                    black_box(bvh.hits_any(ray, spheres, f32::INFINITY));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    for size in SCENE_SIZES {
+        let spheres = spheres(size);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &spheres, |b, spheres| {
+            b.iter(|| Bvh::new(spheres))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, closest_hit, hits_any, build);
+criterion_main!(benches);
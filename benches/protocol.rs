@@ -0,0 +1,94 @@
+//! Measures encoding and decoding the messages exchanged by `Connection::request`
+//! for a batch of rays, with and without compression.
+//! Run with `cargo bench --bench protocol`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rust_workshop::geom::Ray;
+use rust_workshop::protocol::{
+    encode_frame, read_message, FrameLimits, Framing, Outcome, Request, Response, Scene,
+};
+use rust_workshop::vec::Vec3;
+
+const FRAMINGS: [Framing; 2] = [Framing::Plain, Framing::Snappy];
+/// Batch sizes handed out by servers. 4096 is the default.
+const BATCH_SIZES: [usize; 3] = [1024, 4096, 16384];
+
+/// Rays from a camera at the origin through each pixel of a square image, like
+/// those the server hands out
+fn rays(count: usize) -> Vec<Ray> {
+    let side = (count as f32).sqrt().ceil() as usize;
+    (0..count)
+        .map(|i| {
+            let (x, y) = ((i % side) as f32, (i / side) as f32);
+            let direction = Vec3::new(x / side as f32 - 0.5, 0.5 - y / side as f32, 1.0);
+            Ray {
+                origin: Vec3::new(0.0, 0.0, 0.0),
+                direction: direction.normalize(),
+            }
+        })
+        .collect()
+}
+
+/// Results with a mix of hits and misses, and smoothly varying colours
+fn results(count: usize) -> Vec<Outcome> {
+    (0..count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let hit = (i / 37) % 3 != 0;
+            Outcome {
+                hit,
+                color: Some(if hit {
+                    Vec3::new(t, 0.5 * t, 1.0 - t)
+                } else {
+                    Vec3::new(0.0, 0.0, 0.0)
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Encode a message as it's sent, then decode it again as it's received
+fn round_trip<T: serde::Serialize, U: serde::de::DeserializeOwned>(
+    message: &T,
+    framing: Framing,
+    limits: &FrameLimits,
+) -> U {
+    let frame = encode_frame(message, framing).unwrap();
+    read_message(&mut &frame[..], framing, limits).unwrap()
+}
+
+fn reserve_rays(c: &mut Criterion) {
+    let limits = FrameLimits::default();
+    let mut group = c.benchmark_group("reserve_rays");
+    for size in BATCH_SIZES {
+        let response = Response::ReserveRays(rays(size), Scene::demo(0));
+        group.throughput(Throughput::Elements(size as u64));
+        for framing in FRAMINGS {
+            let id = BenchmarkId::new(format!("{:?}", framing), size);
+            group.bench_with_input(id, &response, |b, response| {
+                b.iter(|| black_box(round_trip::<_, Response>(response, framing, &limits)))
+            });
+        }
+    }
+    group.finish();
+}
+
+fn submit_results(c: &mut Criterion) {
+    let limits = FrameLimits::default();
+    let mut group = c.benchmark_group("submit_results");
+    for size in BATCH_SIZES {
+        let request = Request::SubmitResults(results(size));
+        group.throughput(Throughput::Elements(size as u64));
+        for framing in FRAMINGS {
+            let id = BenchmarkId::new(format!("{:?}", framing), size);
+            group.bench_with_input(id, &request, |b, request| {
+                b.iter(|| black_box(round_trip::<_, Request>(request, framing, &limits)))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reserve_rays, submit_results);
+criterion_main!(benches);
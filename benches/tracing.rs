@@ -0,0 +1,164 @@
+//! Measures the hot path of a worker: intersecting rays with spheres, shading
+//! them at different bounce depths, and tracing whole batches with rayon.
+//! Run with `cargo bench --bench tracing`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use rust_workshop::bvh::Bvh;
+use rust_workshop::geom::{Ray, Sphere, Transform};
+use rust_workshop::material::Material;
+use rust_workshop::protocol::{Outcome, Scene};
+use rust_workshop::shading::{compute_result, ShadingOpt};
+use rust_workshop::vec::Vec3;
+
+const RAYS: usize = 1024;
+const BOUNCES: [usize; 4] = [0, 1, 2, 4];
+/// Batch sizes handed out by servers. 4096 is the default.
+const BATCH_SIZES: [usize; 3] = [1024, 4096, 16384];
+
+/// Rays fanning out from behind the scene towards it
+fn rays(count: usize) -> Vec<Ray> {
+    (0..count)
+        .map(|i| {
+            let angle = i as f32 * 2.399_963;
+            let spread = 0.6 * (i as f32 / count as f32).sqrt();
+            let direction = Vec3::new(spread * angle.cos(), spread * angle.sin(), 1.0);
+            Ray {
+                origin: Vec3::new(0.0, 0.5, -1.0),
+                direction: direction.normalize(),
+            }
+        })
+        .collect()
+}
+
+/// A scene like the demo scene file, with every kind of material, so that
+/// extra bounces have something to do
+fn scene() -> Scene {
+    let sphere = |x, y, z, radius, material| Sphere {
+        center: Vec3::new(x, y, z),
+        radius,
+        material,
+        transform: Transform::default(),
+    };
+    Scene {
+        frame: 0,
+        spheres: vec![
+            sphere(-1.5, 0.0, 6.0, 1.0, Material::default()),
+            sphere(
+                0.0,
+                0.0,
+                7.0,
+                1.0,
+                Material {
+                    roughness: 0.2,
+                    ..Material::default()
+                },
+            ),
+            sphere(
+                0.6,
+                -0.6,
+                4.2,
+                0.4,
+                Material {
+                    reflectivity: 0.0,
+                    transparency: 0.9,
+                    ior: 1.5,
+                    ..Material::default()
+                },
+            ),
+            sphere(1.5, 0.0, 6.0, 1.0, Material::default()),
+            sphere(0.0, -1001.0, 6.0, 1000.0, Material::default()),
+        ],
+        shapes: Vec::new(),
+        meshes: Vec::new(),
+        lights: vec![
+            "directional:0.4,-0.8,0.45:1,1,1:0.7".parse().unwrap(),
+            "point:-3,3,3:1,0.9,0.7:12:0.5".parse().unwrap(),
+        ],
+    }
+}
+
+fn shading(bounces: usize) -> ShadingOpt {
+    ShadingOpt {
+        fg: vec![Vec3::new(1.0, 0.2, 0.2), Vec3::new(0.2, 0.2, 1.0)],
+        bg: Vec3::new(0.2, 0.3, 0.5),
+        lights: Vec::new(),
+        ambient: Vec3::new(0.15, 0.15, 0.15),
+        bounces,
+        shadow_samples: 16,
+    }
+}
+
+fn intersect_sphere(c: &mut Criterion) {
+    let rays = rays(RAYS);
+    let mut group = c.benchmark_group("intersect_sphere");
+    group.throughput(Throughput::Elements(RAYS as u64));
+    let cases = [
+        ("hit", Vec3::new(0.0, 0.5, 6.0), 3.0),
+        ("miss", Vec3::new(20.0, 0.0, 6.0), 1.0),
+        ("inside", Vec3::new(0.0, 0.0, 0.0), 5.0),
+    ];
+    for (name, center, radius) in cases {
+        let sphere = Sphere {
+            center,
+            radius,
+            material: Material::default(),
+            transform: Transform::default(),
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for ray in &rays {
+                    black_box(ray.intersect_sphere(&sphere));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn shade(c: &mut Criterion) {
+    let rays = rays(RAYS);
+    let scene = scene();
+    let bvh = Bvh::new(&scene.spheres);
+    let mut group = c.benchmark_group("compute_result");
+    group.throughput(Throughput::Elements(RAYS as u64));
+    for bounces in BOUNCES {
+        let opt = shading(bounces);
+        group.bench_with_input(BenchmarkId::new("bounces", bounces), &opt, |b, opt| {
+            b.iter(|| {
+                for &ray in &rays {
+                    black_box(compute_result(ray, &scene, &bvh, opt, opt.bounces));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Trace a batch the way workers do: build a BVH for the scene, then share it
+/// between rayon's threads
+fn trace_batch(rays: &[Ray], scene: &Scene, opt: &ShadingOpt) -> Vec<Outcome> {
+    let bvh = Bvh::new(&scene.spheres);
+    rays.par_iter()
+        .map(|&ray| compute_result(ray, scene, &bvh, opt, opt.bounces))
+        .collect()
+}
+
+fn batch(c: &mut Criterion) {
+    let scene = scene();
+    let opt = shading(1);
+    let mut group = c.benchmark_group("batch");
+    group.sample_size(20);
+    for size in BATCH_SIZES {
+        let rays = rays(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &rays, |b, rays| {
+            b.iter(|| trace_batch(rays, &scene, &opt))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, intersect_sphere, shade, batch);
+criterion_main!(benches);
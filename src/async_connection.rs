@@ -0,0 +1,300 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::client::unexpected;
+use crate::geom::Ray;
+use crate::protocol::{
+    encode_frame, Capabilities, Codec, FrameLimits, Framing, Hello, HelloResponse,
+    IncomingResponse, MeshCache, Outcome, ProtocolError, Request, Response, Scene, Session,
+    HANDSHAKE, HELLO_TIMEOUT, LEGACY_REFUSAL_WAIT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+/// A connection to a server, for workers embedded in async services.
+///
+/// Every request has a deadline, after which it fails with
+/// `ProtocolError::TimedOut`. Requests can also be cancelled by dropping their
+/// futures. Either way, the stream may be left part way through a message, in
+/// which case any further requests fail with `ProtocolError::Interrupted`,
+/// and the connection has to be replaced.
+pub struct AsyncConnection {
+    stream: TcpStream,
+    session: Session,
+    limits: FrameLimits,
+    timeout: Option<Duration>,
+    /// Set while a message is part way through being sent or received
+    interrupted: bool,
+    meshes: MeshCache,
+}
+
+/// Write a single message to the stream, using the same framing as `write_message`
+async fn write_frame<T: Serialize>(
+    stream: &mut TcpStream,
+    message: &T,
+    framing: Framing,
+) -> Result<(), ProtocolError> {
+    stream.write_all(&encode_frame(message, framing)?).await?;
+    Ok(())
+}
+
+/// Read a single message from the stream, using the same framing as `read_message`
+async fn read_frame<T: DeserializeOwned>(
+    stream: &mut TcpStream,
+    framing: Framing,
+    limits: &FrameLimits,
+) -> Result<T, ProtocolError> {
+    let size = stream.read_u32().await? as usize;
+    limits.check_frame_size(size, framing)?;
+
+    let mut data = Vec::new();
+    stream.take(size as u64).read_to_end(&mut data).await?;
+    if data.len() < size {
+        return Err(ProtocolError::Eof);
+    }
+    framing.decode(&data, limits.max_message_size)
+}
+
+/// Whether a server which predates the handshake closed the connection after
+/// we stated our version. See `LEGACY_REFUSAL_WAIT`.
+async fn refused(stream: &TcpStream) -> Result<bool, ProtocolError> {
+    match tokio::time::timeout(LEGACY_REFUSAL_WAIT, stream.peek(&mut [0])).await {
+        Ok(Ok(read)) => Ok(read == 0),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionReset => Ok(true),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Give up on a future if it doesn't finish within the timeout
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, ProtocolError>>,
+) -> Result<T, ProtocolError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| ProtocolError::TimedOut)?,
+        None => future.await,
+    }
+}
+
+impl AsyncConnection {
+    /// Connect to a server and agree on which version of the protocol to speak.
+    /// Servers which predate the handshake close the connection when they see
+    /// it, in which case we reconnect and state the newest version we speak,
+    /// falling back to older ones if the server closes the connection again.
+    /// Connecting to such a server therefore takes a reconnection per version
+    /// tried, and up to `LEGACY_REFUSAL_WAIT` for each to be accepted.
+    ///
+    /// The timeout applies to connecting, and to each request made afterwards.
+    /// Without one, the server still has `HELLO_TIMEOUT` to answer the handshake.
+    pub async fn connect(
+        addr: SocketAddr,
+        capabilities: &Capabilities,
+        limits: FrameLimits,
+        timeout: Option<Duration>,
+    ) -> Result<Self, ProtocolError> {
+        let open = || async {
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            Ok::<_, ProtocolError>(stream)
+        };
+
+        let handshake = async {
+            let mut stream = open().await?;
+            stream.write_u32(HANDSHAKE).await?;
+            let hello = Hello {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+                capabilities: capabilities.clone(),
+            };
+            let response = match write_frame(&mut stream, &hello, Framing::Plain).await {
+                Ok(()) => {
+                    let read = read_frame(&mut stream, Framing::Plain, &limits);
+                    within(Some(timeout.unwrap_or(HELLO_TIMEOUT)), read).await
+                }
+                Err(e) => Err(e),
+            };
+            match response {
+                Ok(HelloResponse::Accepted(session)) => Ok((stream, session)),
+                Ok(HelloResponse::Rejected(reason)) => Err(ProtocolError::Rejected(reason)),
+                Err(e) if e.is_disconnect() && !matches!(e, ProtocolError::TimedOut) => {
+                    for version in (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).rev() {
+                        let mut stream = open().await?;
+                        stream.write_u32(version).await?;
+                        if !refused(&stream).await? {
+                            return Ok((stream, Session::legacy(version)));
+                        }
+                    }
+                    Err(ProtocolError::VersionMismatch(MIN_PROTOCOL_VERSION))
+                }
+                Err(e) => Err(e),
+            }
+        };
+        let (stream, session) = within(timeout, handshake).await?;
+        Ok(Self {
+            stream,
+            session,
+            limits,
+            timeout,
+            interrupted: false,
+            meshes: MeshCache::default(),
+        })
+    }
+
+    /// What was agreed with the server during the handshake
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Send a request without waiting for the response. The server answers
+    /// requests in the order they were sent, so several can be in flight at once.
+    pub async fn send(&mut self, request: &Request) -> Result<(), ProtocolError> {
+        self.send_within(request, self.timeout).await
+    }
+
+    /// Like `send`, but with a deadline of its own
+    pub async fn send_within(
+        &mut self,
+        request: &Request,
+        timeout: Option<Duration>,
+    ) -> Result<(), ProtocolError> {
+        self.begin()?;
+        let framing = self.session.framing();
+        within(timeout, write_frame(&mut self.stream, request, framing)).await?;
+        self.interrupted = false;
+        Ok(())
+    }
+
+    /// Wait for the response to the oldest request which hasn't been answered yet
+    pub async fn receive(&mut self) -> Result<Response, ProtocolError> {
+        self.receive_within(self.timeout).await
+    }
+
+    /// Like `receive`, but with a deadline of its own
+    pub async fn receive_within(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Response, ProtocolError> {
+        self.begin()?;
+        let framing = self.session.framing();
+        let read = read_frame::<IncomingResponse>(&mut self.stream, framing, &self.limits);
+        let response = within(timeout, read).await?;
+        self.interrupted = false;
+        self.meshes.incoming(response)
+    }
+
+    fn begin(&mut self) -> Result<(), ProtocolError> {
+        if self.interrupted {
+            return Err(ProtocolError::Interrupted);
+        }
+        self.interrupted = true;
+        Ok(())
+    }
+
+    pub async fn request(&mut self, request: Request) -> Result<Response, ProtocolError> {
+        self.send(&request).await?;
+        self.receive().await
+    }
+
+    /// Tell the server what to call us on its leaderboard
+    pub async fn set_name(&mut self, name: String) -> Result<(), ProtocolError> {
+        match self.request(Request::SetName(name)).await? {
+            Response::SetName => Ok(()),
+            response => Err(unexpected("SetName", &response)),
+        }
+    }
+
+    /// Ask the server for a batch of rays to trace, and the scene to trace them through
+    pub async fn reserve_rays(&mut self) -> Result<(Vec<Ray>, Scene), ProtocolError> {
+        match self.request(Request::ReserveRays).await? {
+            Response::ReserveRays(rays, scene) => Ok((rays, scene)),
+            response => Err(unexpected("ReserveRays", &response)),
+        }
+    }
+
+    /// Hand the results for the oldest reserved batch back to the server
+    pub async fn submit_results(&mut self, results: Vec<Outcome>) -> Result<(), ProtocolError> {
+        match self.request(Request::SubmitResults(results)).await? {
+            Response::SubmitResults => Ok(()),
+            response => Err(unexpected("SubmitResults", &response)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use byteorder::{ReadBytesExt, BE};
+
+    use super::*;
+    use crate::protocol::{read_message, write_message};
+
+    /// Start a server which completes the handshake, then never answers
+    fn start_stalled_server() -> (SocketAddr, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), HANDSHAKE);
+            let limits = FrameLimits::default();
+            let hello: Hello = read_message(&mut stream, Framing::Plain, &limits).unwrap();
+            let session = hello.negotiate(&Capabilities::default()).unwrap();
+            let response = HelloResponse::Accepted(session);
+            write_message(&mut stream, &response, Framing::Plain).unwrap();
+            // Ignore everything until the worker gives up
+            std::io::copy(&mut stream, &mut std::io::sink()).unwrap();
+        });
+        (addr, server)
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn per_request_deadline() {
+        let (addr, server) = start_stalled_server();
+        block_on(async {
+            let mut connection =
+                AsyncConnection::connect(addr, &Capabilities::default(), Default::default(), None)
+                    .await
+                    .unwrap();
+            connection.send(&Request::ReserveRays).await.unwrap();
+            let result = connection
+                .receive_within(Some(Duration::from_millis(50)))
+                .await;
+            assert!(matches!(result, Err(ProtocolError::TimedOut)));
+        });
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn cancelled_requests_interrupt_the_connection() {
+        let (addr, server) = start_stalled_server();
+        block_on(async {
+            let mut connection =
+                AsyncConnection::connect(addr, &Capabilities::default(), Default::default(), None)
+                    .await
+                    .unwrap();
+            connection.send(&Request::ReserveRays).await.unwrap();
+            // Give up waiting, which drops the future part way through
+            let receive = connection.receive();
+            let cancelled = tokio::time::timeout(Duration::from_millis(50), receive).await;
+            assert!(cancelled.is_err());
+            let result = connection.reserve_rays().await;
+            assert!(matches!(result, Err(ProtocolError::Interrupted)));
+        });
+        server.join().unwrap();
+    }
+}
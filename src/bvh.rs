@@ -0,0 +1,607 @@
+use crate::geom::{Intersection, Ray, Sphere, Transform};
+use crate::vec::Vec3;
+
+/// Number of buckets sphere centers are sorted into along an axis when looking
+/// for the best place to split a node.
+const SAH_BUCKETS: usize = 12;
+
+/// Nodes with more spheres than this are always split, even if the surface
+/// area heuristic thinks testing them all would be cheaper.
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Cost of checking a ray against a node's bounding box, relative to checking
+/// it against a sphere.
+const TRAVERSAL_COST: f32 = 0.125;
+
+/// An axis-aligned bounding box
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// A box containing nothing, which grows to fit whatever is added to it
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn around_sphere(sphere: &Sphere) -> Self {
+        let extent = Vec3::new(sphere.radius, sphere.radius, sphere.radius);
+        Self {
+            min: sphere.center - extent,
+            max: sphere.center + extent,
+        }
+        .transformed(&sphere.transform)
+    }
+
+    /// A box containing this one once it's been transformed, which may be
+    /// larger than needed if the transform rotates it
+    pub fn transformed(&self, transform: &Transform) -> Self {
+        if transform.is_identity() {
+            return *self;
+        }
+        (0..8)
+            .map(|corner| {
+                let pick = |bit, min, max| if corner & bit == 0 { min } else { max };
+                Vec3::new(
+                    pick(1, self.min.x, self.max.x),
+                    pick(2, self.min.y, self.max.y),
+                    pick(4, self.min.z, self.max.z),
+                )
+            })
+            .fold(Self::empty(), |bounds, corner| {
+                bounds.grow(transform.to_scene().transform_point(corner))
+            })
+    }
+
+    /// The smallest box containing both boxes
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    /// The smallest box containing this box and a point
+    pub fn grow(&self, point: Vec3) -> Self {
+        Self {
+            min: self.min.min(&point),
+            max: self.max.max(&point),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.max - self.min;
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    /// Find how far along a ray it enters the box, if it does so before
+    /// `max_distance`. Rays starting inside the box enter it straight away.
+    /// `inverse_direction` is the reciprocal of each component of the ray's
+    /// direction, which can be shared between every box the ray is checked against.
+    fn entry_distance(&self, ray: &Ray, inverse_direction: Vec3, max_distance: f32) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = max_distance;
+        for axis in 0..3 {
+            let origin = ray.origin[axis];
+            let inverse = inverse_direction[axis];
+            let (min, max) = (self.min[axis], self.max[axis]);
+            // Rays parallel to the planes bounding the box along this axis never
+            // cross them, so either stay between them or miss the box entirely.
+            // This includes rays running along one of the planes, which would
+            // otherwise make NaNs below.
+            if inverse.is_infinite() {
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+            // Find where the ray crosses the two planes bounding the box along this axis
+            let a = (min - origin) * inverse;
+            let b = (max - origin) * inverse;
+            near = near.max(a.min(b));
+            far = far.min(a.max(b));
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum NodeKind {
+    /// Contains the spheres listed in `order[start..start + count]`
+    Leaf {
+        start: usize,
+        count: usize,
+    },
+    Split {
+        left: usize,
+        right: usize,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+/// A bounding volume hierarchy over a list of spheres, which lets a ray skip
+/// over groups of spheres it can't possibly hit. The hierarchy stores indices
+/// into the list rather than the spheres themselves, so the same list must be
+/// passed in when tracing rays.
+///
+/// Hierarchies can also be built over anything else with a bounding box, such
+/// as the triangles of a mesh, using `from_bounds`, `closest_hit_with` and
+/// `hits_any_with`.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    order: Vec<usize>,
+}
+
+impl Bvh {
+    /// Build a hierarchy using the surface area heuristic (SAH), which splits
+    /// nodes in whichever way minimises the expected cost of tracing a ray through them.
+    pub fn new(spheres: &[Sphere]) -> Self {
+        let bounds: Vec<_> = spheres.iter().map(Aabb::around_sphere).collect();
+        Self::from_bounds(&bounds)
+    }
+
+    /// Build a hierarchy over a list of objects, given the bounding box of each
+    pub fn from_bounds(bounds: &[Aabb]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(2 * bounds.len()),
+            order: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    /// Add nodes containing the spheres in `order[start..end]`. Nodes waiting
+    /// to be split are kept on a stack of our own rather than by recursing, so
+    /// lopsided hierarchies can't overflow the call stack.
+    fn build(&mut self, bounds: &[Aabb], start: usize, end: usize) {
+        let root = self.add_leaf(bounds, start, end);
+        let mut stack = vec![(root, start, end)];
+        while let Some((index, start, end)) = stack.pop() {
+            let node_bounds = self.nodes[index].bounds;
+            if let Some(middle) = self.partition(bounds, &node_bounds, start, end) {
+                let left = self.add_leaf(bounds, start, middle);
+                let right = self.add_leaf(bounds, middle, end);
+                self.nodes[index].kind = NodeKind::Split { left, right };
+                stack.extend([(right, middle, end), (left, start, middle)]);
+            }
+        }
+    }
+
+    /// Add a leaf containing the spheres in `order[start..end]`, which may be
+    /// split later. Returns the index of the new node.
+    fn add_leaf(&mut self, bounds: &[Aabb], start: usize, end: usize) -> usize {
+        let node_bounds = self.order[start..end]
+            .iter()
+            .fold(Aabb::empty(), |acc, &i| acc.union(&bounds[i]));
+        self.nodes.push(Node {
+            bounds: node_bounds,
+            kind: NodeKind::Leaf {
+                start,
+                count: end - start,
+            },
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Decide whether to split the spheres in `order[start..end]`, and if so
+    /// rearrange them so that each half is contiguous. Returns where the second
+    /// half starts.
+    fn partition(
+        &mut self,
+        bounds: &[Aabb],
+        node_bounds: &Aabb,
+        start: usize,
+        end: usize,
+    ) -> Option<usize> {
+        let count = end - start;
+        if count <= 1 {
+            return None;
+        }
+
+        // Split along whichever axis the sphere centers are most spread out on
+        let centroid_bounds = self.order[start..end]
+            .iter()
+            .fold(Aabb::empty(), |acc, &i| acc.grow(bounds[i].centroid()));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        let low = centroid_bounds.min[axis];
+        let width = extent[axis];
+        if width <= 0.0 {
+            // All the centers are in the same place, so there's no way to separate them
+            return None;
+        }
+        let bucket = |i: usize| {
+            let offset = (bounds[i].centroid()[axis] - low) / width;
+            ((offset * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+        };
+
+        let mut bucket_bounds = [Aabb::empty(); SAH_BUCKETS];
+        let mut bucket_counts = [0; SAH_BUCKETS];
+        for &i in &self.order[start..end] {
+            let b = bucket(i);
+            bucket_bounds[b] = bucket_bounds[b].union(&bounds[i]);
+            bucket_counts[b] += 1;
+        }
+
+        // Estimate the cost of splitting between each pair of buckets. The chance of
+        // a ray which hits the node also hitting one of its children is proportional
+        // to the child's surface area.
+        let (split, cost) = (1..SAH_BUCKETS)
+            .filter_map(|split| {
+                let (left_bounds, left_count) = bucket_bounds[..split]
+                    .iter()
+                    .zip(&bucket_counts[..split])
+                    .fold((Aabb::empty(), 0), |(b, c), (bounds, count)| {
+                        (b.union(bounds), c + count)
+                    });
+                let (right_bounds, right_count) = bucket_bounds[split..]
+                    .iter()
+                    .zip(&bucket_counts[split..])
+                    .fold((Aabb::empty(), 0), |(b, c), (bounds, count)| {
+                        (b.union(bounds), c + count)
+                    });
+                if left_count == 0 || right_count == 0 {
+                    return None;
+                }
+                let cost = TRAVERSAL_COST
+                    + (left_bounds.surface_area() * left_count as f32
+                        + right_bounds.surface_area() * right_count as f32)
+                        / node_bounds.surface_area();
+                Some((split, cost))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        if count <= MAX_LEAF_SIZE && cost >= count as f32 {
+            return None;
+        }
+
+        let order = &mut self.order[start..end];
+        let mut middle = 0;
+        for i in 0..order.len() {
+            if bucket(order[i]) < split {
+                order.swap(i, middle);
+                middle += 1;
+            }
+        }
+        Some(start + middle)
+    }
+
+    /// Find the closest sphere hit by the ray, along with its index in `spheres`.
+    /// Gives the same result as `Ray::closest_hit`.
+    pub fn closest_hit(&self, ray: &Ray, spheres: &[Sphere]) -> Option<(usize, Intersection)> {
+        self.closest_hit_with(ray, |i| ray.intersect_sphere(&spheres[i]))
+    }
+
+    /// Find the closest object hit by the ray, along with its index, where
+    /// `intersect` finds where the ray hits the object with a given index.
+    pub fn closest_hit_with(
+        &self,
+        ray: &Ray,
+        intersect: impl Fn(usize) -> Option<Intersection>,
+    ) -> Option<(usize, Intersection)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inverse_direction = inverse(ray.direction);
+        let mut closest: Option<(usize, Intersection)> = None;
+        let mut stack = vec![(0, 0.0)];
+        while let Some((index, entry)) = stack.pop() {
+            let max_distance = closest
+                .as_ref()
+                .map_or(f32::INFINITY, |(_, intersection)| intersection.distance);
+            if entry > max_distance {
+                continue;
+            }
+            match self.nodes[index].kind {
+                NodeKind::Leaf { start, count } => {
+                    for &i in &self.order[start..start + count] {
+                        let Some(intersection) = intersect(i) else {
+                            continue;
+                        };
+                        // Break ties the same way as a linear scan, by picking the first object
+                        let closer = match &closest {
+                            Some((j, best)) => {
+                                intersection.distance < best.distance
+                                    || (intersection.distance == best.distance && i < *j)
+                            }
+                            None => true,
+                        };
+                        if closer {
+                            closest = Some((i, intersection));
+                        }
+                    }
+                }
+                NodeKind::Split { left, right } => {
+                    let hit = |child: usize| {
+                        self.nodes[child]
+                            .bounds
+                            .entry_distance(ray, inverse_direction, max_distance)
+                            .map(|entry| (child, entry))
+                    };
+                    // Visit the nearest child first, since anything it hits can rule
+                    // out most of the other one.
+                    match (hit(left), hit(right)) {
+                        (Some(a), Some(b)) if a.1 <= b.1 => stack.extend([b, a]),
+                        (Some(a), Some(b)) => stack.extend([a, b]),
+                        (Some(a), None) | (None, Some(a)) => stack.push(a),
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Check whether the ray hits any of the spheres before travelling
+    /// `max_distance`. Gives the same result as `Ray::hits_any`.
+    pub fn hits_any(&self, ray: &Ray, spheres: &[Sphere], max_distance: f32) -> bool {
+        self.hits_any_with(ray, max_distance, |i| ray.intersect_sphere(&spheres[i]))
+    }
+
+    /// Check whether the ray hits any object before travelling `max_distance`,
+    /// where `intersect` is as for `closest_hit_with`
+    pub fn hits_any_with(
+        &self,
+        ray: &Ray,
+        max_distance: f32,
+        intersect: impl Fn(usize) -> Option<Intersection>,
+    ) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let inverse_direction = inverse(ray.direction);
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node
+                .bounds
+                .entry_distance(ray, inverse_direction, max_distance)
+                .is_none()
+            {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    let hit = self.order[start..start + count].iter().any(|&i| {
+                        intersect(i)
+                            .is_some_and(|intersection| intersection.distance < max_distance)
+                    });
+                    if hit {
+                        return true;
+                    }
+                }
+                NodeKind::Split { left, right } => stack.extend([left, right]),
+            }
+        }
+        false
+    }
+}
+
+fn inverse(direction: Vec3) -> Vec3 {
+    Vec3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+
+    /// A simple pseudo-random number generator, so that tests are repeatable
+    struct Lcg(u64);
+
+    impl Lcg {
+        /// A number between 0 and 1
+        fn next(&mut self) -> f32 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        fn vec(&mut self, scale: f32) -> Vec3 {
+            Vec3::new(
+                scale * (self.next() - 0.5),
+                scale * (self.next() - 0.5),
+                scale * (self.next() - 0.5),
+            )
+        }
+    }
+
+    fn random_spheres(rng: &mut Lcg, count: usize) -> Vec<Sphere> {
+        (0..count)
+            .map(|_| Sphere {
+                center: rng.vec(20.0),
+                radius: 0.1 + rng.next(),
+                material: Material::default(),
+                transform: Transform::default(),
+            })
+            .collect()
+    }
+
+    fn random_rays(rng: &mut Lcg, count: usize) -> impl Iterator<Item = Ray> + '_ {
+        (0..count).map(move |_| {
+            let direction = rng.vec(1.0);
+            Ray {
+                origin: rng.vec(30.0),
+                direction: direction.normalize(),
+            }
+        })
+    }
+
+    #[test]
+    fn matches_linear_scan() {
+        let mut rng = Lcg(1);
+        let spheres = random_spheres(&mut rng, 200);
+        let bvh = Bvh::new(&spheres);
+        let mut hits = 0;
+        for ray in random_rays(&mut rng, 2000) {
+            let expected = ray.closest_hit(&spheres);
+            let actual = bvh.closest_hit(&ray, &spheres);
+            assert_eq!(
+                expected.as_ref().map(|(i, hit)| (*i, hit.distance)),
+                actual.as_ref().map(|(i, hit)| (*i, hit.distance)),
+            );
+            hits += expected.is_some() as usize;
+
+            for max_distance in [1.0, 10.0, f32::INFINITY] {
+                assert_eq!(
+                    ray.hits_any(&spheres, max_distance),
+                    bvh.hits_any(&ray, &spheres, max_distance)
+                );
+            }
+        }
+        // Make sure the test is actually checking some hits
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn transformed_spheres_match_linear_scan() {
+        let mut rng = Lcg(4);
+        let spheres: Vec<Sphere> = random_spheres(&mut rng, 100)
+            .into_iter()
+            .map(|sphere| Sphere {
+                transform: Transform::new(
+                    rng.vec(10.0),
+                    rng.vec(360.0),
+                    rng.vec(2.0) + Vec3::new(1.2, 1.2, 1.2),
+                )
+                .unwrap(),
+                ..sphere
+            })
+            .collect();
+        let bvh = Bvh::new(&spheres);
+        let mut hits = 0;
+        for ray in random_rays(&mut rng, 2000) {
+            let expected = ray.closest_hit(&spheres);
+            let actual = bvh.closest_hit(&ray, &spheres);
+            assert_eq!(
+                expected.as_ref().map(|(i, hit)| (*i, hit.distance)),
+                actual.as_ref().map(|(i, hit)| (*i, hit.distance)),
+            );
+            hits += expected.is_some() as usize;
+            assert_eq!(
+                ray.hits_any(&spheres, 10.0),
+                bvh.hits_any(&ray, &spheres, 10.0)
+            );
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn empty() {
+        let bvh = Bvh::new(&[]);
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 0.0, 1.0),
+        };
+        assert!(bvh.closest_hit(&ray, &[]).is_none());
+        assert!(!bvh.hits_any(&ray, &[], f32::INFINITY));
+    }
+
+    #[test]
+    fn ray_along_a_face() {
+        let aabb = Aabb {
+            min: Vec3::new(0.0, 0.0, 0.0),
+            max: Vec3::new(1.0, 1.0, 1.0),
+        };
+        let along = |origin| Ray {
+            origin,
+            direction: Vec3::new(1.0, 0.0, 0.0),
+        };
+        let ray = along(Vec3::new(-1.0, 1.0, 0.5));
+        assert_eq!(
+            aabb.entry_distance(&ray, inverse(ray.direction), 10.0),
+            Some(1.0)
+        );
+        let ray = along(Vec3::new(-1.0, 1.5, 0.5));
+        assert_eq!(
+            aabb.entry_distance(&ray, inverse(ray.direction), 10.0),
+            None
+        );
+    }
+
+    #[test]
+    fn ray_inside_sphere() {
+        let mut rng = Lcg(2);
+        let mut spheres = random_spheres(&mut rng, 50);
+        spheres.push(Sphere {
+            center: Vec3::new(0.0, 0.0, 0.0),
+            radius: 100.0,
+            material: Material::default(),
+            transform: Transform::default(),
+        });
+        let bvh = Bvh::new(&spheres);
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 1.0, 0.0),
+        };
+        let (index, hit) = bvh.closest_hit(&ray, &spheres).unwrap();
+        let (expected_index, expected) = ray.closest_hit(&spheres).unwrap();
+        assert_eq!(index, expected_index);
+        assert_eq!(hit.distance, expected.distance);
+    }
+
+    #[test]
+    fn spheres_in_the_same_place() {
+        let sphere = Sphere {
+            center: Vec3::new(0.0, 0.0, 5.0),
+            radius: 1.0,
+            material: Material::default(),
+            transform: Transform::default(),
+        };
+        let spheres = vec![sphere; 20];
+        let bvh = Bvh::new(&spheres);
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 0.0, 1.0),
+        };
+        let (index, hit) = bvh.closest_hit(&ray, &spheres).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(hit.distance, 4.0);
+    }
+
+    #[test]
+    fn splits_large_scenes() {
+        let mut rng = Lcg(3);
+        let spheres = random_spheres(&mut rng, 1000);
+        let bvh = Bvh::new(&spheres);
+        let mut order = bvh.order.clone();
+        order.sort_unstable();
+        assert_eq!(order, (0..1000).collect::<Vec<_>>());
+        for node in &bvh.nodes {
+            if let NodeKind::Leaf { count, .. } = node.kind {
+                assert!(count <= MAX_LEAF_SIZE);
+            }
+        }
+    }
+}
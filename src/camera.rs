@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+use rust_workshop::geom::Ray;
+use rust_workshop::vec::{Mat4, Vec3};
+
+/// A pinhole camera positioned at `position` and looking towards `target`.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Camera {
+    pub position: Vec3,
+    pub target: Vec3,
+    /// Which way is "up" for the camera. This does not need to be exactly
+    /// perpendicular to the viewing direction.
+    pub up: Vec3,
+    /// Vertical field of view, in degrees
+    pub fov: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            position: Vec3::new(0.0, 0.0, 0.0),
+            target: Vec3::new(0.0, 0.0, 1.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            fov: 60.0,
+        }
+    }
+}
+
+impl Camera {
+    /// Compute a ray for every pixel of an image, in row-major order.
+    ///
+    /// Panics if `up` is parallel to the viewing direction, which scene files
+    /// don't allow.
+    pub fn rays(&self, width: u32, height: u32) -> Vec<Ray> {
+        // Directions are worked out from the camera's point of view, where it
+        // looks along the z axis, then turned to face the target
+        let to_scene = Mat4::look_at(self.position, self.target, self.up)
+            .expect("the camera needs a viewing direction not parallel to up");
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| self.ray(&to_scene, x, y, width, height))
+            .collect()
+    }
+
+    /// Compute the ray passing through the center of pixel (x, y) of an image
+    /// with the given dimensions. Pixel (0, 0) is the top-left of the image.
+    fn ray(&self, to_scene: &Mat4, x: u32, y: u32, width: u32, height: u32) -> Ray {
+        let half_height = (self.fov.to_radians() / 2.0).tan();
+        let half_width = half_height * width as f32 / height as f32;
+
+        // Map the pixel center into the range -1..1 in both directions
+        let u = 2.0 * (x as f32 + 0.5) / width as f32 - 1.0;
+        let v = 1.0 - 2.0 * (y as f32 + 0.5) / height as f32;
+
+        let direction = to_scene.transform_vector(Vec3::new(u * half_width, v * half_height, 1.0));
+        Ray {
+            origin: self.position,
+            direction: direction.normalize(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn center_ray() {
+        let ray = Camera::default().rays(3, 3)[4];
+        assert_eq!(ray.direction, Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn rays_are_unit_length() {
+        for ray in Camera::default().rays(4, 3) {
+            assert!((ray.direction.length() - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn top_left_points_up_and_left() {
+        let ray = Camera::default().rays(4, 3)[0];
+        assert!(ray.direction.x < 0.0);
+        assert!(ray.direction.y > 0.0);
+    }
+
+    #[test]
+    fn looks_at_target() {
+        let camera = Camera {
+            position: Vec3::new(1.0, 2.0, 3.0),
+            target: Vec3::new(1.0, 2.0, -7.0),
+            ..Camera::default()
+        };
+        let ray = camera.rays(3, 3)[4];
+        assert_eq!(ray.origin, camera.position);
+        assert_eq!(ray.direction, Vec3::new(0.0, 0.0, -1.0));
+    }
+}
@@ -0,0 +1,259 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::geom::Ray;
+use crate::protocol::{
+    Capabilities, FrameLimits, Outcome, ProtocolError, Request, Response, Scene, Session,
+};
+use crate::recording::Recorder;
+
+#[cfg(not(feature = "async"))]
+use {
+    crate::protocol::{
+        read_message, write_message, Framing, Hello, HelloResponse, IncomingResponse, MeshCache,
+        HANDSHAKE, HELLO_TIMEOUT, LEGACY_REFUSAL_WAIT, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    byteorder::{WriteBytesExt, BE},
+    std::{io::ErrorKind, net::TcpStream},
+};
+
+#[cfg(feature = "async")]
+use {crate::async_connection::AsyncConnection, tokio::runtime::Runtime};
+
+/// A blocking connection to a server
+#[cfg(not(feature = "async"))]
+pub struct Connection {
+    stream: TcpStream,
+    session: Session,
+    limits: FrameLimits,
+    /// Set while a message is part way through being sent or received. If that
+    /// fails, the stream is left in the middle of a frame and can't be used again.
+    interrupted: bool,
+    meshes: MeshCache,
+    recorder: Option<Recorder>,
+}
+
+#[cfg(not(feature = "async"))]
+impl Connection {
+    /// Connect to a server and agree on which version of the protocol to speak.
+    /// Servers which predate the handshake close the connection when they see
+    /// it, in which case we reconnect and state the newest version we speak,
+    /// falling back to older ones if the server closes the connection again.
+    /// Connecting to such a server therefore takes a reconnection per version
+    /// tried, and up to `LEGACY_REFUSAL_WAIT` for each to be accepted.
+    ///
+    /// If a timeout is given, any read or write which takes longer than that
+    /// fails, rather than letting a stalled server hang us forever. Without
+    /// one, the server still has `HELLO_TIMEOUT` to answer the handshake.
+    pub fn connect(
+        addr: SocketAddr,
+        capabilities: &Capabilities,
+        limits: FrameLimits,
+        timeout: Option<Duration>,
+    ) -> Result<Self, ProtocolError> {
+        let open = || -> Result<TcpStream, ProtocolError> {
+            let stream = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+                None => TcpStream::connect(addr)?,
+            };
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+            Ok(stream)
+        };
+
+        let mut stream = open()?;
+        stream.write_u32::<BE>(HANDSHAKE)?;
+        let hello = Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: capabilities.clone(),
+        };
+        stream.set_read_timeout(Some(timeout.unwrap_or(HELLO_TIMEOUT)))?;
+        let response = write_message(&mut stream, &hello, Framing::Plain)
+            .and_then(|_| read_message(&mut stream, Framing::Plain, &limits));
+        stream.set_read_timeout(timeout)?;
+        let (stream, session) = match response {
+            Ok(HelloResponse::Accepted(session)) => (stream, session),
+            Ok(HelloResponse::Rejected(reason)) => return Err(ProtocolError::Rejected(reason)),
+            Err(e) if e.is_disconnect() && !matches!(e, ProtocolError::TimedOut) => {
+                let mut legacy = None;
+                for version in (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).rev() {
+                    let mut stream = open()?;
+                    stream.write_u32::<BE>(version)?;
+                    if !refused(&stream, timeout)? {
+                        legacy = Some((stream, Session::legacy(version)));
+                        break;
+                    }
+                }
+                legacy.ok_or(ProtocolError::VersionMismatch(MIN_PROTOCOL_VERSION))?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            stream,
+            session,
+            limits,
+            interrupted: false,
+            meshes: MeshCache::default(),
+            recorder: None,
+        })
+    }
+
+    /// What was agreed with the server during the handshake
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    fn send_message(&mut self, request: &Request) -> Result<(), ProtocolError> {
+        self.begin()?;
+        write_message(&mut self.stream, request, self.session.framing())?;
+        self.interrupted = false;
+        Ok(())
+    }
+
+    fn receive_message(&mut self) -> Result<Response, ProtocolError> {
+        self.begin()?;
+        let response: IncomingResponse =
+            read_message(&mut self.stream, self.session.framing(), &self.limits)?;
+        self.interrupted = false;
+        self.meshes.incoming(response)
+    }
+
+    fn begin(&mut self) -> Result<(), ProtocolError> {
+        if self.interrupted {
+            return Err(ProtocolError::Interrupted);
+        }
+        self.interrupted = true;
+        Ok(())
+    }
+}
+
+/// Whether a server which predates the handshake closed the connection after
+/// we stated our version. See `LEGACY_REFUSAL_WAIT`.
+#[cfg(not(feature = "async"))]
+fn refused(stream: &TcpStream, timeout: Option<Duration>) -> Result<bool, ProtocolError> {
+    let wait = timeout.map_or(LEGACY_REFUSAL_WAIT, |t| t.min(LEGACY_REFUSAL_WAIT));
+    stream.set_read_timeout(Some(wait))?;
+    let refused = match stream.peek(&mut [0]) {
+        Ok(read) => read == 0,
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
+        Err(e) if e.kind() == ErrorKind::ConnectionReset => true,
+        Err(e) => return Err(e.into()),
+    };
+    stream.set_read_timeout(timeout)?;
+    Ok(refused)
+}
+
+/// A blocking connection to a server, which drives an `AsyncConnection` on a
+/// runtime of its own
+#[cfg(feature = "async")]
+pub struct Connection {
+    runtime: Runtime,
+    inner: AsyncConnection,
+    recorder: Option<Recorder>,
+}
+
+#[cfg(feature = "async")]
+impl Connection {
+    /// Connect to a server and agree on which version of the protocol to speak.
+    /// See `AsyncConnection::connect`.
+    pub fn connect(
+        addr: SocketAddr,
+        capabilities: &Capabilities,
+        limits: FrameLimits,
+        timeout: Option<Duration>,
+    ) -> Result<Self, ProtocolError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let inner = runtime.block_on(AsyncConnection::connect(
+            addr,
+            capabilities,
+            limits,
+            timeout,
+        ))?;
+        Ok(Self {
+            runtime,
+            inner,
+            recorder: None,
+        })
+    }
+
+    /// What was agreed with the server during the handshake
+    pub fn session(&self) -> &Session {
+        self.inner.session()
+    }
+
+    fn send_message(&mut self, request: &Request) -> Result<(), ProtocolError> {
+        self.runtime.block_on(self.inner.send(request))
+    }
+
+    fn receive_message(&mut self) -> Result<Response, ProtocolError> {
+        self.runtime.block_on(self.inner.receive())
+    }
+}
+
+impl Connection {
+    /// Record every message sent or received from now on
+    pub fn record(&mut self, recorder: Recorder) -> Result<(), ProtocolError> {
+        recorder.connected(self.session())?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Send a request without waiting for the response. The server answers
+    /// requests in the order they were sent, so several can be in flight at once.
+    pub fn send(&mut self, request: &Request) -> Result<(), ProtocolError> {
+        self.send_message(request)?;
+        if let Some(recorder) = &self.recorder {
+            recorder.sent(request)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the response to the oldest request which hasn't been answered yet
+    pub fn receive(&mut self) -> Result<Response, ProtocolError> {
+        let response = self.receive_message()?;
+        if let Some(recorder) = &self.recorder {
+            recorder.received(&response)?;
+        }
+        Ok(response)
+    }
+
+    pub fn request(&mut self, request: Request) -> Result<Response, ProtocolError> {
+        self.send(&request)?;
+        self.receive()
+    }
+
+    /// Tell the server what to call us on its leaderboard
+    pub fn set_name(&mut self, name: String) -> Result<(), ProtocolError> {
+        match self.request(Request::SetName(name))? {
+            Response::SetName => Ok(()),
+            response => Err(unexpected("SetName", &response)),
+        }
+    }
+
+    /// Ask the server for a batch of rays to trace, and the scene to trace them through
+    pub fn reserve_rays(&mut self) -> Result<(Vec<Ray>, Scene), ProtocolError> {
+        match self.request(Request::ReserveRays)? {
+            Response::ReserveRays(rays, scene) => Ok((rays, scene)),
+            response => Err(unexpected("ReserveRays", &response)),
+        }
+    }
+
+    /// Hand the results for the oldest reserved batch back to the server
+    pub fn submit_results(&mut self, results: Vec<Outcome>) -> Result<(), ProtocolError> {
+        match self.request(Request::SubmitResults(results))? {
+            Response::SubmitResults => Ok(()),
+            response => Err(unexpected("SubmitResults", &response)),
+        }
+    }
+}
+
+pub fn unexpected(expected: &'static str, response: &Response) -> ProtocolError {
+    ProtocolError::UnexpectedResponse {
+        expected,
+        received: response.name(),
+    }
+}
@@ -1,14 +1,317 @@
-use serde::{Deserialize, Serialize};
+use ordered_float::NotNan;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::Vec3;
+use crate::material::Material;
+use crate::vec::{Mat4, Vec3};
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+/// Where an object sits in the scene, relative to its own coordinates. The
+/// object is scaled along each axis, then rotated about the x, y and z axes in
+/// turn, and then moved by the translation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    translation: Vec3,
+    /// Angles in degrees
+    rotation: Vec3,
+    scale: Vec3,
+    /// Takes points in the object's coordinates to the scene's
+    to_scene: Mat4,
+    /// Takes points in the scene's coordinates to the object's
+    to_object: Mat4,
+}
+
+/// The layout of a transform in every format
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Transform", default, deny_unknown_fields)]
+struct TransformDescription {
+    translation: Vec3,
+    rotation: Vec3,
+    scale: Vec3,
+}
+
+impl Default for TransformDescription {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::new(0.0, 0.0, 0.0),
+            rotation: Vec3::new(0.0, 0.0, 0.0),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Default for Transform {
+    /// Leaves objects where they are
+    fn default() -> Self {
+        let description = TransformDescription::default();
+        Self {
+            translation: description.translation,
+            rotation: description.rotation,
+            scale: description.scale,
+            to_scene: Mat4::IDENTITY,
+            to_object: Mat4::IDENTITY,
+        }
+    }
+}
+
+impl Transform {
+    /// Returns `None` unless every value is finite and the scale is non-zero
+    /// along every axis, since other transforms can't be undone.
+    pub fn new(translation: Vec3, rotation: Vec3, scale: Vec3) -> Option<Self> {
+        let radians = |degrees: f32| degrees.to_radians();
+        let to_scene = Mat4::translation(translation)
+            * Mat4::rotation_z(radians(rotation.z))
+            * Mat4::rotation_y(radians(rotation.y))
+            * Mat4::rotation_x(radians(rotation.x))
+            * Mat4::scale(scale);
+        if !to_scene
+            .rows
+            .iter()
+            .flatten()
+            .all(|element| element.is_finite())
+        {
+            return None;
+        }
+        Some(Self {
+            translation,
+            rotation,
+            scale,
+            to_scene,
+            to_object: to_scene.inverse()?,
+        })
+    }
+
+    pub fn translation(&self) -> Vec3 {
+        self.translation
+    }
+
+    pub fn rotation(&self) -> Vec3 {
+        self.rotation
+    }
+
+    pub fn scale(&self) -> Vec3 {
+        self.scale
+    }
+
+    /// The matrix taking points in the object's coordinates to the scene's
+    pub fn to_scene(&self) -> &Mat4 {
+        &self.to_scene
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.to_scene == Mat4::IDENTITY
+    }
+
+    /// The ray in the object's coordinates, along with how much further it
+    /// travels there than in the scene, since scaling stretches distances
+    pub fn ray_to_object(&self, ray: &Ray) -> (Ray, f32) {
+        let direction = self.to_object.transform_vector(ray.direction);
+        let stretch = direction.length();
+        let ray = Ray {
+            origin: self.to_object.transform_point(ray.origin),
+            direction: direction / stretch,
+        };
+        (ray, stretch)
+    }
+}
+
+impl Serialize for Transform {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TransformDescription {
+            translation: self.translation,
+            rotation: self.rotation,
+            scale: self.scale,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Transform {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let transform = TransformDescription::deserialize(deserializer)?;
+        Transform::new(transform.translation, transform.rotation, transform.scale).ok_or_else(
+            || de::Error::custom("transform values must be finite, and scale must not be zero"),
+        )
+    }
+}
+
+/// A sphere, with its center and radius given in its own coordinates, which
+/// its transform then places in the scene. Transforms can stretch spheres into
+/// ellipsoids.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f32,
+    pub material: Material,
+    pub transform: Transform,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+/// The layout of a sphere in binary formats, which is fixed by version 2 of the
+/// protocol. Materials and transforms are sent alongside the scene, as part of
+/// the `Response`, to peers which negotiated the features they need.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Sphere")]
+struct WireSphere {
+    center: Vec3,
+    radius: f32,
+}
+
+/// The layout of a sphere in human-readable formats such as scene files
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Sphere", deny_unknown_fields)]
+struct SphereDescription {
+    center: Vec3,
+    radius: f32,
+    #[serde(default, skip_serializing_if = "Material::is_default")]
+    material: Material,
+    #[serde(default, skip_serializing_if = "Transform::is_identity")]
+    transform: Transform,
+}
+
+impl Serialize for Sphere {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            SphereDescription {
+                center: self.center,
+                radius: self.radius,
+                material: self.material,
+                transform: self.transform,
+            }
+            .serialize(serializer)
+        } else {
+            WireSphere {
+                center: self.center,
+                radius: self.radius,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Sphere {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let sphere = SphereDescription::deserialize(deserializer)?;
+            Ok(Sphere {
+                center: sphere.center,
+                radius: sphere.radius,
+                material: sphere.material,
+                transform: sphere.transform,
+            })
+        } else {
+            let sphere = WireSphere::deserialize(deserializer)?;
+            Ok(Sphere {
+                center: sphere.center,
+                radius: sphere.radius,
+                material: Material::default(),
+                transform: Transform::default(),
+            })
+        }
+    }
+}
+
+/// A surface other than a sphere. Shapes aren't sorted into a `Bvh` like
+/// spheres are, so every ray is checked against each of them; scenes should
+/// only need a few.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Shape {
+    pub kind: ShapeKind,
+    pub material: Material,
+    /// Places the shape, whose geometry is given in its own coordinates
+    pub transform: Transform,
+}
+
+/// The layout of a shape in binary formats, as first sent to peers which
+/// negotiated `FEATURE_SHAPES`. Transforms are sent alongside the scene, as
+/// part of the `Response`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Shape")]
+struct WireShape {
+    kind: ShapeKind,
+    material: Material,
+}
+
+/// The layout of a shape in human-readable formats such as scene files
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Shape", deny_unknown_fields)]
+struct ShapeDescription {
+    kind: ShapeKind,
+    #[serde(default)]
+    material: Material,
+    #[serde(default, skip_serializing_if = "Transform::is_identity")]
+    transform: Transform,
+}
+
+impl Serialize for Shape {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            ShapeDescription {
+                kind: self.kind,
+                material: self.material,
+                transform: self.transform,
+            }
+            .serialize(serializer)
+        } else {
+            WireShape {
+                kind: self.kind,
+                material: self.material,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Shape {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let shape = ShapeDescription::deserialize(deserializer)?;
+            Ok(Shape {
+                kind: shape.kind,
+                material: shape.material,
+                transform: shape.transform,
+            })
+        } else {
+            let shape = WireShape::deserialize(deserializer)?;
+            Ok(Shape {
+                kind: shape.kind,
+                material: shape.material,
+                transform: Transform::default(),
+            })
+        }
+    }
+}
+
+/// The geometry of a shape. Directions don't need to be unit vectors, but
+/// mustn't be zero.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ShapeKind {
+    /// An infinite flat surface passing through `point`, such as the ground
+    Plane { point: Vec3, normal: Vec3 },
+    /// A flat circle, facing in the direction of `normal`
+    Disc {
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+    },
+    /// A solid box whose edges run along the axes, from the corner at `min` to
+    /// the one at `max`
+    AxisAlignedBox { min: Vec3, max: Vec3 },
+    /// A solid box which may be rotated. The box's edges run along `x_axis`,
+    /// `y_axis` (which is adjusted to be perpendicular to `x_axis`), and the
+    /// direction perpendicular to both. `half_size` is the distance from the
+    /// center to the faces along each of those.
+    OrientedBox {
+        center: Vec3,
+        half_size: Vec3,
+        x_axis: Vec3,
+        y_axis: Vec3,
+    },
+    /// A flat triangle. Seen from the side the normal faces, the corners go
+    /// anticlockwise.
+    Triangle { a: Vec3, b: Vec3, c: Vec3 },
+    /// A solid cylinder with flat ends, whose axis runs from `base` to `top`
+    Cylinder { base: Vec3, top: Vec3, radius: f32 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Ray {
     pub origin: Vec3,
     /// Direction should always be a unit vector (have length 1)
@@ -23,15 +326,48 @@ pub struct Intersection {
 
 impl Ray {
     pub fn intersect_sphere(&self, sphere: &Sphere) -> Option<Intersection> {
+        self.intersect_transformed(&sphere.transform, |ray| {
+            ray.intersect_ball(sphere.center, sphere.radius)
+        })
+    }
+
+    /// Find where the ray hits an object placed by `transform`, given how to
+    /// find where a ray in the object's own coordinates hits it
+    pub fn intersect_transformed(
+        &self,
+        transform: &Transform,
+        intersect: impl FnOnce(&Ray) -> Option<Intersection>,
+    ) -> Option<Intersection> {
+        if transform.is_identity() {
+            return intersect(self);
+        }
+        let (ray, stretch) = transform.ray_to_object(self);
+        let intersection = intersect(&ray)?;
+        // Normals are transformed by the transpose of the inverse, which keeps
+        // them perpendicular to surfaces which have been stretched
+        let normal = transform
+            .to_object
+            .transpose()
+            .transform_vector(intersection.normal);
+        Some(self.at(intersection.distance / stretch, normal.normalize()))
+    }
+
+    /// Intersect a sphere, ignoring its transform
+    fn intersect_ball(&self, center: Vec3, radius: f32) -> Option<Intersection> {
         // Compute a vector from the beginning of the ray to the center of the sphere
-        let offset = sphere.center - self.origin;
+        let offset = center - self.origin;
 
         // Project that vector onto the ray direction, to get the distance along the ray
         // to the point where the ray is closest to the sphere's center.
         let distance_along_ray = self.direction.dot(&offset);
 
+        // Rays can start inside a sphere, for example when light is refracted into it.
+        // Rays starting on the surface are treated as outside, since rounding errors
+        // could put them on either side, and the error grows with the sphere's size.
+        let starts_inside = offset.length() < radius * (1.0 - 1e-4);
+
         // Don't consider intersections "behind" the ray.
-        if distance_along_ray < 0.0 {
+        if distance_along_ray < 0.0 && !starts_inside {
             return None;
         }
 
@@ -39,21 +375,28 @@ impl Ray {
         let closest_point = self.origin + distance_along_ray * self.direction;
 
         // Find the distance from that closest point to the center of the sphere
-        let ray_sphere_distance = (sphere.center - closest_point).length();
+        let ray_sphere_distance = (center - closest_point).length();
 
         // Check if that distance is less than the sphere's radius
-        if ray_sphere_distance <= sphere.radius {
+        if ray_sphere_distance <= radius {
             // Use pythagoras' theorem to find out how far the closest point is into the sphere
-            let distance_into_sphere = (sphere.radius.powi(2) - ray_sphere_distance.powi(2)).sqrt();
+            let distance_into_sphere = (radius.powi(2) - ray_sphere_distance.powi(2)).sqrt();
             // Subtract that distance from our original distance calculation to find where the ray
-            // first entered the sphere.
-            let distance = distance_along_ray - distance_into_sphere;
+            // first entered the sphere. If that's behind the ray then it started inside, so we
+            // want to know where it leaves the sphere instead.
+            let entry_distance = distance_along_ray - distance_into_sphere;
+            let distance = if entry_distance > 0.0 {
+                entry_distance
+            } else {
+                distance_along_ray + distance_into_sphere
+            };
 
             // Now we have that distance, we can calculate the position of intersection
             let position = self.origin + distance * self.direction;
 
-            // And with the position, we can subtract the sphere's center and normalize
-            let normal = (1.0 / sphere.radius) * (position - sphere.center);
+            // And with the position, we can subtract the sphere's center and normalize.
+            // The normal always points out of the sphere, even when the ray started inside.
+            let normal = (position - center) / radius;
             Some(Intersection {
                 distance,
                 position,
@@ -64,6 +407,237 @@ impl Ray {
         }
     }
 
+    /// Find the closest sphere hit by the ray, along with its index in `spheres`.
+    /// This checks every sphere, so `Bvh::closest_hit` is faster for large scenes.
+    pub fn closest_hit(&self, spheres: &[Sphere]) -> Option<(usize, Intersection)> {
+        spheres
+            .iter()
+            .enumerate()
+            .filter_map(|(i, sphere)| {
+                self.intersect_sphere(sphere)
+                    .map(|intersection| (i, intersection))
+            })
+            .min_by_key(|tuple| {
+                NotNan::new(tuple.1.distance).expect("Intersection distance to be well defined")
+            })
+    }
+
+    /// Check whether the ray hits any of the spheres before travelling `max_distance`.
+    /// This stops as soon as any hit is found, so is cheaper than finding the closest.
+    pub fn hits_any(&self, spheres: &[Sphere], max_distance: f32) -> bool {
+        spheres.iter().any(|sphere| {
+            self.intersect_sphere(sphere)
+                .is_some_and(|intersection| intersection.distance < max_distance)
+        })
+    }
+
+    /// Find where the ray hits a shape. Flat shapes can be hit from either
+    /// side, and their normal always faces the same way. Solid shapes are like
+    /// spheres: rays starting inside hit them on the way out, and the normal
+    /// points out of the shape.
+    pub fn intersect_shape(&self, shape: &Shape) -> Option<Intersection> {
+        self.intersect_transformed(&shape.transform, |ray| {
+            ray.intersect_shape_kind(&shape.kind)
+        })
+    }
+
+    /// Intersect a shape, ignoring its transform
+    fn intersect_shape_kind(&self, kind: &ShapeKind) -> Option<Intersection> {
+        match *kind {
+            ShapeKind::Plane { point, normal } => self.intersect_plane(point, normal.normalize()),
+            ShapeKind::Disc {
+                center,
+                normal,
+                radius,
+            } => self
+                .intersect_plane(center, normal.normalize())
+                .filter(|intersection| (intersection.position - center).length() <= radius),
+            ShapeKind::AxisAlignedBox { min, max } => self.intersect_box(
+                0.5 * (min + max),
+                0.5 * (max - min),
+                [
+                    Vec3::new(1.0, 0.0, 0.0),
+                    Vec3::new(0.0, 1.0, 0.0),
+                    Vec3::new(0.0, 0.0, 1.0),
+                ],
+            ),
+            ShapeKind::OrientedBox {
+                center,
+                half_size,
+                x_axis,
+                y_axis,
+            } => {
+                let x_axis = x_axis.normalize();
+                let z_axis = x_axis.cross(&y_axis).normalize();
+                let y_axis = z_axis.cross(&x_axis);
+                self.intersect_box(center, half_size, [x_axis, y_axis, z_axis])
+            }
+            ShapeKind::Triangle { a, b, c } => self.intersect_triangle(a, b, c),
+            ShapeKind::Cylinder { base, top, radius } => self.intersect_cylinder(base, top, radius),
+        }
+    }
+
+    /// Find the closest shape hit by the ray, along with its index in `shapes`
+    pub fn closest_shape_hit(&self, shapes: &[Shape]) -> Option<(usize, Intersection)> {
+        shapes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, shape)| {
+                self.intersect_shape(shape)
+                    .map(|intersection| (i, intersection))
+            })
+            .min_by(|a, b| a.1.distance.total_cmp(&b.1.distance))
+    }
+
+    /// Check whether the ray hits any of the shapes before travelling `max_distance`
+    pub fn hits_any_shape(&self, shapes: &[Shape], max_distance: f32) -> bool {
+        shapes.iter().any(|shape| {
+            self.intersect_shape(shape)
+                .is_some_and(|intersection| intersection.distance < max_distance)
+        })
+    }
+
+    pub(crate) fn at(&self, distance: f32, normal: Vec3) -> Intersection {
+        Intersection {
+            distance,
+            position: self.origin + distance * self.direction,
+            normal,
+        }
+    }
+
+    fn intersect_plane(&self, point: Vec3, normal: Vec3) -> Option<Intersection> {
+        // Rays running parallel to the plane never reach it
+        let approach = normal.dot(&self.direction);
+        if approach == 0.0 {
+            return None;
+        }
+        let distance = normal.dot(&(point - self.origin)) / approach;
+        (distance > 0.0).then(|| self.at(distance, normal))
+    }
+
+    /// Intersect a box centered on `center`, whose faces are `half_size` away
+    /// along each of the (unit, perpendicular) `axes`.
+    fn intersect_box(
+        &self,
+        center: Vec3,
+        half_size: Vec3,
+        axes: [Vec3; 3],
+    ) -> Option<Intersection> {
+        let offset = self.origin - center;
+        // Where the ray enters and leaves the box, and the outward normal of
+        // the face it crosses each time
+        let mut near = (f32::NEG_INFINITY, Vec3::new(0.0, 0.0, 0.0));
+        let mut far = (f32::INFINITY, Vec3::new(0.0, 0.0, 0.0));
+        for (axis, half_size) in axes
+            .into_iter()
+            .zip([half_size.x, half_size.y, half_size.z])
+        {
+            let origin = axis.dot(&offset);
+            let direction = axis.dot(&self.direction);
+            if direction == 0.0 {
+                // The ray runs between the two faces, so must start between them
+                if origin.abs() > half_size {
+                    return None;
+                }
+                continue;
+            }
+            // The ray enters through the face it's heading towards, and leaves through the other
+            let facing = if direction > 0.0 { -1.0 } else { 1.0 };
+            let enter = (facing * half_size - origin) / direction;
+            let leave = (-facing * half_size - origin) / direction;
+            if enter > near.0 {
+                near = (enter, facing * axis);
+            }
+            if leave < far.0 {
+                far = (leave, -facing * axis);
+            }
+        }
+        if near.0 > far.0 || far.0 <= 0.0 {
+            return None;
+        }
+        // Rays starting inside the box hit it on the way out
+        let (distance, normal) = if near.0 > 0.0 { near } else { far };
+        Some(self.at(distance, normal))
+    }
+
+    fn intersect_triangle(&self, a: Vec3, b: Vec3, c: Vec3) -> Option<Intersection> {
+        let (distance, _, _) = self.triangle_hit(a, b, c)?;
+        Some(self.at(distance, (b - a).cross(&(c - a)).normalize()))
+    }
+
+    /// The Möller-Trumbore algorithm, which finds where the ray crosses the
+    /// triangle's plane in terms of how far it is along each edge from `a`.
+    /// Returns the distance along the ray, followed by how far the point is
+    /// towards `b` and towards `c`.
+    pub(crate) fn triangle_hit(&self, a: Vec3, b: Vec3, c: Vec3) -> Option<(f32, f32, f32)> {
+        let ab = b - a;
+        let ac = c - a;
+        let p = self.direction.cross(&ac);
+        let determinant = ab.dot(&p);
+        // The ray runs parallel to the triangle
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+        let offset = self.origin - a;
+        let u = offset.dot(&p) / determinant;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = offset.cross(&ab);
+        let v = self.direction.dot(&q) / determinant;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let distance = ac.dot(&q) / determinant;
+        (distance > 0.0).then_some((distance, u, v))
+    }
+
+    fn intersect_cylinder(&self, base: Vec3, top: Vec3, radius: f32) -> Option<Intersection> {
+        let height = (top - base).length();
+        let axis = (top - base) / height;
+        let offset = self.origin - base;
+        // Parts of a vector at right angles to the axis
+        let across = |v: Vec3| v - axis.dot(&v) * axis;
+        let height_at = |distance: f32| axis.dot(&(offset + distance * self.direction));
+
+        let mut hits = Vec::with_capacity(4);
+
+        // The curved side is where the distance from the axis equals the radius
+        let (direction, start) = (across(self.direction), across(offset));
+        let a = direction.dot(&direction);
+        let b = direction.dot(&start);
+        let c = start.dot(&start) - radius.powi(2);
+        let discriminant = b * b - a * c;
+        if a > 0.0 && discriminant >= 0.0 {
+            for distance in [
+                (-b - discriminant.sqrt()) / a,
+                (-b + discriminant.sqrt()) / a,
+            ] {
+                if (0.0..=height).contains(&height_at(distance)) {
+                    let normal = across(offset + distance * self.direction);
+                    hits.push((distance, normal.normalize()));
+                }
+            }
+        }
+
+        // The ends are discs
+        let approach = axis.dot(&self.direction);
+        for (center, normal) in [(base, -axis), (top, axis)] {
+            if approach != 0.0 {
+                let distance = axis.dot(&(center - self.origin)) / approach;
+                let position = self.origin + distance * self.direction;
+                if (position - center).length() <= radius {
+                    hits.push((distance, normal));
+                }
+            }
+        }
+
+        hits.into_iter()
+            .filter(|&(distance, _)| distance > 0.0)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(distance, normal)| self.at(distance, normal))
+    }
+
     // By deferring to our new function, we can reuse our tests
     pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
         self.intersect_sphere(sphere).is_some()
@@ -72,6 +646,8 @@ impl Ray {
 
 #[cfg(test)]
 mod tests {
+    use quickcheck::{quickcheck, Arbitrary, Gen, TestResult};
+
     use super::*;
 
     #[test]
@@ -83,6 +659,8 @@ mod tests {
         let sphere = Sphere {
             center: Vec3::new(0.0, 0.0, 0.0),
             radius: 0.5,
+            material: Material::default(),
+            transform: Transform::default(),
         };
         assert!(ray.intersects_sphere(&sphere));
     }
@@ -96,6 +674,8 @@ mod tests {
         let sphere = Sphere {
             center: Vec3::new(10.0, 5.0, 20.0),
             radius: 0.5,
+            material: Material::default(),
+            transform: Transform::default(),
         };
         assert!(ray.intersects_sphere(&sphere));
     }
@@ -109,6 +689,8 @@ mod tests {
         let sphere = Sphere {
             center: Vec3::new(10.0, 5.0, 20.0),
             radius: 0.5,
+            material: Material::default(),
+            transform: Transform::default(),
         };
         assert!(!ray.intersects_sphere(&sphere));
     }
@@ -122,6 +704,8 @@ mod tests {
         let sphere = Sphere {
             center: Vec3::new(0.0, 0.0, 0.0),
             radius: 0.5,
+            material: Material::default(),
+            transform: Transform::default(),
         };
         assert!(!ray.intersects_sphere(&sphere));
     }
@@ -135,6 +719,8 @@ mod tests {
         let sphere = Sphere {
             center: Vec3::new(4.0, 6.7, -1.8),
             radius: 0.5,
+            material: Material::default(),
+            transform: Transform::default(),
         };
         assert!(ray.intersects_sphere(&sphere));
     }
@@ -148,7 +734,532 @@ mod tests {
         let sphere = Sphere {
             center: Vec3::new(4.0, 6.7, -1.6),
             radius: 0.5,
+            material: Material::default(),
+            transform: Transform::default(),
         };
         assert!(!ray.intersects_sphere(&sphere));
     }
+
+    #[test]
+    fn intersection_from_inside() {
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        let sphere = Sphere {
+            center: Vec3::new(0.0, 0.0, 0.5),
+            radius: 1.0,
+            material: Material::default(),
+            transform: Transform::default(),
+        };
+        let intersection = ray.intersect_sphere(&sphere).unwrap();
+        assert_eq!(intersection.distance, 0.5);
+        assert_eq!(intersection.position, Vec3::new(0.0, 0.0, -0.5));
+        assert_eq!(intersection.normal, Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn hits_any_within_distance() {
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 0.0, 1.0),
+        };
+        let spheres = [
+            Sphere {
+                center: Vec3::new(5.0, 0.0, 5.0),
+                radius: 0.5,
+                material: Material::default(),
+                transform: Transform::default(),
+            },
+            Sphere {
+                center: Vec3::new(0.0, 0.0, 5.0),
+                radius: 0.5,
+                material: Material::default(),
+                transform: Transform::default(),
+            },
+        ];
+        assert!(ray.hits_any(&spheres, 10.0));
+        // The sphere in the way is further away than the distance we care about
+        assert!(!ray.hits_any(&spheres, 4.0));
+    }
+
+    #[test]
+    fn wire_format_has_no_material() {
+        let sphere = Sphere {
+            center: Vec3::new(1.0, 2.0, 3.0),
+            radius: 0.5,
+            material: Material {
+                albedo: Some(Vec3::new(1.0, 0.0, 0.0)),
+                ..Material::default()
+            },
+            transform: Transform::default(),
+        };
+        // The version 2 protocol encodes a sphere as exactly four floats
+        let data = postcard::to_allocvec(&sphere).unwrap();
+        assert_eq!(data.len(), 16);
+
+        let decoded: Sphere = postcard::from_bytes(&data).unwrap();
+        assert_eq!(decoded.center, sphere.center);
+        assert!(decoded.material.is_default());
+    }
+
+    #[test]
+    fn description_has_material() {
+        let sphere: Sphere = serde_json::from_str(
+            r#"{
+                "center": { "x": 1.0, "y": 2.0, "z": 3.0 },
+                "radius": 0.5,
+                "material": { "albedo": { "x": 1.0, "y": 0.0, "z": 0.0 } }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(sphere.material.albedo, Some(Vec3::new(1.0, 0.0, 0.0)));
+        assert_eq!(sphere.material.reflectivity, 0.7);
+    }
+
+    fn shape(kind: ShapeKind) -> Shape {
+        Shape {
+            kind,
+            material: Material::default(),
+            transform: Transform::default(),
+        }
+    }
+
+    /// A ray from `origin` towards `target`
+    fn towards(origin: Vec3, target: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction: (target - origin).normalize(),
+        }
+    }
+
+    fn assert_hit(ray: Ray, shape: &Shape, distance: f32, normal: Vec3) {
+        let intersection = ray.intersect_shape(shape).expect("Ray to hit the shape");
+        assert!(
+            (intersection.distance - distance).abs() < 1e-5,
+            "Hit at {} rather than {}",
+            intersection.distance,
+            distance
+        );
+        assert!((intersection.position - ray.at(distance, normal).position).length() < 1e-5);
+        assert!(
+            (intersection.normal - normal).length() < 1e-5,
+            "Normal was {:?} rather than {:?}",
+            intersection.normal,
+            normal
+        );
+    }
+
+    #[test]
+    fn plane_from_either_side() {
+        let ground = shape(ShapeKind::Plane {
+            point: Vec3::new(0.0, -1.0, 0.0),
+            normal: Vec3::new(0.0, 2.0, 0.0),
+        });
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        assert_hit(towards(origin, Vec3::new(0.0, -1.0, 0.0)), &ground, 1.0, up);
+        let below = Vec3::new(0.0, -3.0, 0.0);
+        assert_hit(towards(below, origin), &ground, 2.0, up);
+        // Rays running parallel to the plane, or away from it, miss
+        assert!(towards(origin, Vec3::new(0.0, 0.0, 1.0))
+            .intersect_shape(&ground)
+            .is_none());
+        assert!(towards(origin, up).intersect_shape(&ground).is_none());
+    }
+
+    #[test]
+    fn disc_has_an_edge() {
+        let disc = shape(ShapeKind::Disc {
+            center: Vec3::new(0.0, 0.0, 5.0),
+            normal: Vec3::new(0.0, 0.0, -1.0),
+            radius: 1.0,
+        });
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let ray = towards(origin, Vec3::new(0.9, 0.0, 5.0));
+        let distance = Vec3::new(0.9, 0.0, 5.0).length();
+        assert_hit(ray, &disc, distance, Vec3::new(0.0, 0.0, -1.0));
+        assert!(towards(origin, Vec3::new(1.1, 0.0, 5.0))
+            .intersect_shape(&disc)
+            .is_none());
+    }
+
+    #[test]
+    fn axis_aligned_box() {
+        let cube = shape(ShapeKind::AxisAlignedBox {
+            min: Vec3::new(-1.0, -1.0, 4.0),
+            max: Vec3::new(1.0, 1.0, 6.0),
+        });
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        assert_hit(
+            towards(origin, Vec3::new(0.5, 0.5, 4.0)),
+            &cube,
+            Vec3::new(0.5, 0.5, 4.0).length(),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        // Hitting the side instead of the front
+        let side = Vec3::new(3.0, 0.0, 5.0);
+        assert_hit(
+            towards(side, Vec3::new(0.0, 0.0, 5.0)),
+            &cube,
+            2.0,
+            Vec3::new(1.0, 0.0, 0.0),
+        );
+        assert!(towards(origin, Vec3::new(1.5, 0.0, 5.0))
+            .intersect_shape(&cube)
+            .is_none());
+        // Rays starting inside hit the far side, with the normal still pointing out
+        let inside = Vec3::new(0.0, 0.0, 5.0);
+        assert_hit(
+            towards(inside, Vec3::new(0.0, 1.0, 5.0)),
+            &cube,
+            1.0,
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn oriented_box() {
+        // A cube turned 45 degrees around the y axis, so that an edge faces the origin
+        let cube = shape(ShapeKind::OrientedBox {
+            center: Vec3::new(0.0, 0.0, 5.0),
+            half_size: Vec3::new(1.0, 1.0, 1.0),
+            x_axis: Vec3::new(1.0, 0.0, 1.0),
+            y_axis: Vec3::new(0.0, 1.0, 0.0),
+        });
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let ray = towards(origin, Vec3::new(0.0, 0.0, 1.0));
+        let edge = 5.0 - std::f32::consts::SQRT_2;
+        let intersection = ray.intersect_shape(&cube).unwrap();
+        assert!((intersection.distance - edge).abs() < 1e-5);
+        // Just off the edge, the ray hits one of the faces either side of it
+        let ray = towards(origin, Vec3::new(0.1, 0.0, 5.0));
+        let intersection = ray.intersect_shape(&cube).unwrap();
+        let face = Vec3::new(1.0, 0.0, -1.0).normalize();
+        assert!((intersection.normal - face).length() < 1e-5);
+        // Unrotated, the same cube would be hit 1 away from its center
+        assert!(towards(origin, Vec3::new(1.2, 0.0, 4.0))
+            .intersect_shape(&cube)
+            .is_none());
+    }
+
+    #[test]
+    fn triangle() {
+        let triangle = shape(ShapeKind::Triangle {
+            a: Vec3::new(-1.0, -1.0, 5.0),
+            b: Vec3::new(1.0, -1.0, 5.0),
+            c: Vec3::new(0.0, 1.0, 5.0),
+        });
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        // The corners go anticlockwise seen from behind, so the normal faces away
+        assert_hit(
+            towards(origin, Vec3::new(0.0, 0.0, 5.0)),
+            &triangle,
+            5.0,
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        for outside in [
+            Vec3::new(0.0, -1.1, 5.0),
+            Vec3::new(0.6, 0.5, 5.0),
+            Vec3::new(-0.6, 0.5, 5.0),
+        ] {
+            assert!(towards(origin, outside)
+                .intersect_shape(&triangle)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn cylinder() {
+        let cylinder = shape(ShapeKind::Cylinder {
+            base: Vec3::new(0.0, -1.0, 5.0),
+            top: Vec3::new(0.0, 1.0, 5.0),
+            radius: 1.0,
+        });
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        assert_hit(
+            towards(origin, Vec3::new(0.0, 0.0, 5.0)),
+            &cylinder,
+            4.0,
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        // Looking down on the top
+        let above = Vec3::new(0.5, 3.0, 5.0);
+        assert_hit(
+            towards(above, Vec3::new(0.5, 0.0, 5.0)),
+            &cylinder,
+            2.0,
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        // Passing over the top
+        assert!(towards(origin, Vec3::new(0.0, 1.1, 4.0))
+            .intersect_shape(&cylinder)
+            .is_none());
+        // Starting inside, and leaving through the bottom
+        let inside = Vec3::new(0.0, 0.0, 5.0);
+        assert_hit(
+            towards(inside, Vec3::new(0.0, -1.0, 5.0)),
+            &cylinder,
+            1.0,
+            Vec3::new(0.0, -1.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn closest_shape() {
+        let wall = |z| {
+            shape(ShapeKind::Plane {
+                point: Vec3::new(0.0, 0.0, z),
+                normal: Vec3::new(0.0, 0.0, 1.0),
+            })
+        };
+        let shapes = [wall(8.0), wall(3.0), wall(-2.0)];
+        let ray = towards(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let (index, intersection) = ray.closest_shape_hit(&shapes).unwrap();
+        assert_eq!((index, intersection.distance), (1, 3.0));
+        assert!(ray.hits_any_shape(&shapes, 4.0));
+        assert!(!ray.hits_any_shape(&shapes, 2.0));
+    }
+
+    #[test]
+    fn stretched_sphere() {
+        // A unit sphere stretched into an ellipsoid twice as wide as it is tall
+        let ellipsoid = Sphere {
+            center: Vec3::new(0.0, 0.0, 0.0),
+            radius: 1.0,
+            material: Material::default(),
+            transform: Transform::new(
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(2.0, 1.0, 1.0),
+            )
+            .unwrap(),
+        };
+        let hit = towards(Vec3::new(-5.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 5.0))
+            .intersect_sphere(&ellipsoid)
+            .unwrap();
+        assert!((hit.distance - 3.0).abs() < 1e-5);
+        assert!((hit.normal - Vec3::new(-1.0, 0.0, 0.0)).length() < 1e-5);
+        // Looking down at x = 1, the surface is at y = sqrt(3) / 2, sloping
+        // away from the middle
+        let ray = towards(Vec3::new(1.0, 5.0, 5.0), Vec3::new(1.0, 0.0, 5.0));
+        let hit = ray.intersect_sphere(&ellipsoid).unwrap();
+        let height = 0.75f32.sqrt();
+        assert!((hit.distance - (5.0 - height)).abs() < 1e-5);
+        let normal = Vec3::new(0.25, height, 0.0).normalize();
+        assert!((hit.normal - normal).length() < 1e-5);
+        // Unstretched, the sphere would be missed
+        assert!(towards(Vec3::new(1.5, 5.0, 5.0), Vec3::new(1.5, 0.0, 5.0))
+            .intersect_sphere(&ellipsoid)
+            .is_some());
+        assert!(!Ray {
+            origin: Vec3::new(1.5, 5.0, 5.0),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+        }
+        .hits_any(std::slice::from_ref(&ellipsoid), 3.0));
+    }
+
+    #[test]
+    fn rotated_box() {
+        // An axis-aligned cube turned 45 degrees around the y axis, which
+        // should match the oriented box with the same turn
+        let rotated = Shape {
+            transform: Transform::new(
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::new(0.0, 45.0, 0.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            )
+            .unwrap(),
+            ..shape(ShapeKind::AxisAlignedBox {
+                min: Vec3::new(-1.0, -1.0, -1.0),
+                max: Vec3::new(1.0, 1.0, 1.0),
+            })
+        };
+        let oriented = shape(ShapeKind::OrientedBox {
+            center: Vec3::new(0.0, 0.0, 5.0),
+            half_size: Vec3::new(1.0, 1.0, 1.0),
+            x_axis: Vec3::new(1.0, 0.0, -1.0),
+            y_axis: Vec3::new(0.0, 1.0, 0.0),
+        });
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        for target in [
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::new(0.1, 0.3, 5.0),
+            Vec3::new(-0.7, -0.2, 5.0),
+            Vec3::new(1.2, 0.0, 4.0),
+        ] {
+            let ray = towards(origin, target);
+            match (
+                ray.intersect_shape(&rotated),
+                ray.intersect_shape(&oriented),
+            ) {
+                (Some(a), Some(b)) => {
+                    assert!((a.distance - b.distance).abs() < 1e-4);
+                    assert!((a.normal - b.normal).length() < 1e-4);
+                }
+                (a, b) => assert_eq!(a.is_some(), b.is_some(), "towards {:?}", target),
+            }
+        }
+    }
+
+    /// A number between `min` and `max`
+    fn between(g: &mut Gen, min: f32, max: f32) -> f32 {
+        let t = (u32::arbitrary(g) % 10_001) as f32 / 10_000.0;
+        min + t * (max - min)
+    }
+
+    fn point(g: &mut Gen, size: f32) -> Vec3 {
+        Vec3::new(
+            between(g, -size, size),
+            between(g, -size, size),
+            between(g, -size, size),
+        )
+    }
+
+    fn unit(g: &mut Gen) -> Vec3 {
+        loop {
+            let v = point(g, 1.0);
+            if v.length() > 1e-2 {
+                return v.normalize();
+            }
+        }
+    }
+
+    fn sphere(g: &mut Gen) -> Sphere {
+        Sphere {
+            center: point(g, 10.0),
+            radius: between(g, 0.1, 10.0),
+            material: Material::default(),
+            transform: Transform::default(),
+        }
+    }
+
+    /// A sphere, and a ray aimed close enough to it that it hits about half the time
+    #[derive(Debug, Clone)]
+    struct Aimed(Ray, Sphere);
+
+    impl Arbitrary for Aimed {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let sphere = sphere(g);
+            let origin = point(g, 20.0);
+            let target = sphere.center + (1.5 * sphere.radius) * unit(g);
+            // Sometimes aim away from the sphere instead
+            let sign = if bool::arbitrary(g) { 1.0 } else { -0.2 };
+            let towards = target - origin;
+            if towards.length() < 1e-2 {
+                return Self::arbitrary(g);
+            }
+            let direction = (sign / towards.length()) * towards;
+            let direction = direction.normalize();
+            Aimed(Ray { origin, direction }, sphere)
+        }
+    }
+
+    /// A sphere, and a ray starting inside it
+    #[derive(Debug, Clone)]
+    struct Inside(Ray, Sphere);
+
+    impl Arbitrary for Inside {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let sphere = sphere(g);
+            let origin = sphere.center + (between(g, 0.0, 0.99) * sphere.radius) * unit(g);
+            let direction = unit(g);
+            Inside(Ray { origin, direction }, sphere)
+        }
+    }
+
+    /// A sphere, and a ray which just touches its surface
+    #[derive(Debug, Clone)]
+    struct Tangent(Ray, Sphere, Vec3);
+
+    impl Arbitrary for Tangent {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let sphere = sphere(g);
+            let direction = unit(g);
+            let perpendicular = direction.cross(&unit(g));
+            if perpendicular.length() < 1e-2 {
+                return Self::arbitrary(g);
+            }
+            let perpendicular = perpendicular.normalize();
+            let touching = sphere.center + sphere.radius * perpendicular;
+            let origin = touching - between(g, 1.0, 10.0) * direction;
+            Tangent(Ray { origin, direction }, sphere, touching)
+        }
+    }
+
+    /// Check that an intersection lies on the sphere's surface, in the direction of the ray
+    fn on_surface(ray: &Ray, sphere: &Sphere, intersection: &Intersection) -> bool {
+        let tolerance = 1e-3 * sphere.radius.max(intersection.distance).max(1.0);
+        let along_ray = ray.origin + intersection.distance * ray.direction;
+        intersection.distance >= 0.0
+            && ((intersection.position - sphere.center).length() - sphere.radius).abs() <= tolerance
+            && (along_ray - intersection.position).length() <= tolerance
+            && (intersection.normal.length() - 1.0).abs() <= 1e-3
+    }
+
+    quickcheck! {
+        fn hits_lie_on_the_surface(aimed: Aimed) -> bool {
+            let Aimed(ray, sphere) = aimed;
+            ray.intersect_sphere(&sphere)
+                .is_none_or(|intersection| on_surface(&ray, &sphere, &intersection))
+        }
+
+        fn hits_match_the_quadratic(aimed: Aimed) -> TestResult {
+            let Aimed(ray, sphere) = aimed;
+            // Solve |origin + t * direction - center|^2 = radius^2 for t
+            let offset = ray.origin - sphere.center;
+            let b = ray.direction.dot(&offset);
+            let c = offset.dot(&offset) - sphere.radius.powi(2);
+            let discriminant = b * b - c;
+            // Rounding errors decide the outcome for tangent rays and rays starting
+            // on the surface, which are tested separately
+            let scale = sphere.radius.powi(2).max(offset.dot(&offset));
+            if discriminant.abs() < 1e-3 * scale || c.abs() < 1e-3 * scale {
+                return TestResult::discard();
+            }
+
+            let far = -b + discriminant.max(0.0).sqrt();
+            let near = -b - discriminant.max(0.0).sqrt();
+            let first = if near > 0.0 { near } else { far };
+            let expected = (discriminant >= 0.0 && far >= 0.0).then_some(first);
+            let intersection = ray.intersect_sphere(&sphere);
+            TestResult::from_bool(
+                ray.intersects_sphere(&sphere) == expected.is_some()
+                    && match (intersection, expected) {
+                        (Some(intersection), Some(t)) => {
+                            (intersection.distance - t).abs() <= 1e-3 * scale.sqrt()
+                        }
+                        (None, None) => true,
+                        _ => false,
+                    },
+            )
+        }
+
+        fn hits_any_agrees_with_intersect_sphere(aimed: Aimed, max_distance: u8) -> bool {
+            let Aimed(ray, sphere) = aimed;
+            let max_distance = max_distance as f32 / 4.0;
+            let expected = ray
+                .intersect_sphere(&sphere)
+                .is_some_and(|intersection| intersection.distance < max_distance);
+            ray.hits_any(&[sphere], max_distance) == expected
+        }
+
+        fn rays_from_inside_hit_on_the_way_out(inside: Inside) -> bool {
+            let Inside(ray, sphere) = inside;
+            ray.intersect_sphere(&sphere).is_some_and(|intersection| {
+                on_surface(&ray, &sphere, &intersection)
+                    && intersection.normal.dot(&ray.direction) >= -1e-3
+            })
+        }
+
+        fn tangent_rays_graze_the_surface(tangent: Tangent) -> bool {
+            let Tangent(ray, sphere, touching) = tangent;
+            // Rounding errors may make the ray miss, but if it hits, it must be at
+            // the point it touches
+            ray.intersect_sphere(&sphere).is_none_or(|intersection| {
+                on_surface(&ray, &sphere, &intersection)
+                    && (intersection.position - touching).length() <= 1e-2 * sphere.radius.max(1.0)
+            })
+        }
+    }
 }
@@ -0,0 +1,222 @@
+//! Golden-image tests for the shading.
+//!
+//! Each canonical scene is rendered and compared against a reference image in
+//! `tests/golden`. Pixels are compared by how different they look, rather than
+//! by their exact values, so that harmless rounding differences don't fail the
+//! tests. When a test fails, the render and an image highlighting the
+//! differences are written to `target/golden`.
+//!
+//! After an intentional change to the shading, regenerate the references with
+//! `UPDATE_GOLDEN=1 cargo test golden`, and check the new images by eye.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use structopt::StructOpt;
+
+use rust_workshop::protocol::Scene;
+use rust_workshop::shading::ShadingOpt;
+use rust_workshop::vec::Vec3;
+
+use crate::image::Image;
+use crate::render::render_scene_file;
+use crate::scene_file::SceneFile;
+
+const WIDTH: u32 = 96;
+const HEIGHT: u32 = 72;
+
+/// Colour difference (CIE76 delta E) above which two pixels are considered
+/// different. Around 2.3 is the smallest difference people can notice.
+const MAX_DELTA_E: f32 = 2.3;
+
+/// Fraction of pixels which may differ, to allow for rays which only just hit
+/// or miss the edge of a sphere coming out differently on other platforms
+const MAX_DIFFERENT_FRACTION: f32 = 0.002;
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// Load an 8-bit RGB PNG, like those written by `Image::save`
+fn load_png(path: &Path) -> anyhow::Result<Image> {
+    let mut reader = png::Decoder::new(BufReader::new(File::open(path)?)).read_info()?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data)?;
+    if (info.color_type, info.bit_depth) != (png::ColorType::Rgb, png::BitDepth::Eight) {
+        bail!(
+            "Unsupported PNG format: {:?} at {:?}",
+            info.color_type,
+            info.bit_depth
+        );
+    }
+    let convert = |c: u8| c as f32 / 255.0;
+    let pixels = data[..info.buffer_size()]
+        .chunks_exact(3)
+        .map(|c| Vec3::new(convert(c[0]), convert(c[1]), convert(c[2])))
+        .collect();
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+/// Convert an 8-bit sRGB colour to CIELAB, under a D65 white point
+fn to_lab(rgb: [u8; 3]) -> Vec3 {
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let [r, g, b] = rgb.map(linear);
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    Vec3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// How different two colours look, once written to an image
+fn delta_e(a: Vec3, b: Vec3) -> f32 {
+    let d = to_lab(Image::to_rgb8(a)) - to_lab(Image::to_rgb8(b));
+    d.dot(&d).sqrt()
+}
+
+/// An image showing where the render differs from the reference: differing
+/// pixels in red, brighter the larger the difference, over a dimmed copy of
+/// the reference.
+fn diff_image(reference: &Image, actual: &Image) -> Image {
+    let pixels = reference
+        .pixels
+        .iter()
+        .zip(&actual.pixels)
+        .map(|(&expected, &actual)| {
+            let delta = delta_e(expected, actual);
+            if delta > MAX_DELTA_E {
+                Vec3::new(0.5 + delta / 20.0, 0.0, 0.0)
+            } else {
+                let luma = 0.2126 * expected.x + 0.7152 * expected.y + 0.0722 * expected.z;
+                0.3 * Vec3::new(luma, luma, luma)
+            }
+        })
+        .collect();
+    Image {
+        width: reference.width,
+        height: reference.height,
+        pixels,
+    }
+}
+
+/// Render a scene and compare it against its reference image
+fn check(name: &str, scene_file: &SceneFile) {
+    let shading = ShadingOpt::from_iter(["golden", "--bounces", "4"]);
+    let actual = render_scene_file(scene_file, WIDTH, HEIGHT, shading);
+
+    let reference_path = golden_dir().join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&reference_path).unwrap();
+        return;
+    }
+    let reference = load_png(&reference_path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to load {}: {}. Run with UPDATE_GOLDEN=1 to create it.",
+            reference_path.display(),
+            e
+        )
+    });
+    assert_eq!(
+        (reference.width, reference.height),
+        (WIDTH, HEIGHT),
+        "{} has the wrong size",
+        reference_path.display()
+    );
+
+    let different = reference
+        .pixels
+        .iter()
+        .zip(&actual.pixels)
+        .filter(|&(&expected, &actual)| delta_e(expected, actual) > MAX_DELTA_E)
+        .count();
+    let allowed = (MAX_DIFFERENT_FRACTION * reference.pixels.len() as f32) as usize;
+    if different > allowed {
+        let output = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/golden");
+        std::fs::create_dir_all(&output).unwrap();
+        let actual_path = output.join(format!("{}-actual.png", name));
+        let diff_path = output.join(format!("{}-diff.png", name));
+        actual.save(&actual_path).unwrap();
+        diff_image(&reference, &actual).save(&diff_path).unwrap();
+        panic!(
+            "{} of {} pixels differ from {}. See {} and {}",
+            different,
+            reference.pixels.len(),
+            reference_path.display(),
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+#[test]
+fn builtin_demo() {
+    // Lit by the default lights, since the scene doesn't have any of its own
+    check("builtin", &SceneFile::from_scene(&Scene::demo(0)));
+}
+
+#[test]
+fn demo_scene_file() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/demo.ron");
+    check("demo", &SceneFile::load(&path).unwrap());
+}
+
+#[test]
+fn mirrors() {
+    check(
+        "mirrors",
+        &SceneFile::load(&golden_dir().join("mirrors.ron")).unwrap(),
+    );
+}
+
+#[test]
+fn shapes() {
+    check(
+        "shapes",
+        &SceneFile::load(&golden_dir().join("shapes.ron")).unwrap(),
+    );
+}
+
+#[test]
+fn meshes() {
+    check(
+        "meshes",
+        &SceneFile::load(&golden_dir().join("meshes.ron")).unwrap(),
+    );
+}
+
+#[test]
+fn transforms() {
+    check(
+        "transforms",
+        &SceneFile::load(&golden_dir().join("transforms.ron")).unwrap(),
+    );
+}
+
+#[test]
+fn identical_colours_match() {
+    let grey = Vec3::new(0.5, 0.5, 0.5);
+    assert_eq!(delta_e(grey, grey), 0.0);
+    // Too small a change to show up once written to an image
+    assert!(delta_e(grey, grey + Vec3::new(0.001, 0.0, 0.0)) < MAX_DELTA_E);
+    assert!(delta_e(grey, Vec3::new(0.5, 0.6, 0.5)) > MAX_DELTA_E);
+}
@@ -0,0 +1,91 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::bail;
+
+use rust_workshop::vec::Vec3;
+
+/// An RGB image with floating point colour components, nominally in the range 0..1
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels in row-major order, starting from the top-left
+    pub pixels: Vec<Vec3>,
+}
+
+impl Image {
+    /// Construct a black image of the given size
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Vec3::new(0.0, 0.0, 0.0); (width * height) as usize],
+        }
+    }
+
+    /// Convert a colour to 8-bit components, clamping anything out of range
+    pub fn to_rgb8(color: Vec3) -> [u8; 3] {
+        let convert = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+        [convert(color.x), convert(color.y), convert(color.z)]
+    }
+
+    /// Write the image in binary PPM format
+    pub fn write_ppm(&self, mut writer: impl Write) -> std::io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for &pixel in &self.pixels {
+            writer.write_all(&Self::to_rgb8(pixel))?;
+        }
+        writer.flush()
+    }
+
+    /// Write the image in PNG format
+    pub fn write_png(&self, writer: impl Write) -> anyhow::Result<()> {
+        let mut encoder = png::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let data: Vec<u8> = self.pixels.iter().flat_map(|&p| Self::to_rgb8(p)).collect();
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(())
+    }
+
+    /// Save the image to a file, choosing the format from the file extension
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("png") => self.write_png(BufWriter::new(File::create(path)?)),
+            Some("ppm") => Ok(self.write_ppm(BufWriter::new(File::create(path)?))?),
+            _ => bail!("Unsupported image format: {}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm_header_and_size() {
+        let mut image = Image::new(2, 1);
+        image.pixels[1] = Vec3::new(1.0, 0.5, 2.0);
+        let mut data = Vec::new();
+        image.write_ppm(&mut data).unwrap();
+        assert_eq!(data, b"P6\n2 1\n255\n\0\0\0\xff\x80\xff");
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut image = Image::new(3, 2);
+        image.pixels[4] = Vec3::new(0.0, 1.0, 0.0);
+        let mut data = Vec::new();
+        image.write_png(&mut data).unwrap();
+
+        let mut reader = png::Decoder::new(&data[..]).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(&buf[12..15], &[0, 255, 0]);
+    }
+}
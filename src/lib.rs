@@ -0,0 +1,46 @@
+//! Geometry, shading and the wire protocol shared by the worker, the server and the benchmarks.
+//!
+//! - [`vec`](crate::vec) and [`geom`]: vectors, matrices, quaternions, rays, transforms, spheres and other shapes
+//! - [`mesh`]: triangle meshes, loaded from OBJ and PLY files
+//! - [`protocol`]: the messages exchanged with a server, and how they're framed
+//! - [`client`]: a connection to a server, for tracing the rays it hands out
+//! - [`shading`]: working out the colour seen along a ray
+//!
+//! A minimal worker traces each batch of rays it's given, and hands the
+//! results back:
+//!
+//! ```no_run
+//! use rust_workshop::bvh::Bvh;
+//! use rust_workshop::client::Connection;
+//! use rust_workshop::protocol::{Capabilities, FrameLimits};
+//! use rust_workshop::shading::{compute_result, ShadingOpt};
+//! use structopt::StructOpt;
+//!
+//! let addr = "127.0.0.1:5000".parse()?;
+//! let mut connection =
+//!     Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None)?;
+//! connection.set_name("Example".into())?;
+//! let opt = ShadingOpt::from_iter(["example"]);
+//! loop {
+//!     let (rays, scene) = connection.reserve_rays()?;
+//!     let bvh = Bvh::new(&scene.spheres);
+//!     let results = rays
+//!         .into_iter()
+//!         .map(|ray| compute_result(ray, &scene, &bvh, &opt, opt.bounces))
+//!         .collect();
+//!     connection.submit_results(results)?;
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+#[cfg(feature = "async")]
+pub mod async_connection;
+pub mod bvh;
+pub mod client;
+pub mod geom;
+pub mod light;
+pub mod material;
+pub mod mesh;
+pub mod protocol;
+pub mod recording;
+pub mod shading;
+pub mod vec;
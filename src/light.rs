@@ -0,0 +1,274 @@
+use std::{num::ParseFloatError, str::FromStr};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+
+use crate::vec::{ParseVecError, Vec3};
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LightKind {
+    /// Light arriving from the same direction everywhere, like sunlight.
+    /// The direction is the way the light travels, as a unit vector. Lights
+    /// which are parsed or deserialized have theirs normalized.
+    Directional {
+        #[serde(deserialize_with = "unit_direction")]
+        direction: Vec3,
+    },
+    /// Light radiating in all directions from a single point
+    Point { position: Vec3 },
+    /// Light radiating from a single point, within a cone around `direction`.
+    /// The angle between the edge of the cone and the direction is given in degrees,
+    /// and the direction is a unit vector, like a directional light's.
+    Spot {
+        position: Vec3,
+        #[serde(deserialize_with = "unit_direction")]
+        direction: Vec3,
+        angle: f32,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Light {
+    pub kind: LightKind,
+    #[serde(default = "Light::default_color")]
+    pub color: Vec3,
+    #[serde(default = "Light::default_intensity")]
+    pub intensity: f32,
+    /// Point and spot lights with a radius are treated as glowing discs rather than
+    /// points, which makes the edges of their shadows soft.
+    #[serde(default)]
+    pub radius: f32,
+}
+
+/// The light arriving at a point from a particular light source
+#[derive(Debug, Copy, Clone)]
+pub struct Illumination {
+    /// Unit vector pointing from the point towards the light
+    pub direction: Vec3,
+    /// How far away the light is (infinite for directional lights)
+    pub distance: f32,
+    /// Colour and brightness of the light arriving at the point
+    pub radiance: Vec3,
+}
+
+impl Light {
+    fn default_color() -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
+
+    fn default_intensity() -> f32 {
+        1.0
+    }
+
+    /// Where the light is, if it has a position
+    pub fn position(&self) -> Option<Vec3> {
+        match self.kind {
+            LightKind::Directional { .. } => None,
+            LightKind::Point { position } | LightKind::Spot { position, .. } => Some(position),
+        }
+    }
+
+    /// Compute how this light illuminates the given point, ignoring anything
+    /// which might be in the way. Returns `None` if the point is not lit at all.
+    pub fn illuminate(&self, point: Vec3) -> Option<Illumination> {
+        let radiance = self.intensity * self.color;
+        match self.kind {
+            LightKind::Directional { direction } => Some(Illumination {
+                direction: -direction,
+                distance: f32::INFINITY,
+                radiance,
+            }),
+            LightKind::Point { position } => {
+                let offset = position - point;
+                let distance = offset.length();
+                Some(Illumination {
+                    direction: offset / distance,
+                    distance,
+                    // Light spreads out over the surface of a sphere as it travels
+                    radiance: (1.0 / distance.powi(2)) * radiance,
+                })
+            }
+            LightKind::Spot {
+                position,
+                direction,
+                angle,
+            } => {
+                let offset = position - point;
+                let distance = offset.length();
+                let to_light = offset / distance;
+
+                // Fade out over the outer fifth of the cone, rather than having a hard edge
+                let cos_angle = -to_light.dot(&direction);
+                let cos_outer = angle.to_radians().cos();
+                let cos_inner = (angle * 0.8).to_radians().cos();
+                let falloff = ((cos_angle - cos_outer) / (cos_inner - cos_outer)).clamp(0.0, 1.0);
+                if falloff <= 0.0 {
+                    return None;
+                }
+
+                Some(Illumination {
+                    direction: to_light,
+                    distance,
+                    radiance: (falloff / distance.powi(2)) * radiance,
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for Light {
+    type Err = ParseLightError;
+
+    /// Parse a light from one of the following forms, where each `<vec>` is
+    /// written as `x,y,z`:
+    ///
+    /// - `directional:<direction>[:<color>[:<intensity>]]`
+    /// - `point:<position>[:<color>[:<intensity>[:<radius>]]]`
+    /// - `spot:<position>:<direction>:<angle>[:<color>[:<intensity>[:<radius>]]]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split(':').collect();
+        let (kind, rest) = match parts[..] {
+            ["directional", direction, ref rest @ ..] => {
+                let direction = parse_direction(direction)?;
+                (LightKind::Directional { direction }, rest)
+            }
+            ["point", position, ref rest @ ..] => (
+                LightKind::Point {
+                    position: position.parse()?,
+                },
+                rest,
+            ),
+            ["spot", position, direction, angle, ref rest @ ..] => {
+                let direction = parse_direction(direction)?;
+                (
+                    LightKind::Spot {
+                        position: position.parse()?,
+                        direction,
+                        angle: angle.parse()?,
+                    },
+                    rest,
+                )
+            }
+            _ => return Err(ParseLightError::UnknownKind),
+        };
+        let is_directional = matches!(kind, LightKind::Directional { .. });
+        let (color, intensity, radius) = match *rest {
+            [] => (Self::default_color(), Self::default_intensity(), 0.0),
+            [color] => (color.parse()?, Self::default_intensity(), 0.0),
+            [color, intensity] => (color.parse()?, intensity.parse()?, 0.0),
+            [color, intensity, radius] if !is_directional => {
+                (color.parse()?, intensity.parse()?, radius.parse()?)
+            }
+            _ => return Err(ParseLightError::TooManyParts),
+        };
+        Ok(Self {
+            kind,
+            color,
+            intensity,
+            radius,
+        })
+    }
+}
+
+/// Parse a light's direction, and scale it to unit length
+fn parse_direction(s: &str) -> Result<Vec3, ParseLightError> {
+    let direction: Vec3 = s.parse()?;
+    direction
+        .try_normalize()
+        .ok_or(ParseLightError::ZeroDirection)
+}
+
+/// Deserialize a light's direction, and scale it to unit length so that it
+/// doesn't need normalizing every time the light is used
+fn unit_direction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec3, D::Error> {
+    Vec3::deserialize(deserializer)?
+        .try_normalize()
+        .ok_or_else(|| D::Error::custom("light direction must not be zero"))
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseLightError {
+    #[error(
+        "Expected directional:<direction>, point:<position> or spot:<position>:<direction>:<angle>"
+    )]
+    UnknownKind,
+    #[error("Too many parts")]
+    TooManyParts,
+    #[error("Direction must not be zero")]
+    ZeroDirection,
+    #[error("Invalid vector")]
+    InvalidVector(#[from] ParseVecError),
+    #[error("Invalid number")]
+    InvalidNumber(#[from] ParseFloatError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_directional() {
+        let light: Light = "directional:0,-2,0".parse().unwrap();
+        assert_eq!(
+            light,
+            Light {
+                kind: LightKind::Directional {
+                    direction: Vec3::new(0.0, -1.0, 0.0)
+                },
+                color: Vec3::new(1.0, 1.0, 1.0),
+                intensity: 1.0,
+                radius: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_spot_with_color() {
+        let light: Light = "spot:0,5,0:0,-1,0:30:1,0.5,0:20".parse().unwrap();
+        assert_eq!(light.color, Vec3::new(1.0, 0.5, 0.0));
+        assert_eq!(light.intensity, 20.0);
+        assert!(matches!(light.kind, LightKind::Spot { angle, .. } if angle == 30.0));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            "area:0,0,0".parse::<Light>(),
+            Err(ParseLightError::UnknownKind)
+        );
+        assert_eq!(
+            "point:0,0,0:1,1,1:1:1:1".parse::<Light>(),
+            Err(ParseLightError::TooManyParts)
+        );
+        // Directional lights are infinitely far away, so can't have a size
+        assert_eq!(
+            "directional:0,0,1:1,1,1:1:1".parse::<Light>(),
+            Err(ParseLightError::TooManyParts)
+        );
+        assert!(matches!(
+            "point:0,0".parse::<Light>(),
+            Err(ParseLightError::InvalidVector(_))
+        ));
+        assert_eq!(
+            "spot:0,0,0:0,0,0:30".parse::<Light>(),
+            Err(ParseLightError::ZeroDirection)
+        );
+    }
+
+    #[test]
+    fn point_light_falls_off() {
+        let light: Light = "point:0,0,0".parse().unwrap();
+        let near = light.illuminate(Vec3::new(0.0, 1.0, 0.0)).unwrap();
+        let far = light.illuminate(Vec3::new(0.0, 2.0, 0.0)).unwrap();
+        assert_eq!(near.direction, Vec3::new(0.0, -1.0, 0.0));
+        assert_eq!(near.radiance.x, 4.0 * far.radiance.x);
+    }
+
+    #[test]
+    fn spot_light_cone() {
+        let light: Light = "spot:0,0,0:0,0,1:10".parse().unwrap();
+        assert!(light.illuminate(Vec3::new(0.0, 0.0, 5.0)).is_some());
+        assert!(light.illuminate(Vec3::new(5.0, 0.0, 5.0)).is_none());
+    }
+}
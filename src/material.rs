@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::vec::Vec3;
+
+/// Describes how the surface of an object interacts with light
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Material {
+    /// Base colour of the surface. When omitted, a colour is picked from the
+    /// palette based on the object's position in the scene.
+    pub albedo: Option<Vec3>,
+    /// How much light is reflected at grazing angles, from 0 to 1. Surfaces
+    /// viewed head-on reflect less.
+    pub reflectivity: f32,
+    /// How blurry reflections are, from 0 (a perfect mirror) to 1
+    pub roughness: f32,
+    /// Light given off by the surface, regardless of any lighting
+    pub emissive: Vec3,
+    /// How much light passes through the object, from 0 (opaque) to 1
+    pub transparency: f32,
+    /// Index of refraction of transparent materials, which controls how much light
+    /// bends as it enters and leaves. Air is 1.0 and glass is around 1.5.
+    pub ior: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            albedo: None,
+            reflectivity: 0.7,
+            roughness: 0.0,
+            emissive: Vec3::new(0.0, 0.0, 0.0),
+            transparency: 0.0,
+            ior: 1.0,
+        }
+    }
+}
+
+impl Material {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
@@ -0,0 +1,977 @@
+//! Triangle meshes, loaded from Wavefront OBJ or PLY files.
+//!
+//! OBJ files may contain polygons with any number of corners, which are split
+//! into triangles. Normals given for every corner of every face are
+//! interpolated across each triangle, for smooth shading; otherwise triangles
+//! are shaded flat. Texture coordinates, materials and groups are ignored.
+//! Meshes can be written back out as OBJ files, with a normal for every
+//! corner if they're smooth.
+//!
+//! PLY files may be ASCII or binary (either endianness). The `x`, `y` and `z`
+//! properties of each vertex give its position, and `nx`, `ny` and `nz` its
+//! normal, if present. Faces are read from the `vertex_indices` (or
+//! `vertex_index`) list of each face. Any other elements and properties are
+//! skipped.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use byteorder::{ByteOrder, ReadBytesExt, BE, LE};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::bvh::{Aabb, Bvh};
+use crate::geom::{Intersection, Ray, Transform};
+use crate::material::Material;
+use crate::vec::Vec3;
+
+/// A surface made of triangles, with its own BVH over them. The BVH is built
+/// the first time a ray is traced through the mesh.
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    /// The normal at each vertex, or empty if the triangles are shaded flat
+    normals: Vec<Vec3>,
+    /// Indices into `positions` of the corners of each triangle
+    triangles: Vec<[u32; 3]>,
+    bvh: OnceLock<Bvh>,
+}
+
+/// A mesh placed in a scene. Instances of the same mesh share its triangles
+/// and BVH, so a mesh can appear many times without costing much more.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshInstance {
+    pub mesh: Arc<Mesh>,
+    #[serde(default)]
+    pub material: Material,
+    #[serde(default, skip_serializing_if = "Transform::is_identity")]
+    pub transform: Transform,
+}
+
+#[derive(Debug, Error)]
+pub enum MeshError {
+    #[error("Failed to read mesh file")]
+    Io(#[from] io::Error),
+    #[error("Line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+    #[error("Unknown mesh format (expected .obj or .ply): {}", .0.display())]
+    UnknownFormat(PathBuf),
+    #[error("Triangle {triangle} refers to vertex {index}, but there are only {count}")]
+    InvalidIndex {
+        triangle: usize,
+        index: u32,
+        count: usize,
+    },
+    #[error("Expected a normal for each of the {positions} vertices, but there are {normals}")]
+    NormalCount { positions: usize, normals: usize },
+}
+
+impl PartialEq for Mesh {
+    /// Meshes are equal if they have the same triangles, whether or not their
+    /// BVHs have been built yet
+    fn eq(&self, other: &Self) -> bool {
+        self.positions == other.positions
+            && self.normals == other.normals
+            && self.triangles == other.triangles
+    }
+}
+
+impl Mesh {
+    /// Make a mesh from its vertices and triangles. `normals` must either be
+    /// empty, or contain one normal for each position.
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        triangles: Vec<[u32; 3]>,
+    ) -> Result<Self, MeshError> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(MeshError::NormalCount {
+                positions: positions.len(),
+                normals: normals.len(),
+            });
+        }
+        for (triangle, corners) in triangles.iter().enumerate() {
+            if let Some(&index) = corners.iter().find(|&&i| i as usize >= positions.len()) {
+                return Err(MeshError::InvalidIndex {
+                    triangle,
+                    index,
+                    count: positions.len(),
+                });
+            }
+        }
+        Ok(Self {
+            positions,
+            normals,
+            triangles,
+            bvh: OnceLock::new(),
+        })
+    }
+
+    /// Load a mesh, choosing the format from the file extension
+    pub fn load(path: &Path) -> Result<Self, MeshError> {
+        let reader = || -> io::Result<_> { Ok(BufReader::new(File::open(path)?)) };
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("obj") => Self::from_obj(reader()?),
+            Some("ply") => Self::from_ply(reader()?),
+            _ => Err(MeshError::UnknownFormat(path.to_owned())),
+        }
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> &[Vec3] {
+        &self.normals
+    }
+
+    pub fn triangles(&self) -> &[[u32; 3]] {
+        &self.triangles
+    }
+
+    fn corners(&self, triangle: usize) -> [Vec3; 3] {
+        self.triangles[triangle].map(|i| self.positions[i as usize])
+    }
+
+    fn bvh(&self) -> &Bvh {
+        self.bvh.get_or_init(|| {
+            let bounds: Vec<_> = (0..self.triangles.len())
+                .map(|i| {
+                    self.corners(i)
+                        .into_iter()
+                        .fold(Aabb::empty(), |bounds, corner| bounds.grow(corner))
+                })
+                .collect();
+            Bvh::from_bounds(&bounds)
+        })
+    }
+
+    fn intersect_triangle(&self, ray: &Ray, triangle: usize) -> Option<Intersection> {
+        let [a, b, c] = self.corners(triangle);
+        let (distance, u, v) = ray.triangle_hit(a, b, c)?;
+        let flat = (b - a).cross(&(c - a));
+        let normal = if self.normals.is_empty() {
+            flat
+        } else {
+            let [na, nb, nc] = self.triangles[triangle].map(|i| self.normals[i as usize]);
+            let smooth = (1.0 - u - v) * na + u * nb + v * nc;
+            // Normals pointing in opposite directions at the corners can cancel out
+            if smooth.length() > 0.0 {
+                smooth
+            } else {
+                flat
+            }
+        };
+        Some(ray.at(distance, normal.normalize()))
+    }
+
+    /// Find where the ray first hits the mesh. Like other flat shapes, meshes
+    /// can be hit from either side.
+    pub fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.bvh()
+            .closest_hit_with(ray, |i| self.intersect_triangle(ray, i))
+            .map(|(_, intersection)| intersection)
+    }
+
+    /// Check whether the ray hits the mesh before travelling `max_distance`
+    pub fn hits_any(&self, ray: &Ray, max_distance: f32) -> bool {
+        self.bvh()
+            .hits_any_with(ray, max_distance, |i| self.intersect_triangle(ray, i))
+    }
+}
+
+/// The layout of a mesh in every format
+#[derive(Serialize)]
+#[serde(rename = "Mesh")]
+struct MeshRef<'a> {
+    positions: &'a [Vec3],
+    normals: &'a [Vec3],
+    triangles: &'a [[u32; 3]],
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Mesh")]
+struct MeshData {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    triangles: Vec<[u32; 3]>,
+}
+
+impl Serialize for Mesh {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MeshRef {
+            positions: &self.positions,
+            normals: &self.normals,
+            triangles: &self.triangles,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mesh {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = MeshData::deserialize(deserializer)?;
+        Mesh::new(data.positions, data.normals, data.triangles).map_err(de::Error::custom)
+    }
+}
+
+impl MeshInstance {
+    /// Place a mesh in the scene as it is, with the default material
+    pub fn new(mesh: Arc<Mesh>) -> Self {
+        Self {
+            mesh,
+            material: Material::default(),
+            transform: Transform::default(),
+        }
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        ray.intersect_transformed(&self.transform, |ray| self.mesh.intersect(ray))
+    }
+
+    pub fn hits_any(&self, ray: &Ray, max_distance: f32) -> bool {
+        if self.transform.is_identity() {
+            return self.mesh.hits_any(ray, max_distance);
+        }
+        let (ray, stretch) = self.transform.ray_to_object(ray);
+        self.mesh.hits_any(&ray, stretch * max_distance)
+    }
+}
+
+fn parse_error(line: usize, reason: impl Into<String>) -> MeshError {
+    MeshError::Parse {
+        line,
+        reason: reason.into(),
+    }
+}
+
+/// Parse whitespace separated numbers into a vector
+fn parse_vec3<'a>(
+    line: usize,
+    mut words: impl Iterator<Item = &'a str>,
+) -> Result<Vec3, MeshError> {
+    let mut next = || -> Result<f32, MeshError> {
+        let word = words
+            .next()
+            .ok_or_else(|| parse_error(line, "Expected three coordinates"))?;
+        word.parse()
+            .map_err(|_| parse_error(line, format!("Invalid number {:?}", word)))
+    };
+    Ok(Vec3::new(next()?, next()?, next()?))
+}
+
+/// Split a polygon into a fan of triangles sharing its first corner
+fn fan(corners: &[u32]) -> impl Iterator<Item = [u32; 3]> + '_ {
+    corners
+        .windows(2)
+        .skip(1)
+        .map(|pair| [corners[0], pair[0], pair[1]])
+}
+
+impl Mesh {
+    pub fn from_obj(reader: impl BufRead) -> Result<Self, MeshError> {
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        // The position and normal index of each corner of each face
+        let mut faces: Vec<Vec<(usize, Option<usize>)>> = Vec::new();
+
+        for (i, text) in reader.lines().enumerate() {
+            let text = text?;
+            let line = i + 1;
+            let mut words = text.split_whitespace();
+            match words.next() {
+                Some("v") => positions.push(parse_vec3(line, words)?),
+                Some("vn") => normals.push(parse_vec3(line, words)?),
+                Some("f") => {
+                    // Indices count from 1, or back from the most recent vertex if negative
+                    let resolve = |word: &str, count: usize| -> Result<usize, MeshError> {
+                        let index: i64 = word
+                            .parse()
+                            .map_err(|_| parse_error(line, format!("Invalid index {:?}", word)))?;
+                        let resolved = if index < 0 {
+                            count as i64 + index
+                        } else {
+                            index - 1
+                        };
+                        if resolved < 0 || resolved >= count as i64 {
+                            return Err(parse_error(line, format!("No vertex {}", index)));
+                        }
+                        Ok(resolved as usize)
+                    };
+                    // Corners are written as position/texture/normal, where the last two are optional
+                    let face = words
+                        .map(|word| {
+                            let mut indices = word.split('/');
+                            let position = resolve(indices.next().unwrap_or(""), positions.len())?;
+                            let normal = match indices.nth(1) {
+                                Some(normal) if !normal.is_empty() => {
+                                    Some(resolve(normal, normals.len())?)
+                                }
+                                _ => None,
+                            };
+                            Ok((position, normal))
+                        })
+                        .collect::<Result<Vec<_>, MeshError>>()?;
+                    if face.len() < 3 {
+                        return Err(parse_error(line, "Faces need at least three corners"));
+                    }
+                    faces.push(face);
+                }
+                _ => {}
+            }
+        }
+
+        let smooth = faces.iter().flatten().all(|(_, normal)| normal.is_some());
+        if !smooth || faces.is_empty() {
+            let triangles = faces
+                .iter()
+                .flat_map(|face| {
+                    let corners: Vec<_> = face.iter().map(|&(i, _)| i as u32).collect();
+                    fan(&corners).collect::<Vec<_>>()
+                })
+                .collect();
+            return Mesh::new(positions, Vec::new(), triangles);
+        }
+
+        // Corners which share a position can have different normals, so each
+        // combination of the two becomes a vertex of its own
+        let mut vertices = HashMap::new();
+        let mut mesh_positions = Vec::new();
+        let mut mesh_normals = Vec::new();
+        let mut triangles = Vec::new();
+        for face in &faces {
+            let corners: Vec<u32> = face
+                .iter()
+                .map(|&(position, normal)| {
+                    let normal = normal.expect("Every corner to have a normal");
+                    *vertices.entry((position, normal)).or_insert_with(|| {
+                        mesh_positions.push(positions[position]);
+                        mesh_normals.push(normals[normal]);
+                        mesh_positions.len() as u32 - 1
+                    })
+                })
+                .collect();
+            triangles.extend(fan(&corners));
+        }
+        Mesh::new(mesh_positions, mesh_normals, triangles)
+    }
+
+    /// Write the mesh as an OBJ file, which `from_obj` reads back as the same mesh
+    pub fn write_obj(&self, mut writer: impl Write) -> io::Result<()> {
+        for v in &self.positions {
+            writeln!(writer, "v {} {} {}", v.x, v.y, v.z)?;
+        }
+        for n in &self.normals {
+            writeln!(writer, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+        for triangle in &self.triangles {
+            // OBJ indices count from 1, and smooth meshes have a normal for each vertex
+            let [a, b, c] = triangle.map(|i| i + 1);
+            if self.normals.is_empty() {
+                writeln!(writer, "f {a} {b} {c}")?;
+            } else {
+                writeln!(writer, "f {a}//{a} {b}//{b} {c}//{c}")?;
+            }
+        }
+        writer.flush()
+    }
+
+    pub fn from_ply(mut reader: impl BufRead) -> Result<Self, MeshError> {
+        let (header, mut line) = read_ply_header(&mut reader)?;
+        let end_header = line;
+        let mut body = match header.format {
+            PlyFormat::Ascii => PlyBody::Ascii {
+                reader: &mut reader,
+                line: &mut line,
+            },
+            PlyFormat::BinaryLittleEndian => PlyBody::Binary {
+                reader: &mut reader,
+                big_endian: false,
+            },
+            PlyFormat::BinaryBigEndian => PlyBody::Binary {
+                reader: &mut reader,
+                big_endian: true,
+            },
+        };
+
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut triangles = Vec::new();
+        for element in &header.elements {
+            let find = |name: &str| element.properties.iter().position(|p| p.name == name);
+            let position = [find("x"), find("y"), find("z")];
+            let normal = [find("nx"), find("ny"), find("nz")];
+            let indices = find("vertex_indices").or_else(|| find("vertex_index"));
+            for _ in 0..element.count {
+                let values = body.read_element(element)?;
+                let scalar = |i: Option<usize>| match i.map(|i| &values[i]) {
+                    Some(PlyValue::Scalar(value)) => *value as f32,
+                    _ => 0.0,
+                };
+                match element.name.as_str() {
+                    "vertex" => {
+                        let [x, y, z] = position.map(scalar);
+                        positions.push(Vec3::new(x, y, z));
+                        if normal.iter().all(Option::is_some) {
+                            let [x, y, z] = normal.map(scalar);
+                            normals.push(Vec3::new(x, y, z));
+                        }
+                    }
+                    "face" => {
+                        if let Some(PlyValue::List(corners)) = indices.map(|i| &values[i]) {
+                            let line = body.line(end_header);
+                            let corners = corners
+                                .iter()
+                                .map(|&index| {
+                                    if index >= 0.0
+                                        && index.fract() == 0.0
+                                        && index <= u32::MAX as f64
+                                    {
+                                        Ok(index as u32)
+                                    } else {
+                                        Err(parse_error(line, format!("Invalid index {}", index)))
+                                    }
+                                })
+                                .collect::<Result<Vec<_>, _>>()?;
+                            if corners.len() < 3 {
+                                return Err(parse_error(line, "Faces need at least three corners"));
+                            }
+                            triangles.extend(fan(&corners));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Mesh::new(positions, normals, triangles)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PlyScalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PlyType {
+    Scalar(PlyScalar),
+    /// A list of values, preceded by how many there are
+    List {
+        count: PlyScalar,
+        item: PlyScalar,
+    },
+}
+
+#[derive(Debug)]
+struct PlyProperty {
+    name: String,
+    kind: PlyType,
+}
+
+#[derive(Debug)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+#[derive(Debug)]
+struct PlyHeader {
+    format: PlyFormat,
+    elements: Vec<PlyElement>,
+}
+
+enum PlyValue {
+    Scalar(f64),
+    List(Vec<f64>),
+}
+
+impl PlyScalar {
+    fn parse(line: usize, name: &str) -> Result<Self, MeshError> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(parse_error(line, format!("Unknown type {:?}", name))),
+        })
+    }
+
+    fn read<E: ByteOrder>(self, reader: &mut impl Read) -> io::Result<f64> {
+        Ok(match self {
+            Self::I8 => reader.read_i8()? as f64,
+            Self::U8 => reader.read_u8()? as f64,
+            Self::I16 => reader.read_i16::<E>()? as f64,
+            Self::U16 => reader.read_u16::<E>()? as f64,
+            Self::I32 => reader.read_i32::<E>()? as f64,
+            Self::U32 => reader.read_u32::<E>()? as f64,
+            Self::F32 => reader.read_f32::<E>()? as f64,
+            Self::F64 => reader.read_f64::<E>()?,
+        })
+    }
+}
+
+/// Read the header, up to and including the `end_header` line. Also returns
+/// the number of lines read, so that errors in ASCII bodies can be located.
+fn read_ply_header(reader: &mut impl BufRead) -> Result<(PlyHeader, usize), MeshError> {
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    let mut line = 0;
+    let mut text = Vec::new();
+    loop {
+        text.clear();
+        if reader.read_until(b'\n', &mut text)? == 0 {
+            return Err(parse_error(line, "Missing end_header"));
+        }
+        line += 1;
+        let text = String::from_utf8_lossy(&text);
+        let words: Vec<_> = text.split_whitespace().collect();
+        if line == 1 {
+            if words != ["ply"] {
+                return Err(parse_error(line, "Not a PLY file"));
+            }
+            continue;
+        }
+        match words[..] {
+            ["format", kind, _version] => {
+                format = Some(match kind {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                    "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                    _ => return Err(parse_error(line, format!("Unknown format {:?}", kind))),
+                })
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_owned(),
+                count: count
+                    .parse()
+                    .map_err(|_| parse_error(line, format!("Invalid count {:?}", count)))?,
+                properties: Vec::new(),
+            }),
+            ["property", ref rest @ ..] => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| parse_error(line, "Property before any element"))?;
+                let (kind, name) = match rest {
+                    ["list", count, item, name] => (
+                        PlyType::List {
+                            count: PlyScalar::parse(line, count)?,
+                            item: PlyScalar::parse(line, item)?,
+                        },
+                        name,
+                    ),
+                    [kind, name] => (PlyType::Scalar(PlyScalar::parse(line, kind)?), name),
+                    _ => return Err(parse_error(line, "Invalid property")),
+                };
+                element.properties.push(PlyProperty {
+                    name: name.to_string(),
+                    kind,
+                });
+            }
+            ["end_header"] => break,
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(parse_error(line, format!("Unexpected {:?}", text.trim()))),
+        }
+    }
+    let format = format.ok_or_else(|| parse_error(line, "Missing format"))?;
+    Ok((PlyHeader { format, elements }, line))
+}
+
+/// The elements following the header
+enum PlyBody<'a, R> {
+    /// One element per line, with its values separated by spaces
+    Ascii {
+        reader: &'a mut R,
+        line: &'a mut usize,
+    },
+    Binary {
+        reader: &'a mut R,
+        big_endian: bool,
+    },
+}
+
+impl<R: BufRead> PlyBody<'_, R> {
+    /// The line the last element was read from, for reporting errors. Binary
+    /// bodies don't have lines, so their errors point at the end of the header.
+    fn line(&self, end_header: usize) -> usize {
+        match self {
+            PlyBody::Ascii { line, .. } => **line,
+            PlyBody::Binary { .. } => end_header,
+        }
+    }
+
+    fn read_element(&mut self, element: &PlyElement) -> Result<Vec<PlyValue>, MeshError> {
+        match self {
+            PlyBody::Ascii { reader, line } => {
+                let mut text = String::new();
+                while text.trim().is_empty() {
+                    text.clear();
+                    if reader.read_line(&mut text)? == 0 {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    **line += 1;
+                }
+                let line = **line;
+                let mut words = text.split_whitespace();
+                let mut next = || -> Result<f64, MeshError> {
+                    let word = words
+                        .next()
+                        .ok_or_else(|| parse_error(line, "Too few values"))?;
+                    word.parse()
+                        .map_err(|_| parse_error(line, format!("Invalid number {:?}", word)))
+                };
+                element
+                    .properties
+                    .iter()
+                    .map(|property| match property.kind {
+                        PlyType::Scalar(_) => Ok(PlyValue::Scalar(next()?)),
+                        PlyType::List { .. } => {
+                            let count = next()? as usize;
+                            Ok(PlyValue::List(
+                                (0..count).map(|_| next()).collect::<Result<_, _>>()?,
+                            ))
+                        }
+                    })
+                    .collect()
+            }
+            PlyBody::Binary { reader, big_endian } => {
+                let read = |reader: &mut R, scalar: PlyScalar| {
+                    if *big_endian {
+                        scalar.read::<BE>(reader)
+                    } else {
+                        scalar.read::<LE>(reader)
+                    }
+                };
+                element
+                    .properties
+                    .iter()
+                    .map(|property| match property.kind {
+                        PlyType::Scalar(scalar) => Ok(PlyValue::Scalar(read(reader, scalar)?)),
+                        PlyType::List { count, item } => {
+                            let count = read(reader, count)? as usize;
+                            Ok(PlyValue::List(
+                                (0..count)
+                                    .map(|_| read(reader, item))
+                                    .collect::<Result<_, _>>()?,
+                            ))
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    /// A unit square in the z = 5 plane, split into two triangles
+    fn square() -> Mesh {
+        let positions = vec![
+            Vec3::new(0.0, 0.0, 5.0),
+            Vec3::new(1.0, 0.0, 5.0),
+            Vec3::new(1.0, 1.0, 5.0),
+            Vec3::new(0.0, 1.0, 5.0),
+        ];
+        Mesh::new(positions, Vec::new(), vec![[0, 1, 2], [0, 2, 3]]).unwrap()
+    }
+
+    #[test]
+    fn obj_polygons() {
+        let obj = "# A square, written as one polygon
+v 0 0 5
+v 1 0 5
+v 1 1 5
+v 0 1 5
+vt 0 0
+f 1/1 2/1 -2/1 -1/1
+";
+        assert_eq!(Mesh::from_obj(obj.as_bytes()).unwrap(), square());
+    }
+
+    #[test]
+    fn obj_normals() {
+        // Two triangles sharing an edge, with a crease along it
+        let obj = "v 0 0 0
+v 1 0 0
+v 0 1 0
+v 1 1 1
+vn 0 0 1
+vn 1 0 0
+f 1//1 2//1 3//1
+f 2//2 4//2 3//2
+";
+        let mesh = Mesh::from_obj(obj.as_bytes()).unwrap();
+        assert_eq!(mesh.positions().len(), 6);
+        assert_eq!(mesh.triangles(), [[0, 1, 2], [3, 4, 5]]);
+        assert_eq!(mesh.normals()[4], Vec3::new(1.0, 0.0, 0.0));
+
+        // Normals are ignored unless every corner has one
+        let obj = obj.replace("f 1//1", "f 1");
+        let mesh = Mesh::from_obj(obj.as_bytes()).unwrap();
+        assert_eq!(mesh.positions().len(), 4);
+        assert!(mesh.normals().is_empty());
+    }
+
+    #[test]
+    fn obj_round_trip() {
+        let smooth = "v 0 0 0
+v 1 0 0
+v 0 1 0
+v 1 1 1.5
+vn 0 0 1
+vn 0.6 0 0.8
+f 1//1 2//1 3//1
+f 2//2 4//2 3//2
+";
+        let flat = "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0.25\nf 1 2 3 4\n";
+        for obj in [smooth, flat] {
+            let mesh = Mesh::from_obj(obj.as_bytes()).unwrap();
+            let mut written = Vec::new();
+            mesh.write_obj(&mut written).unwrap();
+            assert_eq!(Mesh::from_obj(&written[..]).unwrap(), mesh);
+        }
+    }
+
+    #[test]
+    fn obj_errors() {
+        let error = Mesh::from_obj("v 0 0 0\nv 1 0 0\nf 1 2 3\n".as_bytes()).unwrap_err();
+        assert!(
+            matches!(error, MeshError::Parse { line: 3, .. }),
+            "{}",
+            error
+        );
+        let error = Mesh::from_obj("v 0 zero 0\n".as_bytes()).unwrap_err();
+        assert!(
+            matches!(error, MeshError::Parse { line: 1, .. }),
+            "{}",
+            error
+        );
+    }
+
+    const PLY_HEADER: &str = "element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+element face 1
+property list uchar int vertex_indices
+element edge 1
+property int vertex1
+property int vertex2
+end_header
+";
+
+    #[test]
+    fn ply_ascii() {
+        let ply = format!(
+            "ply\nformat ascii 1.0\ncomment A square\n{}\
+0 0 5 255
+1 0 5 255
+1 1 5 255
+0 1 5 255
+4 0 1 2 3
+0 1
+",
+            PLY_HEADER
+        );
+        assert_eq!(Mesh::from_ply(ply.as_bytes()).unwrap(), square());
+    }
+
+    #[test]
+    fn ply_binary() {
+        fn write<E: ByteOrder>(format: &str) -> Vec<u8> {
+            let mut data = format!("ply\nformat {} 1.0\n{}", format, PLY_HEADER).into_bytes();
+            for position in square().positions() {
+                for coordinate in [position.x, position.y, position.z] {
+                    data.write_f32::<E>(coordinate).unwrap();
+                }
+                data.write_u8(255).unwrap();
+            }
+            data.write_u8(4).unwrap();
+            for index in 0..4 {
+                data.write_i32::<E>(index).unwrap();
+            }
+            data.write_i32::<E>(0).unwrap();
+            data.write_i32::<E>(1).unwrap();
+            data
+        }
+        let little = write::<LE>("binary_little_endian");
+        assert_eq!(Mesh::from_ply(&little[..]).unwrap(), square());
+        let big = write::<BE>("binary_big_endian");
+        assert_eq!(Mesh::from_ply(&big[..]).unwrap(), square());
+
+        // Files which end early are reported rather than padded out
+        let error = Mesh::from_ply(&big[..big.len() - 9]).unwrap_err();
+        assert!(matches!(error, MeshError::Io(_)), "{}", error);
+    }
+
+    #[test]
+    fn invalid_meshes() {
+        let error = Mesh::new(square().positions.clone(), Vec::new(), vec![[0, 1, 4]]);
+        assert!(matches!(
+            error,
+            Err(MeshError::InvalidIndex {
+                triangle: 0,
+                index: 4,
+                count: 4
+            })
+        ));
+        let normals = vec![Vec3::new(0.0, 0.0, 1.0)];
+        let error = Mesh::new(square().positions.clone(), normals, vec![[0, 1, 2]]);
+        assert!(matches!(error, Err(MeshError::NormalCount { .. })));
+
+        for (face, reason) in [
+            ("3 0 1 -1", "Invalid index -1"),
+            ("3 0 1 1.5", "Invalid index 1.5"),
+            ("2 0 1", "Faces need at least three corners"),
+        ] {
+            let ply = format!(
+                "ply\nformat ascii 1.0\n{}0 0 5 255\n1 0 5 255\n1 1 5 255\n0 1 5 255\n{}\n0 1\n",
+                PLY_HEADER, face
+            );
+            let error = Mesh::from_ply(ply.as_bytes()).unwrap_err();
+            assert_eq!(error.to_string(), format!("Line 18: {}", reason));
+        }
+    }
+
+    #[test]
+    fn flat_and_smooth() {
+        let hit = square()
+            .intersect(&ray(Vec3::new(0.25, 0.75, 0.0), Vec3::new(0.0, 0.0, 1.0)))
+            .unwrap();
+        assert_eq!(hit.distance, 5.0);
+        assert_eq!(hit.position, Vec3::new(0.25, 0.75, 5.0));
+        assert_eq!(hit.normal, Vec3::new(0.0, 0.0, 1.0));
+
+        // Normals are interpolated from the corners of the triangle that's hit
+        let tilted = [
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(-1.0, 0.0, 1.0),
+        ];
+        let square = square();
+        let smooth = Mesh::new(
+            square.positions.clone(),
+            tilted.to_vec(),
+            square.triangles.clone(),
+        )
+        .unwrap();
+        let normal_at = |x| {
+            smooth
+                .intersect(&ray(Vec3::new(x, 0.5, 0.0), Vec3::new(0.0, 0.0, 1.0)))
+                .unwrap()
+                .normal
+        };
+        assert_eq!(normal_at(0.5), Vec3::new(0.0, 0.0, 1.0));
+        assert!((normal_at(0.75) - Vec3::new(0.5, 0.0, 1.0).normalize()).length() < 1e-6);
+    }
+
+    #[test]
+    fn instances() {
+        let instance = MeshInstance {
+            transform: Transform::new(
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(2.0, 2.0, 2.0),
+            )
+            .unwrap(),
+            ..MeshInstance::new(Arc::new(square()))
+        };
+        let ray = ray(Vec3::new(2.5, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let hit = instance.intersect(&ray).unwrap();
+        assert_eq!(hit.distance, 10.0);
+        assert_eq!(hit.position, Vec3::new(2.5, 1.0, 10.0));
+        assert!(instance.hits_any(&ray, 11.0));
+        assert!(!instance.hits_any(&ray, 9.0));
+    }
+
+    #[test]
+    fn bvh_agrees_with_every_triangle() {
+        // A bumpy grid of triangles, seen from above
+        let size = 12;
+        let positions = (0..=size)
+            .flat_map(|z| {
+                (0..=size).map(move |x| {
+                    let height = ((x * 7 + z * 3) % 5) as f32 * 0.2;
+                    Vec3::new(x as f32, height, z as f32)
+                })
+            })
+            .collect();
+        let corner = |x: u32, z: u32| z * (size + 1) + x;
+        let triangles = (0..size)
+            .flat_map(|z| {
+                (0..size).flat_map(move |x| {
+                    [
+                        [corner(x, z), corner(x + 1, z), corner(x + 1, z + 1)],
+                        [corner(x, z), corner(x + 1, z + 1), corner(x, z + 1)],
+                    ]
+                })
+            })
+            .collect();
+        let mesh = Mesh::new(positions, Vec::new(), triangles).unwrap();
+
+        for i in 0..200 {
+            let angle = i as f32 * 2.399_963;
+            let origin = Vec3::new(6.0, 5.0, 6.0);
+            let direction = Vec3::new(angle.cos(), -1.0 + (i % 7) as f32 * 0.3, angle.sin());
+            let ray = ray(origin, direction);
+            let expected = (0..mesh.triangles().len())
+                .filter_map(|triangle| mesh.intersect_triangle(&ray, triangle))
+                .min_by(|a, b| a.distance.total_cmp(&b.distance));
+            let actual = mesh.intersect(&ray);
+            assert_eq!(
+                actual.map(|hit| hit.distance),
+                expected.map(|hit| hit.distance),
+                "{:?}",
+                ray
+            );
+        }
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mesh = square();
+        let data = postcard::to_allocvec(&mesh).unwrap();
+        assert_eq!(postcard::from_bytes::<Mesh>(&data).unwrap(), mesh);
+        let json = serde_json::to_string(&mesh).unwrap();
+        assert_eq!(serde_json::from_str::<Mesh>(&json).unwrap(), mesh);
+
+        // Meshes are checked as they're decoded
+        let json = json.replace("[0,2,3]", "[0,2,7]");
+        assert!(serde_json::from_str::<Mesh>(&json).is_err());
+    }
+}
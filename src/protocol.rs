@@ -0,0 +1,1643 @@
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use serde::{
+    de::{self, DeserializeOwned},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use snap::raw::{decompress_len, Decoder, Encoder};
+use structopt::StructOpt;
+use thiserror::Error;
+
+use crate::geom::{Ray, Shape, Sphere, Transform};
+use crate::light::Light;
+use crate::material::Material;
+use crate::mesh::{Mesh, MeshInstance};
+use crate::vec::Vec3;
+
+/// The newest version of the protocol spoken by this crate. Version 1 sends
+/// messages uncompressed, and version 2 compresses them with snappy.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest version of the protocol spoken by this crate
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// As soon as the connection is established, the client sends a big-endian
+/// u32. Older clients send the protocol version they speak, but newer ones send
+/// this instead, followed by a `Hello` to negotiate the version. Servers which
+/// predate the handshake reject it as an unsupported version.
+pub const HANDSHAKE: u32 = u32::from_be_bytes(*b"RWHS");
+
+/// Servers which predate the handshake don't reply to the version a client
+/// states, but close the connection straight away if they don't speak it.
+/// Clients wait this long for that to happen before taking silence to mean the
+/// version was accepted.
+pub const LEGACY_REFUSAL_WAIT: Duration = Duration::from_millis(200);
+
+/// How long clients wait for the server to answer their `Hello`, when no
+/// timeout has been set for requests. Servers reply straight away, so this
+/// only stops a server which neither replies nor hangs up from holding up the
+/// client forever.
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a peer is able (or would prefer) to do, beyond what the protocol
+/// version requires
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Whether messages may be compressed. Compression saves bandwidth but
+    /// costs CPU time, which isn't worth it on fast local networks.
+    pub compression: bool,
+    /// The largest batch of rays the peer wants to handle at once
+    pub max_batch_size: Option<u32>,
+    /// Optional protocol features, as a set of bit flags. Peers ignore any
+    /// flags they don't know about. The `FEATURE_*` codec flags ask for
+    /// messages to be encoded with something other than the protocol
+    /// version's default.
+    pub features: u32,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            compression: true,
+            max_batch_size: None,
+            features: 0,
+        }
+    }
+}
+
+/// Sent by the client to start the handshake
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub capabilities: Capabilities,
+}
+
+/// The server's reply to a `Hello`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Accepted(Session),
+    Rejected(String),
+}
+
+/// What both sides of a connection agreed to during the handshake
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Session {
+    /// The session used by peers which don't know about the handshake, and
+    /// just state which version they speak
+    pub fn legacy(version: u32) -> Self {
+        Self {
+            version,
+            capabilities: Capabilities {
+                compression: version >= 2,
+                ..Capabilities::default()
+            },
+        }
+    }
+
+    /// How messages are laid out on the wire in this session. If several codecs
+    /// were agreed on, JSON is preferred, since it's only ever asked for when
+    /// debugging, followed by whichever compresses best.
+    pub fn framing(&self) -> Framing {
+        let features = self.capabilities.features;
+        if features & FEATURE_JSON != 0 {
+            Framing::Json
+        } else if features & FEATURE_ZSTD != 0 {
+            Framing::Zstd
+        } else if features & FEATURE_LZ4 != 0 {
+            Framing::Lz4
+        } else if self.version >= 2 {
+            Framing::Snappy
+        } else {
+            Framing::Plain
+        }
+    }
+}
+
+impl Hello {
+    /// Agree on the newest version of the protocol both sides speak, and the
+    /// capabilities they have in common.
+    pub fn negotiate(&self, server: &Capabilities) -> Result<Session, String> {
+        let min_version = self.min_version.max(MIN_PROTOCOL_VERSION);
+        let max_version = self.max_version.min(PROTOCOL_VERSION);
+        if min_version > max_version {
+            return Err(format!(
+                "No common protocol version: client speaks {}-{}, server speaks {}-{}",
+                self.min_version, self.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+
+        // Version 2 only adds compression, so don't use it if either side would rather not
+        let compression = self.capabilities.compression && server.compression;
+        let version = if compression {
+            max_version
+        } else {
+            min_version
+        };
+        let max_batch_size = match (self.capabilities.max_batch_size, server.max_batch_size) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if max_batch_size == Some(0) {
+            return Err("Batches of at most 0 rays would never finish a frame".into());
+        }
+        // Codecs which compress are no more welcome than snappy is
+        let mut features = self.capabilities.features & server.features;
+        if !compression {
+            features &= !(FEATURE_LZ4 | FEATURE_ZSTD);
+        }
+        Ok(Session {
+            version,
+            capabilities: Capabilities {
+                compression: version >= 2,
+                max_batch_size,
+                features,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    ReserveRays,
+    SubmitResults(Vec<Outcome>),
+    SetName(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outcome {
+    pub hit: bool,
+    pub color: Option<Vec3>,
+}
+
+#[derive(Debug)]
+pub enum Response {
+    ReserveRays(Vec<Ray>, Scene),
+    SubmitResults,
+    SetName,
+}
+
+/// The layout of a response in binary formats. The layout of `Scene` is fixed
+/// by version 2 of the protocol, so scenes with shapes other than spheres are
+/// sent as `ReserveRaysWithShapes` instead, which only peers that negotiated
+/// `FEATURE_SHAPES` understand. Likewise, scenes with meshes are sent as
+/// `ReserveRaysWithMeshes`, for peers that negotiated `FEATURE_MESHES`, and
+/// scenes with transformed objects as `ReserveRaysWithTransforms`, for peers
+/// that negotiated `FEATURE_TRANSFORMS`. Spheres made of anything but the
+/// default material need `ReserveRaysWithMaterials` and `FEATURE_MATERIALS`.
+/// Peers which negotiated `FEATURE_MESH_CACHE` are sent scenes with meshes as
+/// `ReserveRaysWithCachedMeshes`, by a `MeshCache`.
+#[derive(Serialize)]
+#[serde(rename = "Response")]
+enum WireResponseRef<'a> {
+    ReserveRays(&'a [Ray], &'a Scene),
+    SubmitResults,
+    SetName,
+    ReserveRaysWithShapes(&'a [Ray], &'a Scene, &'a [Shape]),
+    ReserveRaysWithMeshes(&'a [Ray], &'a Scene, &'a [Shape], Vec<LegacyMeshRef<'a>>),
+    ReserveRaysWithTransforms(&'a [Ray], &'a Scene, WireObjectsRef<'a, &'a Arc<Mesh>>),
+    ReserveRaysWithMaterials(
+        &'a [Ray],
+        &'a Scene,
+        WireObjectsRef<'a, &'a Arc<Mesh>>,
+        Vec<(u32, Material)>,
+    ),
+    ReserveRaysWithCachedMeshes(
+        &'a [Ray],
+        &'a Scene,
+        WireObjectsRef<'a, CachedMeshRef<'a>>,
+        Vec<(u32, Material)>,
+    ),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Response")]
+enum WireResponse {
+    ReserveRays(Vec<Ray>, Scene),
+    SubmitResults,
+    SetName,
+    ReserveRaysWithShapes(Vec<Ray>, Scene, Vec<Shape>),
+    ReserveRaysWithMeshes(Vec<Ray>, Scene, Vec<Shape>, Vec<LegacyMesh>),
+    ReserveRaysWithTransforms(Vec<Ray>, Scene, WireObjects<Mesh>),
+    /// The materials of the spheres which don't have the default, by index
+    ReserveRaysWithMaterials(Vec<Ray>, Scene, WireObjects<Mesh>, Vec<(u32, Material)>),
+    ReserveRaysWithCachedMeshes(
+        Vec<Ray>,
+        Scene,
+        WireObjects<CachedMesh>,
+        Vec<(u32, Material)>,
+    ),
+}
+
+/// The layout of a mesh in `ReserveRaysWithMeshes`, which predates instancing,
+/// so each mesh is sent with its material
+#[derive(Serialize)]
+#[serde(rename = "Mesh")]
+struct LegacyMeshRef<'a> {
+    positions: &'a [Vec3],
+    normals: &'a [Vec3],
+    triangles: &'a [[u32; 3]],
+    material: &'a Material,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Mesh")]
+struct LegacyMesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    triangles: Vec<[u32; 3]>,
+    material: Material,
+}
+
+/// The objects in a scene other than its spheres, as sent in
+/// `ReserveRaysWithTransforms`. Meshes are sent once, however many instances
+/// of them there are.
+#[derive(Serialize)]
+#[serde(rename = "Objects")]
+struct WireObjectsRef<'a, M> {
+    shapes: &'a [Shape],
+    meshes: Vec<M>,
+    instances: Vec<WireInstance>,
+    /// The spheres and shapes which have a transform, numbered with the
+    /// spheres first, followed by the shapes
+    transforms: Vec<(u32, Transform)>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Objects")]
+struct WireObjects<M> {
+    shapes: Vec<Shape>,
+    meshes: Vec<M>,
+    instances: Vec<WireInstance>,
+    transforms: Vec<(u32, Transform)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "MeshInstance")]
+struct WireInstance {
+    /// Index into the meshes sent alongside
+    mesh: u32,
+    material: Material,
+    transform: Transform,
+}
+
+/// The layout of a mesh in `ReserveRaysWithCachedMeshes`. Meshes are only sent
+/// in full the first time they're used on a connection, and by their position
+/// in the `MeshCache` after that.
+#[derive(Serialize)]
+#[serde(rename = "CachedMesh")]
+enum CachedMeshRef<'a> {
+    New(&'a Mesh),
+    Known(u32),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "CachedMesh")]
+enum CachedMesh {
+    New(Mesh),
+    Known(u32),
+}
+
+impl<'a> WireObjectsRef<'a, &'a Arc<Mesh>> {
+    fn new(scene: &'a Scene) -> Self {
+        let mut meshes: Vec<&Arc<Mesh>> = Vec::new();
+        let instances = scene
+            .meshes
+            .iter()
+            .map(|instance| {
+                let mesh = match meshes.iter().position(|&m| Arc::ptr_eq(m, &instance.mesh)) {
+                    Some(mesh) => mesh,
+                    None => {
+                        meshes.push(&instance.mesh);
+                        meshes.len() - 1
+                    }
+                };
+                WireInstance {
+                    mesh: mesh as u32,
+                    material: instance.material,
+                    transform: instance.transform,
+                }
+            })
+            .collect();
+        let transforms = scene
+            .spheres
+            .iter()
+            .map(|sphere| sphere.transform)
+            .chain(scene.shapes.iter().map(|shape| shape.transform))
+            .enumerate()
+            .filter(|(_, transform)| !transform.is_identity())
+            .map(|(i, transform)| (i as u32, transform))
+            .collect();
+        Self {
+            shapes: &scene.shapes,
+            meshes,
+            instances,
+            transforms,
+        }
+    }
+
+    fn map_meshes<M>(self, f: impl FnMut(&'a Arc<Mesh>) -> M) -> WireObjectsRef<'a, M> {
+        WireObjectsRef {
+            shapes: self.shapes,
+            meshes: self.meshes.into_iter().map(f).collect(),
+            instances: self.instances,
+            transforms: self.transforms,
+        }
+    }
+}
+
+impl<M> WireObjects<M> {
+    /// Add the objects to a scene which only has spheres, using `resolve` to
+    /// find each mesh
+    fn into_scene(
+        self,
+        scene: Scene,
+        resolve: impl FnMut(M) -> Result<Arc<Mesh>, &'static str>,
+    ) -> Result<Scene, &'static str> {
+        let meshes = self
+            .meshes
+            .into_iter()
+            .map(resolve)
+            .collect::<Result<Vec<_>, _>>()?;
+        let instances = self
+            .instances
+            .into_iter()
+            .map(|instance| {
+                Ok(MeshInstance {
+                    mesh: meshes
+                        .get(instance.mesh as usize)
+                        .ok_or("instance of a mesh which wasn't sent")?
+                        .clone(),
+                    material: instance.material,
+                    transform: instance.transform,
+                })
+            })
+            .collect::<Result<_, &'static str>>()?;
+        let mut scene = Scene {
+            shapes: self.shapes,
+            meshes: instances,
+            ..scene
+        };
+        for (index, transform) in self.transforms {
+            let index = index as usize;
+            let spheres = scene.spheres.len();
+            let object = match scene.spheres.get_mut(index) {
+                Some(sphere) => &mut sphere.transform,
+                None => match scene.shapes.get_mut(index - spheres) {
+                    Some(shape) => &mut shape.transform,
+                    None => return Err("transform of an object which wasn't sent"),
+                },
+            };
+            *object = transform;
+        }
+        Ok(scene)
+    }
+}
+
+/// The materials of the spheres which don't have the default, by index
+fn sphere_materials(scene: &Scene) -> Vec<(u32, Material)> {
+    scene
+        .spheres
+        .iter()
+        .enumerate()
+        .filter(|(_, sphere)| !sphere.material.is_default())
+        .map(|(i, sphere)| (i as u32, sphere.material))
+        .collect()
+}
+
+fn set_sphere_materials(
+    scene: &mut Scene,
+    materials: Vec<(u32, Material)>,
+) -> Result<(), &'static str> {
+    for (index, material) in materials {
+        scene
+            .spheres
+            .get_mut(index as usize)
+            .ok_or("material of a sphere which wasn't sent")?
+            .material = material;
+    }
+    Ok(())
+}
+
+impl Serialize for Response {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let human_readable = serializer.is_human_readable();
+        match self {
+            // Human-readable formats include everything in the scene
+            Response::ReserveRays(rays, scene)
+                if scene.has_sphere_materials() && !human_readable =>
+            {
+                WireResponseRef::ReserveRaysWithMaterials(
+                    rays,
+                    scene,
+                    WireObjectsRef::new(scene),
+                    sphere_materials(scene),
+                )
+            }
+            Response::ReserveRays(rays, scene) if scene.has_transforms() && !human_readable => {
+                WireResponseRef::ReserveRaysWithTransforms(rays, scene, WireObjectsRef::new(scene))
+            }
+            Response::ReserveRays(rays, scene) if !scene.meshes.is_empty() && !human_readable => {
+                let meshes = scene
+                    .meshes
+                    .iter()
+                    .map(|instance| LegacyMeshRef {
+                        positions: instance.mesh.positions(),
+                        normals: instance.mesh.normals(),
+                        triangles: instance.mesh.triangles(),
+                        material: &instance.material,
+                    })
+                    .collect();
+                WireResponseRef::ReserveRaysWithMeshes(rays, scene, &scene.shapes, meshes)
+            }
+            Response::ReserveRays(rays, scene) if !scene.shapes.is_empty() && !human_readable => {
+                WireResponseRef::ReserveRaysWithShapes(rays, scene, &scene.shapes)
+            }
+            Response::ReserveRays(rays, scene) => WireResponseRef::ReserveRays(rays, scene),
+            Response::SubmitResults => WireResponseRef::SubmitResults,
+            Response::SetName => WireResponseRef::SetName,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Response {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Responses which refer to meshes sent earlier on the connection can't
+        // be decoded on their own, and need the connection's `MeshCache`
+        WireResponse::deserialize(deserializer)?
+            .into_response(&mut MeshCache::default())
+            .map_err(de::Error::custom)
+    }
+}
+
+impl WireResponse {
+    fn into_response(self, cache: &mut MeshCache) -> Result<Response, BoxError> {
+        Ok(match self {
+            WireResponse::ReserveRays(rays, scene) => Response::ReserveRays(rays, scene),
+            WireResponse::SubmitResults => Response::SubmitResults,
+            WireResponse::SetName => Response::SetName,
+            WireResponse::ReserveRaysWithShapes(rays, scene, shapes) => {
+                Response::ReserveRays(rays, Scene { shapes, ..scene })
+            }
+            WireResponse::ReserveRaysWithMeshes(rays, scene, shapes, meshes) => {
+                let meshes = meshes
+                    .into_iter()
+                    .map(|mesh| {
+                        Ok(MeshInstance {
+                            material: mesh.material,
+                            ..MeshInstance::new(Arc::new(Mesh::new(
+                                mesh.positions,
+                                mesh.normals,
+                                mesh.triangles,
+                            )?))
+                        })
+                    })
+                    .collect::<Result<_, BoxError>>()?;
+                Response::ReserveRays(
+                    rays,
+                    Scene {
+                        shapes,
+                        meshes,
+                        ..scene
+                    },
+                )
+            }
+            WireResponse::ReserveRaysWithTransforms(rays, scene, objects) => {
+                Response::ReserveRays(rays, objects.into_scene(scene, |mesh| Ok(Arc::new(mesh)))?)
+            }
+            WireResponse::ReserveRaysWithMaterials(rays, scene, objects, materials) => {
+                let mut scene = objects.into_scene(scene, |mesh| Ok(Arc::new(mesh)))?;
+                set_sphere_materials(&mut scene, materials)?;
+                Response::ReserveRays(rays, scene)
+            }
+            WireResponse::ReserveRaysWithCachedMeshes(rays, scene, objects, materials) => {
+                let mut scene = objects.into_scene(scene, |mesh| cache.resolve(mesh))?;
+                set_sphere_materials(&mut scene, materials)?;
+                Response::ReserveRays(rays, scene)
+            }
+        })
+    }
+}
+
+/// The meshes sent over a connection so far, for peers which negotiated
+/// `FEATURE_MESH_CACHE`. Each mesh is sent in full the first time it's used,
+/// and by its position in the cache after that, so that large meshes aren't
+/// sent again with every batch. Both ends of the connection keep a cache, which
+/// agree as long as every response is passed through them in order.
+#[derive(Debug, Default)]
+pub struct MeshCache {
+    meshes: Vec<Arc<Mesh>>,
+}
+
+/// A response prepared for sending by `MeshCache::outgoing`
+pub struct OutgoingResponse<'a> {
+    response: &'a Response,
+    cached: Option<WireResponseRef<'a>>,
+}
+
+/// A response as received from a peer which negotiated `FEATURE_MESH_CACHE`,
+/// whose meshes may have been sent earlier. `MeshCache::incoming` finds them.
+#[derive(Deserialize)]
+#[serde(transparent)]
+pub struct IncomingResponse(WireResponse);
+
+impl MeshCache {
+    /// Prepare a response for sending, referring to meshes which have already
+    /// been sent rather than sending them again. Meshes are told apart by
+    /// their `Arc`s, not their contents. Human-readable formats still include
+    /// every mesh in full.
+    pub fn outgoing<'a>(&mut self, response: &'a Response) -> OutgoingResponse<'a> {
+        let cached = match response {
+            Response::ReserveRays(rays, scene) if !scene.meshes.is_empty() => {
+                let objects = WireObjectsRef::new(scene).map_meshes(|mesh| {
+                    match self.meshes.iter().position(|m| Arc::ptr_eq(m, mesh)) {
+                        Some(id) => CachedMeshRef::Known(id as u32),
+                        None => {
+                            self.meshes.push(mesh.clone());
+                            CachedMeshRef::New(mesh)
+                        }
+                    }
+                });
+                Some(WireResponseRef::ReserveRaysWithCachedMeshes(
+                    rays,
+                    scene,
+                    objects,
+                    sphere_materials(scene),
+                ))
+            }
+            _ => None,
+        };
+        OutgoingResponse { response, cached }
+    }
+
+    /// Decode a received response, filling in meshes which were sent earlier
+    pub fn incoming(&mut self, response: IncomingResponse) -> Result<Response, ProtocolError> {
+        response
+            .0
+            .into_response(self)
+            .map_err(ProtocolError::Decode)
+    }
+
+    fn resolve(&mut self, mesh: CachedMesh) -> Result<Arc<Mesh>, &'static str> {
+        match mesh {
+            CachedMesh::New(mesh) => {
+                let mesh = Arc::new(mesh);
+                self.meshes.push(mesh.clone());
+                Ok(mesh)
+            }
+            CachedMesh::Known(id) => self
+                .meshes
+                .get(id as usize)
+                .cloned()
+                .ok_or("mesh which wasn't sent"),
+        }
+    }
+}
+
+impl Serialize for OutgoingResponse<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.cached {
+            Some(cached) if !serializer.is_human_readable() => cached.serialize(serializer),
+            _ => self.response.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Scene {
+    pub frame: u64,
+    pub spheres: Vec<Sphere>,
+    /// Everything in the scene which isn't a sphere
+    pub shapes: Vec<Shape>,
+    /// Triangle meshes placed in the scene. Their triangles are shared rather
+    /// than copied along with the scene, since they can be large.
+    pub meshes: Vec<MeshInstance>,
+    /// Lights illuminating the scene. When empty, the lights configured on
+    /// the command line are used instead.
+    pub lights: Vec<Light>,
+}
+
+/// The layout of a scene in binary formats, which is fixed by version 2 of the
+/// protocol. Servers don't send lights, so these are always configured locally.
+/// Shapes and meshes are sent alongside the scene, as part of the `Response`.
+#[derive(Deserialize)]
+#[serde(rename = "Scene")]
+struct WireScene {
+    frame: u64,
+    spheres: Vec<Sphere>,
+}
+
+/// The layout of a scene in human-readable formats
+#[derive(Deserialize)]
+#[serde(rename = "Scene", deny_unknown_fields)]
+struct SceneDescription {
+    frame: u64,
+    spheres: Vec<Sphere>,
+    #[serde(default)]
+    shapes: Vec<Shape>,
+    #[serde(default)]
+    meshes: Vec<MeshInstance>,
+    #[serde(default)]
+    lights: Vec<Light>,
+}
+
+impl Serialize for Scene {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let human_readable = serializer.is_human_readable();
+        let include_shapes = human_readable && !self.shapes.is_empty();
+        let include_meshes = human_readable && !self.meshes.is_empty();
+        let include_lights = human_readable && !self.lights.is_empty();
+        let mut state = serializer.serialize_struct(
+            "Scene",
+            2 + include_shapes as usize + include_meshes as usize + include_lights as usize,
+        )?;
+        state.serialize_field("frame", &self.frame)?;
+        state.serialize_field("spheres", &self.spheres)?;
+        if include_shapes {
+            state.serialize_field("shapes", &self.shapes)?;
+        }
+        if include_meshes {
+            state.serialize_field("meshes", &self.meshes)?;
+        }
+        if include_lights {
+            state.serialize_field("lights", &self.lights)?;
+        }
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Scene {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let scene = SceneDescription::deserialize(deserializer)?;
+            Ok(Scene {
+                frame: scene.frame,
+                spheres: scene.spheres,
+                shapes: scene.shapes,
+                meshes: scene.meshes,
+                lights: scene.lights,
+            })
+        } else {
+            let scene = WireScene::deserialize(deserializer)?;
+            Ok(Scene {
+                frame: scene.frame,
+                spheres: scene.spheres,
+                shapes: Vec::new(),
+                meshes: Vec::new(),
+                lights: Vec::new(),
+            })
+        }
+    }
+}
+
+impl Scene {
+    /// Whether any object in the scene has been moved from its own
+    /// coordinates, so that the scene can only be sent to peers which
+    /// negotiated `FEATURE_TRANSFORMS`
+    pub fn has_transforms(&self) -> bool {
+        let spheres = self.spheres.iter().map(|sphere| &sphere.transform);
+        let shapes = self.shapes.iter().map(|shape| &shape.transform);
+        let meshes = self.meshes.iter().map(|instance| &instance.transform);
+        !spheres
+            .chain(shapes)
+            .chain(meshes)
+            .all(Transform::is_identity)
+    }
+
+    /// Whether any sphere is made of something other than the default
+    /// material, so that the scene can only be sent to peers which negotiated
+    /// `FEATURE_MATERIALS`
+    pub fn has_sphere_materials(&self) -> bool {
+        self.spheres
+            .iter()
+            .any(|sphere| !sphere.material.is_default())
+    }
+
+    /// The feature flags a peer needs to have negotiated to be sent this
+    /// scene. Peers without them would only see part of it.
+    pub fn required_features(&self) -> u32 {
+        let mut features = 0;
+        if !self.shapes.is_empty() {
+            features |= FEATURE_SHAPES;
+        }
+        if !self.meshes.is_empty() {
+            features |= FEATURE_MESHES;
+        }
+        if self.has_transforms() {
+            features |= FEATURE_TRANSFORMS;
+        }
+        if self.has_sphere_materials() {
+            features |= FEATURE_MATERIALS;
+        }
+        features
+    }
+
+    /// A small scene of spheres, which slowly orbit as the frame number increases.
+    pub fn demo(frame: u64) -> Self {
+        let angle = frame as f32 * 0.1;
+        let spheres = (0..5)
+            .map(|i| {
+                let offset = angle + i as f32 * std::f32::consts::TAU / 5.0;
+                Sphere {
+                    center: Vec3::new(
+                        1.5 * offset.cos(),
+                        0.5 * offset.sin(),
+                        6.0 + 1.5 * offset.sin(),
+                    ),
+                    radius: 0.6,
+                    material: Material::default(),
+                    transform: Transform::default(),
+                }
+            })
+            .chain(std::iter::once(Sphere {
+                center: Vec3::new(0.0, -1001.0, 6.0),
+                radius: 1000.0,
+                material: Material::default(),
+                transform: Transform::default(),
+            }))
+            .collect();
+        Self {
+            frame,
+            spheres,
+            shapes: Vec::new(),
+            meshes: Vec::new(),
+            lights: Vec::new(),
+        }
+    }
+}
+
+impl Response {
+    /// The name of the request this is a response to
+    pub fn name(&self) -> &'static str {
+        match self {
+            Response::ReserveRays(..) => "ReserveRays",
+            Response::SubmitResults => "SubmitResults",
+            Response::SetName => "SetName",
+        }
+    }
+}
+
+/// The underlying error from whichever codec failed
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Expected a response to {expected}, but received one to {received}")]
+    UnexpectedResponse {
+        expected: &'static str,
+        received: &'static str,
+    },
+    #[error("Frame of {size} bytes is larger than the limit of {limit} bytes")]
+    FrameTooLarge { size: usize, limit: usize },
+    #[error("Failed to decompress frame")]
+    Decompress(#[source] BoxError),
+    #[error("Failed to encode message")]
+    Encode(#[source] BoxError),
+    #[error("Failed to decode message")]
+    Decode(#[source] BoxError),
+    #[error("Unsupported protocol version {0}")]
+    VersionMismatch(u32),
+    #[error("Handshake rejected: {0}")]
+    Rejected(String),
+    #[error("Connection closed by peer")]
+    Eof,
+    #[error("Timed out waiting for the peer")]
+    TimedOut,
+    #[error("Connection was left part way through a message by a cancelled request")]
+    Interrupted,
+    #[error("Failed to record message")]
+    Record(#[source] io::Error),
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => ProtocolError::Eof,
+            // Blocking sockets report that a read or write timed out in either way,
+            // depending on the platform
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ProtocolError::TimedOut,
+            _ => ProtocolError::Io(e),
+        }
+    }
+}
+
+impl ProtocolError {
+    /// Whether the connection itself failed, in which case it may be worth
+    /// reconnecting. Any other error means the peer sent something we don't
+    /// understand, which will most likely happen again.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            ProtocolError::Eof
+                | ProtocolError::TimedOut
+                | ProtocolError::Interrupted
+                | ProtocolError::Io(_)
+        )
+    }
+}
+
+/// How messages are laid out on the wire. Every message is encoded with one of
+/// these codecs, and prefixed with its length once any compression has been applied.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Framing {
+    /// Uncompressed postcard, as spoken by version 1
+    Plain,
+    /// Postcard compressed with snappy, as spoken by version 2
+    Snappy,
+    /// Postcard compressed with lz4
+    Lz4,
+    /// Postcard compressed with zstd, which is slower than the others but
+    /// uses the least bandwidth
+    Zstd,
+    /// Uncompressed JSON, so that traffic can be read by eye
+    Json,
+}
+
+/// Feature flag for peers which can encode messages with lz4
+pub const FEATURE_LZ4: u32 = 1 << 0;
+/// Feature flag for peers which can encode messages with zstd
+pub const FEATURE_ZSTD: u32 = 1 << 1;
+/// Feature flag for peers which can encode messages as JSON
+pub const FEATURE_JSON: u32 = 1 << 2;
+/// Feature flag for peers which can decode scenes containing shapes other than spheres
+pub const FEATURE_SHAPES: u32 = 1 << 3;
+/// Feature flag for peers which can decode scenes containing triangle meshes
+pub const FEATURE_MESHES: u32 = 1 << 4;
+/// Feature flag for peers which can decode scenes containing transformed
+/// objects and instanced meshes
+pub const FEATURE_TRANSFORMS: u32 = 1 << 5;
+/// Feature flag for peers which can decode scenes containing spheres with
+/// materials of their own
+pub const FEATURE_MATERIALS: u32 = 1 << 6;
+/// Feature flag for peers which keep a `MeshCache`, so that meshes are only
+/// sent once per connection
+pub const FEATURE_MESH_CACHE: u32 = 1 << 7;
+/// Every codec which has to be negotiated, rather than being implied by the
+/// protocol version
+pub const FEATURE_CODECS: u32 = FEATURE_LZ4 | FEATURE_ZSTD | FEATURE_JSON;
+
+/// The names of the features in a set of feature flags, for error messages
+pub fn feature_names(features: u32) -> Vec<&'static str> {
+    [
+        (FEATURE_LZ4, "lz4"),
+        (FEATURE_ZSTD, "zstd"),
+        (FEATURE_JSON, "json"),
+        (FEATURE_SHAPES, "shapes"),
+        (FEATURE_MESHES, "meshes"),
+        (FEATURE_TRANSFORMS, "transforms"),
+        (FEATURE_MATERIALS, "materials"),
+        (FEATURE_MESH_CACHE, "mesh cache"),
+    ]
+    .into_iter()
+    .filter(|(feature, _)| features & feature != 0)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+impl Framing {
+    /// The feature flag which a peer sets to ask for this codec, if it isn't
+    /// implied by the protocol version
+    pub fn feature(self) -> u32 {
+        match self {
+            Framing::Plain | Framing::Snappy => 0,
+            Framing::Lz4 => FEATURE_LZ4,
+            Framing::Zstd => FEATURE_ZSTD,
+            Framing::Json => FEATURE_JSON,
+        }
+    }
+
+    /// Whether frames are compressed, so may decode to something larger
+    pub fn is_compressed(self) -> bool {
+        !matches!(self, Framing::Plain | Framing::Json)
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Framing::Plain),
+            "snappy" => Ok(Framing::Snappy),
+            "lz4" => Ok(Framing::Lz4),
+            "zstd" => Ok(Framing::Zstd),
+            "json" => Ok(Framing::Json),
+            _ => Err(format!(
+                "Expected plain, snappy, lz4, zstd or json, but got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+/// Turns messages into the contents of a frame, and back again
+pub trait Codec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError>;
+
+    /// Decode a frame. Codecs which compress must refuse to decompress to more
+    /// than `max_size` bytes, without allocating them first.
+    fn decode<T: DeserializeOwned>(
+        &self,
+        frame: &[u8],
+        max_size: usize,
+    ) -> Result<T, ProtocolError>;
+}
+
+/// Messages serialized with postcard, without compression
+pub struct PostcardCodec;
+
+/// Messages serialized with postcard, and compressed with snappy
+pub struct SnappyCodec;
+
+/// Messages serialized with postcard, and compressed with lz4
+pub struct Lz4Codec;
+
+/// Messages serialized with postcard, and compressed with zstd
+pub struct ZstdCodec;
+
+/// Messages serialized as JSON
+pub struct JsonCodec;
+
+fn check_size(size: usize, limit: usize) -> Result<(), ProtocolError> {
+    if size > limit {
+        Err(ProtocolError::FrameTooLarge { size, limit })
+    } else {
+        Ok(())
+    }
+}
+
+fn decompress_error(e: impl Into<BoxError>) -> ProtocolError {
+    ProtocolError::Decompress(e.into())
+}
+
+impl Codec for PostcardCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError> {
+        postcard::to_allocvec(message).map_err(|e| ProtocolError::Encode(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8], _: usize) -> Result<T, ProtocolError> {
+        postcard::from_bytes(frame).map_err(|e| ProtocolError::Decode(e.into()))
+    }
+}
+
+impl Codec for SnappyCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError> {
+        let data = PostcardCodec.encode(message)?;
+        // Snappy can't compress anything larger than a u32 either
+        Encoder::new()
+            .compress_vec(&data)
+            .map_err(|_| ProtocolError::FrameTooLarge {
+                size: data.len(),
+                limit: u32::MAX as usize,
+            })
+    }
+
+    fn decode<T: DeserializeOwned>(
+        &self,
+        frame: &[u8],
+        max_size: usize,
+    ) -> Result<T, ProtocolError> {
+        // Snappy records the decompressed size at the start of the data
+        check_size(decompress_len(frame).map_err(decompress_error)?, max_size)?;
+        let data = Decoder::new()
+            .decompress_vec(frame)
+            .map_err(decompress_error)?;
+        PostcardCodec.decode(&data, max_size)
+    }
+}
+
+impl Codec for Lz4Codec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError> {
+        Ok(lz4_flex::compress_prepend_size(
+            &PostcardCodec.encode(message)?,
+        ))
+    }
+
+    fn decode<T: DeserializeOwned>(
+        &self,
+        frame: &[u8],
+        max_size: usize,
+    ) -> Result<T, ProtocolError> {
+        let (size, compressed) =
+            lz4_flex::block::uncompressed_size(frame).map_err(decompress_error)?;
+        check_size(size, max_size)?;
+        let data = lz4_flex::decompress(compressed, size).map_err(decompress_error)?;
+        PostcardCodec.decode(&data, max_size)
+    }
+}
+
+impl Codec for ZstdCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError> {
+        let data = PostcardCodec.encode(message)?;
+        zstd::bulk::compress(&data, zstd::DEFAULT_COMPRESSION_LEVEL)
+            .map_err(|e| ProtocolError::Encode(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(
+        &self,
+        frame: &[u8],
+        max_size: usize,
+    ) -> Result<T, ProtocolError> {
+        // The decompressed size is optional in zstd frames. When it's there,
+        // oversized messages can be turned away before decompressing them.
+        let size = zstd::zstd_safe::get_frame_content_size(frame)
+            .map_err(|e| decompress_error(e.to_string()))?;
+        let mut data = Vec::new();
+        if let Some(size) = size {
+            let size = usize::try_from(size).unwrap_or(usize::MAX);
+            check_size(size, max_size)?;
+            data.reserve(size);
+        }
+        // Otherwise, stop decompressing as soon as the message is too large
+        let decoder = zstd::stream::read::Decoder::with_buffer(frame).map_err(decompress_error)?;
+        decoder
+            .take((max_size as u64).saturating_add(1))
+            .read_to_end(&mut data)
+            .map_err(decompress_error)?;
+        check_size(data.len(), max_size)?;
+        PostcardCodec.decode(&data, max_size)
+    }
+}
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError> {
+        serde_json::to_vec(message).map_err(|e| ProtocolError::Encode(e.into()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8], _: usize) -> Result<T, ProtocolError> {
+        serde_json::from_slice(frame).map_err(|e| ProtocolError::Decode(e.into()))
+    }
+}
+
+impl Codec for Framing {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Framing::Plain => PostcardCodec.encode(message),
+            Framing::Snappy => SnappyCodec.encode(message),
+            Framing::Lz4 => Lz4Codec.encode(message),
+            Framing::Zstd => ZstdCodec.encode(message),
+            Framing::Json => JsonCodec.encode(message),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(
+        &self,
+        frame: &[u8],
+        max_size: usize,
+    ) -> Result<T, ProtocolError> {
+        match self {
+            Framing::Plain => PostcardCodec.decode(frame, max_size),
+            Framing::Snappy => SnappyCodec.decode(frame, max_size),
+            Framing::Lz4 => Lz4Codec.decode(frame, max_size),
+            Framing::Zstd => ZstdCodec.decode(frame, max_size),
+            Framing::Json => JsonCodec.decode(frame, max_size),
+        }
+    }
+}
+
+/// Encode a message into a frame, prefixed with its u32 length, ready to be
+/// written to a stream.
+pub fn encode_frame<T: Serialize>(message: &T, framing: Framing) -> Result<Vec<u8>, ProtocolError> {
+    let data = framing.encode(message)?;
+    let size = u32::try_from(data.len()).map_err(|_| ProtocolError::FrameTooLarge {
+        size: data.len(),
+        limit: u32::MAX as usize,
+    })?;
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.write_u32::<BE>(size)?;
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+/// Write a single message to the stream.
+pub fn write_message<T: Serialize>(
+    stream: &mut impl Write,
+    message: &T,
+    framing: Framing,
+) -> Result<(), ProtocolError> {
+    stream.write_all(&encode_frame(message, framing)?)?;
+    Ok(())
+}
+
+/// Limits on the size of messages read from a peer, so that a misbehaving one
+/// can't make us run out of memory
+#[derive(Debug, Copy, Clone, StructOpt)]
+pub struct FrameLimits {
+    /// Largest frame to accept from the network, in bytes
+    #[structopt(long, default_value = "16777216")]
+    pub max_frame_size: usize,
+    /// Largest message to accept once decompressed, in bytes
+    #[structopt(long, default_value = "67108864")]
+    pub max_message_size: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Check the size a peer claims its next frame has, before reading it
+    pub fn check_frame_size(&self, size: usize, framing: Framing) -> Result<(), ProtocolError> {
+        let limit = if framing.is_compressed() {
+            self.max_frame_size
+        } else {
+            self.max_frame_size.min(self.max_message_size)
+        };
+        check_size(size, limit)
+    }
+}
+
+/// Read a single message written by `write_message` from the stream. Sizes
+/// are checked against the limits before any memory is allocated for them.
+pub fn read_message<T: DeserializeOwned>(
+    stream: &mut impl Read,
+    framing: Framing,
+    limits: &FrameLimits,
+) -> Result<T, ProtocolError> {
+    let size = stream.read_u32::<BE>()? as usize;
+    limits.check_frame_size(size, framing)?;
+
+    // Only allocate as much as the peer actually sends, rather than trusting
+    // the size it claimed
+    let mut data = Vec::new();
+    stream.take(size as u64).read_to_end(&mut data)?;
+    if data.len() < size {
+        return Err(ProtocolError::Eof);
+    }
+
+    framing.decode(&data, limits.max_message_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lights_are_not_sent_to_workers() {
+        let scene = Scene {
+            lights: vec!["point:0,1,0".parse().unwrap()],
+            ..Scene::demo(7)
+        };
+        let data = postcard::to_allocvec(&scene).unwrap();
+        let decoded: Scene = postcard::from_bytes(&data).unwrap();
+        assert_eq!(decoded.frame, 7);
+        assert_eq!(decoded.spheres.len(), scene.spheres.len());
+        assert!(decoded.lights.is_empty());
+
+        let json = serde_json::to_string(&scene).unwrap();
+        let decoded: Scene = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.lights, scene.lights);
+    }
+
+    #[test]
+    fn shapes_are_sent_separately() {
+        let ground = Shape {
+            kind: crate::geom::ShapeKind::Plane {
+                point: Vec3::new(0.0, -1.0, 0.0),
+                normal: Vec3::new(0.0, 1.0, 0.0),
+            },
+            material: Material::default(),
+            transform: Transform::default(),
+        };
+        let scene = Scene {
+            shapes: vec![ground],
+            ..Scene::demo(7)
+        };
+        let limits = FrameLimits::default();
+        for framing in [Framing::Plain, Framing::Json] {
+            let mut data = Vec::new();
+            let response = Response::ReserveRays(Vec::new(), scene.clone());
+            write_message(&mut data, &response, framing).unwrap();
+            let response: Response = read_message(&mut &data[..], framing, &limits).unwrap();
+            assert!(matches!(response, Response::ReserveRays(_, decoded) if decoded == scene));
+        }
+
+        // Scenes without shapes are laid out as version 2 expects, which starts
+        // with the index of the variant
+        let data = postcard::to_allocvec(&Response::ReserveRays(Vec::new(), Scene::demo(7)));
+        assert_eq!(data.unwrap()[0], 0);
+        let data = postcard::to_allocvec(&Response::ReserveRays(Vec::new(), scene));
+        assert_eq!(data.unwrap()[0], 3);
+    }
+
+    #[test]
+    fn meshes_are_sent_separately() {
+        let triangle = Mesh::new(
+            vec![
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::new(1.0, 0.0, 5.0),
+                Vec3::new(0.0, 1.0, 5.0),
+            ],
+            Vec::new(),
+            vec![[0, 1, 2]],
+        )
+        .unwrap();
+        let scene = Scene {
+            meshes: vec![MeshInstance::new(Arc::new(triangle))],
+            ..Scene::demo(7)
+        };
+        let limits = FrameLimits::default();
+        for framing in [Framing::Plain, Framing::Json] {
+            let mut data = Vec::new();
+            let response = Response::ReserveRays(Vec::new(), scene.clone());
+            write_message(&mut data, &response, framing).unwrap();
+            let response: Response = read_message(&mut &data[..], framing, &limits).unwrap();
+            assert!(matches!(response, Response::ReserveRays(_, decoded) if decoded == scene));
+        }
+
+        let data = postcard::to_allocvec(&Response::ReserveRays(Vec::new(), scene));
+        assert_eq!(data.unwrap()[0], 4);
+    }
+
+    #[test]
+    fn meshes_are_sent_once_per_connection() {
+        let triangle = Mesh::new(
+            vec![
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::new(1.0, 0.0, 5.0),
+                Vec3::new(0.0, 1.0, 5.0),
+            ],
+            Vec::new(),
+            vec![[0, 1, 2]],
+        )
+        .unwrap();
+        let scene = |frame| Scene {
+            meshes: vec![MeshInstance::new(Arc::new(triangle.clone()))],
+            ..Scene::demo(frame)
+        };
+        let first = scene(1);
+        // Later frames share the first frame's mesh, as the server's do
+        let second = Scene {
+            meshes: first.meshes.clone(),
+            ..Scene::demo(2)
+        };
+
+        let limits = FrameLimits::default();
+        for framing in [Framing::Plain, Framing::Json] {
+            let mut outgoing = MeshCache::default();
+            let mut incoming = MeshCache::default();
+            let mut decoded = Vec::new();
+            let mut sizes = Vec::new();
+            for scene in [&first, &second] {
+                let mut data = Vec::new();
+                let response = Response::ReserveRays(Vec::new(), scene.clone());
+                write_message(&mut data, &outgoing.outgoing(&response), framing).unwrap();
+                sizes.push(data.len());
+                let response = read_message(&mut &data[..], framing, &limits).unwrap();
+                match incoming.incoming(response).unwrap() {
+                    Response::ReserveRays(_, scene) => decoded.push(scene),
+                    response => panic!("Expected rays, got {:?}", response),
+                }
+            }
+            assert_eq!(decoded, [first.clone(), second.clone()]);
+            if framing == Framing::Plain {
+                assert!(sizes[1] < sizes[0]);
+                assert!(Arc::ptr_eq(
+                    &decoded[0].meshes[0].mesh,
+                    &decoded[1].meshes[0].mesh
+                ));
+            }
+        }
+
+        // A different mesh is sent in full, even if it has the same triangles
+        let mut outgoing = MeshCache::default();
+        let mut data = Vec::new();
+        for scene in [&first, &scene(3)] {
+            let response = Response::ReserveRays(Vec::new(), scene.clone());
+            data = postcard::to_allocvec(&outgoing.outgoing(&response)).unwrap();
+        }
+        let response: Response = postcard::from_bytes(&data).unwrap();
+        assert!(matches!(response, Response::ReserveRays(_, decoded) if decoded == scene(3)));
+
+        // Without the cache, a mesh sent earlier can't be found
+        let response = Response::ReserveRays(Vec::new(), second.clone());
+        let data = postcard::to_allocvec(&outgoing.outgoing(&response)).unwrap();
+        assert_eq!(data[0], 7);
+        assert!(postcard::from_bytes::<Response>(&data).is_err());
+    }
+
+    #[test]
+    fn transforms_are_sent_separately() {
+        let moved = |x| {
+            Transform::new(
+                Vec3::new(x, 0.0, 0.0),
+                Vec3::new(0.0, 45.0, 0.0),
+                Vec3::new(1.0, 2.0, 1.0),
+            )
+            .unwrap()
+        };
+        let triangle = Mesh::new(
+            vec![
+                Vec3::new(0.0, 0.0, 5.0),
+                Vec3::new(1.0, 0.0, 5.0),
+                Vec3::new(0.0, 1.0, 5.0),
+            ],
+            Vec::new(),
+            vec![[0, 1, 2]],
+        )
+        .unwrap();
+        let instance = MeshInstance::new(Arc::new(triangle));
+        let mut scene = Scene {
+            shapes: vec![Shape {
+                kind: crate::geom::ShapeKind::Cylinder {
+                    base: Vec3::new(0.0, 0.0, 0.0),
+                    top: Vec3::new(0.0, 1.0, 0.0),
+                    radius: 0.5,
+                },
+                material: Material::default(),
+                transform: moved(1.0),
+            }],
+            meshes: vec![
+                instance.clone(),
+                MeshInstance {
+                    transform: moved(2.0),
+                    ..instance
+                },
+            ],
+            ..Scene::demo(7)
+        };
+        scene.spheres[2].transform = moved(3.0);
+        assert!(scene.has_transforms());
+
+        let limits = FrameLimits::default();
+        for framing in [Framing::Plain, Framing::Json] {
+            let mut data = Vec::new();
+            let response = Response::ReserveRays(Vec::new(), scene.clone());
+            write_message(&mut data, &response, framing).unwrap();
+            let response: Response = read_message(&mut &data[..], framing, &limits).unwrap();
+            let Response::ReserveRays(_, decoded) = response else {
+                panic!("Expected rays, got {:?}", response);
+            };
+            assert_eq!(decoded, scene);
+            if framing == Framing::Plain {
+                // Instances of the same mesh still share it once they've been decoded
+                assert!(Arc::ptr_eq(
+                    &decoded.meshes[0].mesh,
+                    &decoded.meshes[1].mesh
+                ));
+            }
+        }
+
+        let data = postcard::to_allocvec(&Response::ReserveRays(Vec::new(), scene));
+        assert_eq!(data.unwrap()[0], 5);
+    }
+
+    #[test]
+    fn sphere_materials_are_sent_separately() {
+        let mut scene = Scene::demo(7);
+        scene.spheres[1].material = Material {
+            albedo: Some(Vec3::new(0.2, 0.4, 0.8)),
+            reflectivity: 0.5,
+            ..Material::default()
+        };
+        scene.spheres[3].material = Material {
+            transparency: 0.9,
+            ior: 1.5,
+            ..Material::default()
+        };
+        assert!(scene.has_sphere_materials());
+
+        let limits = FrameLimits::default();
+        for framing in [Framing::Plain, Framing::Json] {
+            let mut data = Vec::new();
+            let response = Response::ReserveRays(Vec::new(), scene.clone());
+            write_message(&mut data, &response, framing).unwrap();
+            let response: Response = read_message(&mut &data[..], framing, &limits).unwrap();
+            let Response::ReserveRays(_, decoded) = response else {
+                panic!("Expected rays, got {:?}", response);
+            };
+            assert_eq!(decoded, scene);
+        }
+
+        let data = postcard::to_allocvec(&Response::ReserveRays(Vec::new(), scene));
+        assert_eq!(data.unwrap()[0], 6);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        for framing in [
+            Framing::Plain,
+            Framing::Snappy,
+            Framing::Lz4,
+            Framing::Zstd,
+            Framing::Json,
+        ] {
+            let mut data = Vec::new();
+            write_message(&mut data, &Request::SetName("Bob".into()), framing).unwrap();
+            let request: Request =
+                read_message(&mut &data[..], framing, &FrameLimits::default()).unwrap();
+            assert!(matches!(request, Request::SetName(name) if name == "Bob"));
+        }
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut data = Vec::new();
+        write_message(&mut data, &Request::SetName("Bob".into()), Framing::Snappy).unwrap();
+
+        let limits = FrameLimits::default();
+
+        // The stream ends part way through the message
+        let result =
+            read_message::<Request>(&mut &data[..data.len() - 1], Framing::Snappy, &limits);
+        assert!(matches!(result, Err(ProtocolError::Eof)));
+        assert!(result.unwrap_err().is_disconnect());
+
+        // The message isn't valid snappy
+        let mut garbage = data.clone();
+        garbage[4..].fill(0xff);
+        let result = read_message::<Request>(&mut &garbage[..], Framing::Snappy, &limits);
+        assert!(matches!(result, Err(ProtocolError::Decompress(_))));
+
+        // The message is a response rather than a request
+        let mut data = Vec::new();
+        write_message(&mut data, &Response::SubmitResults, Framing::Snappy).unwrap();
+        let result = read_message::<Request>(&mut &data[..], Framing::Snappy, &limits);
+        assert!(matches!(result, Err(ProtocolError::Decode(_))));
+    }
+
+    #[test]
+    fn size_limits() {
+        let request = Request::SubmitResults(
+            (0..1000)
+                .map(|_| Outcome {
+                    hit: false,
+                    color: None,
+                })
+                .collect(),
+        );
+        let mut data = Vec::new();
+        write_message(&mut data, &request, Framing::Snappy).unwrap();
+        let frame_size = data.len() - 4;
+
+        // The frame itself is too large
+        let limits = FrameLimits {
+            max_frame_size: frame_size - 1,
+            ..FrameLimits::default()
+        };
+        let result = read_message::<Request>(&mut &data[..], Framing::Snappy, &limits);
+        assert!(
+            matches!(result, Err(ProtocolError::FrameTooLarge { size, .. }) if size == frame_size)
+        );
+
+        // The frame is small, but decompresses to something too large
+        let limits = FrameLimits {
+            max_message_size: 1000,
+            ..FrameLimits::default()
+        };
+        for framing in [Framing::Snappy, Framing::Lz4, Framing::Zstd] {
+            let mut data = Vec::new();
+            write_message(&mut data, &request, framing).unwrap();
+            let result = read_message::<Request>(&mut &data[..], framing, &limits);
+            assert!(matches!(
+                result,
+                Err(ProtocolError::FrameTooLarge { limit: 1000, .. })
+            ));
+        }
+
+        // Even when the zstd frame doesn't say how large it decompresses to
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0).unwrap();
+        encoder
+            .write_all(&PostcardCodec.encode(&request).unwrap())
+            .unwrap();
+        let frame = encoder.finish().unwrap();
+        assert!(matches!(
+            zstd::zstd_safe::get_frame_content_size(&frame),
+            Ok(None)
+        ));
+        assert!(matches!(
+            ZstdCodec.decode::<Request>(&frame, 1000),
+            Err(ProtocolError::FrameTooLarge { limit: 1000, .. })
+        ));
+        assert!(matches!(
+            ZstdCodec.decode(&frame, 4000),
+            Ok(Request::SubmitResults(results)) if results.len() == 1000
+        ));
+
+        // A peer claiming to send a huge frame doesn't make us allocate it
+        let data = u32::MAX.to_be_bytes();
+        let limits = FrameLimits {
+            max_frame_size: usize::MAX,
+            max_message_size: usize::MAX,
+        };
+        let result = read_message::<Request>(&mut &data[..], Framing::Plain, &limits);
+        assert!(matches!(result, Err(ProtocolError::Eof)));
+    }
+
+    fn hello(min_version: u32, max_version: u32, capabilities: Capabilities) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn negotiates_newest_common_version() {
+        let session = hello(1, 5, Capabilities::default())
+            .negotiate(&Capabilities::default())
+            .unwrap();
+        assert_eq!(session, Session::legacy(PROTOCOL_VERSION));
+        assert_eq!(session.framing(), Framing::Snappy);
+
+        let session = hello(1, 1, Capabilities::default())
+            .negotiate(&Capabilities::default())
+            .unwrap();
+        assert_eq!(session.version, 1);
+        assert_eq!(session.framing(), Framing::Plain);
+
+        assert!(hello(3, 5, Capabilities::default())
+            .negotiate(&Capabilities::default())
+            .is_err());
+    }
+
+    #[test]
+    fn negotiates_capabilities() {
+        let client = Capabilities {
+            compression: false,
+            max_batch_size: Some(1000),
+            features: 0b011_000,
+        };
+        let server = Capabilities {
+            compression: true,
+            max_batch_size: Some(4096),
+            features: 0b110_000,
+        };
+        let session = hello(1, 2, client).negotiate(&server).unwrap();
+        assert_eq!(
+            session,
+            Session {
+                version: 1,
+                capabilities: Capabilities {
+                    compression: false,
+                    max_batch_size: Some(1000),
+                    features: 0b010_000,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_empty_batches() {
+        let client = Capabilities {
+            max_batch_size: Some(0),
+            ..Capabilities::default()
+        };
+        assert!(hello(1, 2, client.clone())
+            .negotiate(&Capabilities::default())
+            .is_err());
+        assert!(hello(1, 2, Capabilities::default())
+            .negotiate(&client)
+            .is_err());
+    }
+
+    #[test]
+    fn negotiates_codecs() {
+        let server = Capabilities {
+            features: FEATURE_CODECS,
+            ..Capabilities::default()
+        };
+        let codec = |capabilities: Capabilities, server: &Capabilities| {
+            hello(1, 2, capabilities)
+                .negotiate(server)
+                .unwrap()
+                .framing()
+        };
+
+        for framing in [Framing::Lz4, Framing::Zstd, Framing::Json] {
+            let client = Capabilities {
+                features: framing.feature(),
+                ..Capabilities::default()
+            };
+            assert_eq!(codec(client.clone(), &server), framing);
+            // Servers which don't support the codec fall back to snappy
+            assert_eq!(codec(client, &Capabilities::default()), Framing::Snappy);
+        }
+
+        // Asking for compression with a codec doesn't override asking for none
+        let client = Capabilities {
+            compression: false,
+            features: FEATURE_ZSTD,
+            ..Capabilities::default()
+        };
+        assert_eq!(codec(client, &server), Framing::Plain);
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::protocol::{
+    encode_frame, read_message, FrameLimits, Framing, ProtocolError, Request, Response, Session,
+};
+use crate::shading::ShadingOpt;
+
+/// Recordings start with this big-endian u32, followed by a frame for each `Entry`
+pub const RECORDING_MAGIC: u32 = u32::from_be_bytes(*b"RWRC");
+
+/// Something which happened on a connection to a server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Event {
+    /// A new connection was established, so any requests still awaiting a
+    /// response on the previous one never got one
+    Connected(Session),
+    Sent(Request),
+    Received(Response),
+    /// The options the worker shades rays with, which replaying needs to give
+    /// the same results. Recorded once, before the first connection.
+    Shading(ShadingOpt),
+}
+
+/// The same layout as `Event`, but borrowing the message, so it can be
+/// recorded without being cloned
+#[derive(Serialize)]
+#[serde(rename = "Event")]
+enum EventRef<'a> {
+    Connected(&'a Session),
+    Sent(&'a Request),
+    Received(&'a Response),
+    Shading(&'a ShadingOpt),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the event happened, in microseconds since the Unix epoch
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Entry")]
+struct EntryRef<'a> {
+    timestamp: u64,
+    event: EventRef<'a>,
+}
+
+#[derive(Debug, Error)]
+pub enum RecordingError {
+    #[error("Not a recording")]
+    NotARecording,
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+}
+
+impl From<io::Error> for RecordingError {
+    fn from(e: io::Error) -> Self {
+        RecordingError::Protocol(e.into())
+    }
+}
+
+/// Writes every message exchanged with a server to a file, so that the
+/// session can be replayed later. Clones write to the same file, so one
+/// recording can span several connections.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_u32::<BE>(RECORDING_MAGIC)?;
+        file.flush()?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn connected(&self, session: &Session) -> Result<(), ProtocolError> {
+        self.record(EventRef::Connected(session))
+    }
+
+    pub fn sent(&self, request: &Request) -> Result<(), ProtocolError> {
+        self.record(EventRef::Sent(request))
+    }
+
+    pub fn received(&self, response: &Response) -> Result<(), ProtocolError> {
+        self.record(EventRef::Received(response))
+    }
+
+    pub fn shading(&self, opt: &ShadingOpt) -> Result<(), ProtocolError> {
+        self.record(EventRef::Shading(opt))
+    }
+
+    fn record(&self, event: EventRef) -> Result<(), ProtocolError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_micros() as u64);
+        let frame = encode_frame(&EntryRef { timestamp, event }, Framing::Plain)?;
+        // Flush every entry, so that nothing is lost if the worker is killed
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&frame)
+            .and_then(|_| file.flush())
+            .map_err(ProtocolError::Record)
+    }
+}
+
+/// Read every entry from a recording. A recording which ends part way through
+/// an entry was cut short while it was being written, so that entry is ignored.
+pub fn load(path: &Path) -> Result<Vec<Entry>, RecordingError> {
+    let mut file = BufReader::new(File::open(path)?);
+    read_entries(&mut file)
+}
+
+fn read_entries(stream: &mut impl Read) -> Result<Vec<Entry>, RecordingError> {
+    match stream.read_u32::<BE>() {
+        Ok(RECORDING_MAGIC) => {}
+        Ok(_) => return Err(RecordingError::NotARecording),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(RecordingError::NotARecording)
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let limits = FrameLimits::default();
+    let mut entries = Vec::new();
+    loop {
+        match read_message(stream, Framing::Plain, &limits) {
+            Ok(entry) => entries.push(entry),
+            Err(ProtocolError::Eof) => return Ok(entries),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("rust-workshop-record-{}", std::process::id()));
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.connected(&Session::legacy(2)).unwrap();
+        recorder.sent(&Request::SetName("Bob".into())).unwrap();
+        recorder.clone().received(&Response::SetName).unwrap();
+        drop(recorder);
+
+        let entries = load(&path).unwrap();
+        assert!(matches!(
+            &entries[..],
+            [
+                Entry {
+                    event: Event::Connected(session),
+                    ..
+                },
+                Entry {
+                    event: Event::Sent(Request::SetName(name)),
+                    ..
+                },
+                Entry {
+                    event: Event::Received(Response::SetName),
+                    ..
+                },
+            ] if *session == Session::legacy(2) && name == "Bob"
+        ));
+        assert!(entries.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        // A recording cut short keeps the entries written in full
+        let data = std::fs::read(&path).unwrap();
+        let entries = read_entries(&mut &data[..data.len() - 1]).unwrap();
+        assert_eq!(entries.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        let result = read_entries(&mut &b"nope"[..]);
+        assert!(matches!(result, Err(RecordingError::NotARecording)));
+    }
+}
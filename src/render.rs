@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use structopt::StructOpt;
+
+use rust_workshop::bvh::Bvh;
+use rust_workshop::protocol::Scene;
+use rust_workshop::shading::{compute_result, ShadingOpt};
+
+use crate::camera::Camera;
+use crate::image::Image;
+use crate::scene_file::SceneFile;
+
+#[derive(StructOpt)]
+pub struct RenderOpt {
+    /// Scene file to render (.json or .ron). Renders a built-in demo scene if omitted.
+    #[structopt(long)]
+    pub scene: Option<PathBuf>,
+    #[structopt(long, default_value = "640")]
+    pub width: u32,
+    #[structopt(long, default_value = "480")]
+    pub height: u32,
+    /// Where to save the image. The format (PNG or PPM) is chosen from the extension.
+    #[structopt(short, long, default_value = "render.png")]
+    pub output: PathBuf,
+    #[structopt(flatten)]
+    pub shading: ShadingOpt,
+}
+
+/// Trace a ray through every pixel of the image, in parallel
+pub fn render_image(
+    scene: &Scene,
+    camera: &Camera,
+    width: u32,
+    height: u32,
+    opt: &ShadingOpt,
+) -> Image {
+    let bvh = Bvh::new(&scene.spheres);
+    let pixels = camera
+        .rays(width, height)
+        .into_par_iter()
+        .map(|ray| {
+            compute_result(ray, scene, &bvh, opt, opt.bounces)
+                .color
+                .unwrap_or(opt.bg)
+        })
+        .collect();
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// Render the first frame of a scene file, which overrides any colours it
+/// specifies in the shading options
+pub fn render_scene_file(
+    scene_file: &SceneFile,
+    width: u32,
+    height: u32,
+    mut shading: ShadingOpt,
+) -> Image {
+    if let Some(background) = scene_file.background {
+        shading.bg = background;
+    }
+    if let Some(palette) = &scene_file.palette {
+        shading.fg = palette.clone();
+    }
+    if let Some(ambient) = scene_file.ambient {
+        shading.ambient = ambient;
+    }
+    render_image(
+        &scene_file.scene(0),
+        &scene_file.camera,
+        width,
+        height,
+        &shading,
+    )
+}
+
+pub fn render(opt: RenderOpt) -> anyhow::Result<()> {
+    let scene_file = match &opt.scene {
+        Some(path) => SceneFile::load(path)?,
+        None => SceneFile::from_scene(&Scene::demo(0)),
+    };
+    let image = render_scene_file(&scene_file, opt.width, opt.height, opt.shading);
+    image.save(&opt.output)?;
+    println!("Saved to {}", opt.output.display());
+    Ok(())
+}
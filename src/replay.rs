@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use structopt::StructOpt;
+
+use rust_workshop::bvh::Bvh;
+use rust_workshop::geom::Ray;
+use rust_workshop::protocol::{Outcome, Request, Response, Scene};
+use rust_workshop::recording::{self, Entry, Event};
+use rust_workshop::shading::{compute_result, ShadingOpt};
+
+#[derive(StructOpt)]
+pub struct ReplayOpt {
+    /// Recording made by `work --record`
+    pub recording: PathBuf,
+    /// Shading options, used instead of the worker's if the recording doesn't
+    /// include them
+    #[structopt(flatten)]
+    pub shading: ShadingOpt,
+    /// Use the shading options given here even if the recording includes the
+    /// worker's, to see whether they change the results
+    #[structopt(long)]
+    pub ignore_recorded_shading: bool,
+    /// Largest difference in any colour component which isn't reported
+    #[structopt(long, default_value = "0.0001")]
+    pub tolerance: f32,
+}
+
+/// A batch of rays handed out by the server, and the results the worker submitted for it
+#[derive(Debug)]
+struct RecordedBatch {
+    rays: Vec<Ray>,
+    scene: Scene,
+    results: Vec<Outcome>,
+}
+
+/// Pair each batch of rays the worker received with the results it submitted.
+/// Workers submit results in the order the batches were reserved, so the
+/// first submission on a connection is for the first batch, and so on.
+/// Batches which were never submitted are left out.
+fn recorded_batches(entries: Vec<Entry>) -> Vec<RecordedBatch> {
+    let mut reserved = Vec::new();
+    let mut next = 0;
+    let mut batches = Vec::new();
+    for entry in entries {
+        match entry.event {
+            Event::Connected(_) => {
+                reserved.clear();
+                next = 0;
+            }
+            Event::Received(Response::ReserveRays(rays, scene)) => reserved.push((rays, scene)),
+            Event::Sent(Request::SubmitResults(results)) => {
+                if let Some((rays, scene)) = reserved.get_mut(next) {
+                    batches.push(RecordedBatch {
+                        rays: std::mem::take(rays),
+                        scene: std::mem::take(scene),
+                        results,
+                    });
+                    next += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    batches
+}
+
+/// The options the worker shaded rays with, if it recorded them
+fn recorded_shading(entries: &[Entry]) -> Option<&ShadingOpt> {
+    entries.iter().find_map(|entry| match &entry.event {
+        Event::Shading(opt) => Some(opt),
+        _ => None,
+    })
+}
+
+/// Whether two results differ by more than the tolerance
+fn differs(a: &Outcome, b: &Outcome, tolerance: f32) -> bool {
+    let colors_differ = match (a.color, b.color) {
+        (Some(a), Some(b)) => !a.approx_eq(&b, tolerance),
+        (None, None) => false,
+        _ => true,
+    };
+    a.hit != b.hit || colors_differ
+}
+
+pub fn replay(opt: ReplayOpt) -> anyhow::Result<()> {
+    let entries = recording::load(&opt.recording)?;
+    let shading = match recorded_shading(&entries) {
+        Some(recorded) if !opt.ignore_recorded_shading => recorded.clone(),
+        _ => opt.shading,
+    };
+    let batches = recorded_batches(entries);
+
+    let mut rays = 0;
+    let mut differences = 0;
+    for (i, batch) in batches.iter().enumerate() {
+        let bvh = Bvh::new(&batch.scene.spheres);
+        let results: Vec<Outcome> = batch
+            .rays
+            .par_iter()
+            .map(|&ray| compute_result(ray, &batch.scene, &bvh, &shading, shading.bounces))
+            .collect();
+        if results.len() != batch.results.len() {
+            println!(
+                "Batch {} (frame {}): {} rays, but {} results were submitted",
+                i,
+                batch.scene.frame,
+                results.len(),
+                batch.results.len()
+            );
+            differences += 1;
+        }
+        for (j, (recorded, replayed)) in batch.results.iter().zip(&results).enumerate() {
+            if differs(recorded, replayed, opt.tolerance) {
+                println!(
+                    "Batch {} (frame {}), ray {:?}: submitted {:?}, but replay gave {:?}",
+                    i, batch.scene.frame, batch.rays[j], recorded, replayed
+                );
+                differences += 1;
+            }
+        }
+        rays += results.len();
+    }
+
+    println!(
+        "Replayed {} batches of {} rays in total, with {} differences",
+        batches.len(),
+        rays,
+        differences
+    );
+    if differences > 0 {
+        bail!("Replay didn't match the recording");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_workshop::protocol::Session;
+    use rust_workshop::vec::Vec3;
+
+    fn entry(event: Event) -> Entry {
+        Entry {
+            timestamp: 0,
+            event,
+        }
+    }
+
+    fn reserved(frame: u64) -> Event {
+        Event::Received(Response::ReserveRays(
+            Vec::new(),
+            Scene {
+                frame,
+                ..Scene::default()
+            },
+        ))
+    }
+
+    fn submitted(count: usize) -> Event {
+        let miss = Outcome {
+            hit: false,
+            color: None,
+        };
+        Event::Sent(Request::SubmitResults(vec![miss; count]))
+    }
+
+    #[test]
+    fn pairs_submissions_with_reservations() {
+        let entries = vec![
+            entry(Event::Connected(Session::legacy(2))),
+            entry(Event::Sent(Request::ReserveRays)),
+            entry(Event::Sent(Request::ReserveRays)),
+            entry(reserved(0)),
+            entry(submitted(1)),
+            entry(reserved(1)),
+            entry(Event::Received(Response::SubmitResults)),
+            // The connection is lost before the second batch is submitted
+            entry(Event::Connected(Session::legacy(2))),
+            entry(Event::Sent(Request::ReserveRays)),
+            entry(reserved(2)),
+            entry(submitted(3)),
+        ];
+        let batches = recorded_batches(entries);
+        let summary: Vec<_> = batches
+            .iter()
+            .map(|batch| (batch.scene.frame, batch.results.len()))
+            .collect();
+        assert_eq!(summary, [(0, 1), (2, 3)]);
+    }
+
+    #[test]
+    fn finds_recorded_shading() {
+        let shading = ShadingOpt::from_iter(["work", "--bounces", "3"]);
+        let mut entries = vec![
+            entry(Event::Connected(Session::legacy(2))),
+            entry(reserved(0)),
+        ];
+        assert!(recorded_shading(&entries).is_none());
+
+        entries.insert(0, entry(Event::Shading(shading)));
+        assert_eq!(recorded_shading(&entries).unwrap().bounces, 3);
+    }
+
+    #[test]
+    fn compares_outcomes() {
+        let outcome = |color| Outcome { hit: true, color };
+        let grey = Some(Vec3::new(0.5, 0.5, 0.5));
+        let lighter = Some(Vec3::new(0.5, 0.5, 0.6));
+        assert!(!differs(&outcome(grey), &outcome(grey), 0.0));
+        assert!(!differs(&outcome(grey), &outcome(lighter), 0.2));
+        assert!(differs(&outcome(grey), &outcome(lighter), 0.01));
+        assert!(differs(&outcome(grey), &outcome(None), 1.0));
+    }
+}
@@ -0,0 +1,930 @@
+//! Scene description files.
+//!
+//! A scene file describes everything needed to render an image locally: the
+//! camera, the spheres, other shapes and triangle meshes, and the colours used
+//! to shade them. Files may be written as JSON (`.json`) or RON (`.ron`); the
+//! format is chosen from the file extension. Everything has a default, but a
+//! scene must contain at least one sphere, shape or mesh.
+//!
+//! Meshes are loaded from Wavefront OBJ (`.obj`) or PLY (`.ply`) files, whose
+//! paths are relative to the directory containing the scene file. A file used
+//! by several meshes is only loaded once, and its triangles are shared.
+//!
+//! Every sphere, shape and mesh may have a `transform`, which scales it along
+//! each axis, rotates it by the given number of degrees about the x, y and z
+//! axes in turn, and then moves it by the translation. Any part may be left
+//! out.
+//!
+//! ```ron
+//! (
+//!     camera: (
+//!         position: (x: 0.0, y: 1.0, z: -2.0),
+//!         target: (x: 0.0, y: 0.0, z: 6.0),
+//!         up: (x: 0.0, y: 1.0, z: 0.0),
+//!         fov: 60.0,
+//!     ),
+//!     // Colour of rays which miss every sphere
+//!     background: Some((x: 0.2, y: 0.3, z: 0.5)),
+//!     // Spheres are coloured by interpolating along this palette
+//!     palette: Some([(x: 1.0, y: 0.0, z: 0.0), (x: 0.0, y: 0.0, z: 1.0)]),
+//!     // Light reaching every surface, regardless of the lights below
+//!     ambient: Some((x: 0.1, y: 0.1, z: 0.1)),
+//!     // Colour and intensity are optional, and default to white and 1.0.
+//!     // Point and spot lights get dimmer with the square of the distance.
+//!     lights: [
+//!         (kind: Directional(direction: (x: 0.0, y: -1.0, z: 0.0))),
+//!         (
+//!             kind: Point(position: (x: 2.0, y: 4.0, z: 3.0)),
+//!             color: (x: 1.0, y: 0.9, z: 0.8),
+//!             intensity: 20.0,
+//!             // Lights with a radius cast soft shadows
+//!             radius: 0.5,
+//!         ),
+//!         (
+//!             kind: Spot(
+//!                 position: (x: 0.0, y: 5.0, z: 6.0),
+//!                 direction: (x: 0.0, y: -1.0, z: 0.0),
+//!                 // Degrees between the edge of the cone and its center
+//!                 angle: 30.0,
+//!             ),
+//!             intensity: 25.0,
+//!         ),
+//!     ],
+//!     spheres: [
+//!         (center: (x: 0.0, y: 0.0, z: 6.0), radius: 1.0),
+//!         (
+//!             center: (x: 0.0, y: 0.0, z: 0.0),
+//!             radius: 1.0,
+//!             // Stretched into an ellipsoid, tipped over and moved into place
+//!             transform: (
+//!                 translation: (x: -2.0, y: 0.0, z: 6.0),
+//!                 rotation: (x: 0.0, y: 0.0, z: 30.0),
+//!                 scale: (x: 0.5, y: 1.0, z: 0.5),
+//!             ),
+//!         ),
+//!         (
+//!             center: (x: 2.0, y: 0.0, z: 6.0),
+//!             radius: 1.0,
+//!             // Every field of a material is optional
+//!             material: (
+//!                 albedo: Some((x: 1.0, y: 1.0, z: 1.0)),
+//!                 reflectivity: 0.7,
+//!                 roughness: 0.2,
+//!                 emissive: (x: 0.0, y: 0.0, z: 0.0),
+//!                 transparency: 0.0,
+//!                 ior: 1.0,
+//!             ),
+//!         ),
+//!     ],
+//!     // Objects are coloured from the palette after the spheres, in order.
+//!     // Directions don't need to be unit vectors.
+//!     shapes: [
+//!         (kind: Plane(point: (x: 0.0, y: -1.0, z: 0.0), normal: (x: 0.0, y: 1.0, z: 0.0))),
+//!         (
+//!             kind: Disc(
+//!                 center: (x: 0.0, y: 3.0, z: 8.0),
+//!                 normal: (x: 0.0, y: 0.0, z: -1.0),
+//!                 radius: 1.0,
+//!             ),
+//!             material: (emissive: (x: 1.0, y: 1.0, z: 1.0)),
+//!         ),
+//!         (kind: AxisAlignedBox(min: (x: -3.0, y: -1.0, z: 5.0), max: (x: -2.0, y: 0.0, z: 6.0))),
+//!         (
+//!             kind: OrientedBox(
+//!                 center: (x: 3.0, y: -0.5, z: 6.0),
+//!                 // Distance from the center to each face
+//!                 half_size: (x: 0.5, y: 0.5, z: 0.5),
+//!                 x_axis: (x: 1.0, y: 0.0, z: 1.0),
+//!                 y_axis: (x: 0.0, y: 1.0, z: 0.0),
+//!             ),
+//!         ),
+//!         (
+//!             kind: Triangle(
+//!                 a: (x: -1.0, y: 1.0, z: 9.0),
+//!                 b: (x: 1.0, y: 1.0, z: 9.0),
+//!                 c: (x: 0.0, y: 2.5, z: 9.0),
+//!             ),
+//!         ),
+//!         (
+//!             kind: Cylinder(
+//!                 base: (x: 0.0, y: -1.0, z: 4.0),
+//!                 top: (x: 0.0, y: -0.5, z: 4.0),
+//!                 radius: 0.3,
+//!             ),
+//!             // Lying on its side
+//!             transform: (rotation: (x: 90.0, y: 0.0, z: 0.0)),
+//!         ),
+//!     ],
+//!     // Meshes are coloured from the palette after the other shapes
+//!     meshes: [
+//!         (
+//!             path: "models/teapot.obj",
+//!             transform: (
+//!                 translation: (x: 0.0, y: -1.0, z: 5.0),
+//!                 scale: (x: 0.5, y: 0.5, z: 0.5),
+//!             ),
+//!             material: (reflectivity: 0.2),
+//!         ),
+//!         // A second teapot, sharing the first one's triangles
+//!         (
+//!             path: "models/teapot.obj",
+//!             transform: (translation: (x: 1.5, y: -1.0, z: 7.0), rotation: (x: 0.0, y: 180.0, z: 0.0)),
+//!         ),
+//!     ],
+//! )
+//! ```
+//!
+//! The equivalent JSON uses the same field names, with `null` (or a missing
+//! field) in place of `None`:
+//!
+//! ```json
+//! {
+//!     "camera": { "position": { "x": 0.0, "y": 1.0, "z": -2.0 } },
+//!     "background": { "x": 0.2, "y": 0.3, "z": 0.5 },
+//!     "lights": [
+//!         { "kind": { "Point": { "position": { "x": 2.0, "y": 4.0, "z": 3.0 } } }, "intensity": 20.0 }
+//!     ],
+//!     "spheres": [
+//!         { "center": { "x": 0.0, "y": 0.0, "z": 6.0 }, "radius": 1.0 }
+//!     ]
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use rust_workshop::geom::{Shape, ShapeKind, Sphere, Transform};
+use rust_workshop::light::{Light, LightKind};
+use rust_workshop::material::Material;
+use rust_workshop::mesh::{Mesh, MeshError, MeshInstance};
+use rust_workshop::protocol::Scene;
+use rust_workshop::vec::{Mat4, Vec3};
+
+use crate::camera::Camera;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    #[serde(default)]
+    pub camera: Camera,
+    /// Overrides the background colour given on the command line
+    #[serde(default)]
+    pub background: Option<Vec3>,
+    /// Overrides the foreground colours given on the command line
+    #[serde(default)]
+    pub palette: Option<Vec<Vec3>>,
+    /// Overrides the ambient light given on the command line
+    #[serde(default)]
+    pub ambient: Option<Vec3>,
+    /// When empty, the lights given on the command line are used
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    #[serde(default)]
+    pub spheres: Vec<Sphere>,
+    #[serde(default)]
+    pub shapes: Vec<Shape>,
+    #[serde(default)]
+    pub meshes: Vec<MeshFile>,
+    /// The meshes, once loaded from their files
+    #[serde(skip)]
+    loaded_meshes: Vec<MeshInstance>,
+}
+
+/// A triangle mesh, and where to place it in the scene. Meshes listed more
+/// than once with the same path are only loaded once, and each instance shares
+/// its triangles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeshFile {
+    /// An OBJ or PLY file, relative to the directory containing the scene file
+    pub path: PathBuf,
+    #[serde(default)]
+    pub material: Material,
+    #[serde(default, skip_serializing_if = "Transform::is_identity")]
+    pub transform: Transform,
+}
+
+#[derive(Debug, Error)]
+pub enum SceneFileError {
+    #[error("Failed to access scene file")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid JSON")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid RON")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("Failed to write RON")]
+    RonWrite(#[from] ron::Error),
+    #[error("Unknown scene format (expected .json or .ron): {}", .0.display())]
+    UnknownFormat(PathBuf),
+    #[error("Invalid scene")]
+    Invalid(#[from] ValidationError),
+    #[error("Failed to load mesh {}", .path.display())]
+    Mesh {
+        path: PathBuf,
+        #[source]
+        source: MeshError,
+    },
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("Scene contains no spheres, shapes or meshes")]
+    Empty,
+    #[error("spheres[{index}]: radius must be positive, but was {radius}")]
+    InvalidRadius { index: usize, radius: f32 },
+    #[error("spheres[{index}]: center must be finite, but was {center:?}")]
+    InvalidCenter { index: usize, center: Vec3 },
+    #[error("{list}[{index}].material.{field}: must be between 0 and 1, but was {value}")]
+    InvalidMaterial {
+        list: &'static str,
+        index: usize,
+        field: &'static str,
+        value: f32,
+    },
+    #[error("{list}[{index}].material.ior: must be positive, but was {ior}")]
+    InvalidIor {
+        list: &'static str,
+        index: usize,
+        ior: f32,
+    },
+    #[error("shapes[{index}]: {reason}")]
+    InvalidShape { index: usize, reason: &'static str },
+    #[error("lights[{index}]: {reason}")]
+    InvalidLight { index: usize, reason: &'static str },
+    #[error("palette: must contain at least one colour")]
+    EmptyPalette,
+    #[error("camera: field of view must be between 0 and 180 degrees, but was {0}")]
+    InvalidFov(f32),
+    #[error("camera: position and target must be different")]
+    NoViewingDirection,
+    #[error("camera: up must not be parallel to the viewing direction")]
+    ParallelUp,
+}
+
+enum Format {
+    Json,
+    Ron,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self, SceneFileError> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("ron") => Ok(Self::Ron),
+            _ => Err(SceneFileError::UnknownFormat(path.to_owned())),
+        }
+    }
+}
+
+impl SceneFile {
+    /// Describe a scene received from a server. Servers do not tell us where
+    /// their camera is, so the default camera is used. The scene's meshes are
+    /// rendered, but there are no files to refer to, so `save` leaves them out;
+    /// `save_with_meshes` writes them out as well.
+    pub fn from_scene(scene: &Scene) -> Self {
+        Self {
+            camera: Camera::default(),
+            background: None,
+            palette: None,
+            ambient: None,
+            lights: scene.lights.clone(),
+            spheres: scene.spheres.clone(),
+            shapes: scene.shapes.clone(),
+            meshes: Vec::new(),
+            loaded_meshes: scene.meshes.clone(),
+        }
+    }
+
+    pub fn scene(&self, frame: u64) -> Scene {
+        Scene {
+            frame,
+            spheres: self.spheres.clone(),
+            shapes: self.shapes.clone(),
+            meshes: self.loaded_meshes.clone(),
+            lights: self.lights.clone(),
+        }
+    }
+
+    /// Parse and validate a scene. Its meshes aren't loaded until
+    /// `load_meshes` is called.
+    pub fn from_json(s: &str) -> Result<Self, SceneFileError> {
+        let scene: Self = serde_json::from_str(s)?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Parse and validate a scene. Its meshes aren't loaded until
+    /// `load_meshes` is called.
+    pub fn from_ron(s: &str) -> Result<Self, SceneFileError> {
+        let scene: Self = ron::from_str(s)?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Load and validate a scene file, choosing the format from its extension,
+    /// along with the meshes it refers to
+    pub fn load(path: &Path) -> Result<Self, SceneFileError> {
+        let format = Format::from_path(path)?;
+        let contents = std::fs::read_to_string(path)?;
+        let mut scene = match format {
+            Format::Json => Self::from_json(&contents)?,
+            Format::Ron => Self::from_ron(&contents)?,
+        };
+        scene.load_meshes(path.parent().unwrap_or(Path::new("")))?;
+        Ok(scene)
+    }
+
+    /// Load the meshes from their files, relative to `dir`
+    pub fn load_meshes(&mut self, dir: &Path) -> Result<(), SceneFileError> {
+        let mut loaded: HashMap<PathBuf, Arc<Mesh>> = HashMap::new();
+        self.loaded_meshes = Vec::with_capacity(self.meshes.len());
+        for file in &self.meshes {
+            let path = dir.join(&file.path);
+            let mesh = match loaded.get(&path) {
+                Some(mesh) => mesh.clone(),
+                None => {
+                    let mesh = Mesh::load(&path).map_err(|source| SceneFileError::Mesh {
+                        path: path.clone(),
+                        source,
+                    })?;
+                    loaded.entry(path).or_insert(Arc::new(mesh)).clone()
+                }
+            };
+            self.loaded_meshes.push(MeshInstance {
+                mesh,
+                material: file.material,
+                transform: file.transform,
+            });
+        }
+        Ok(())
+    }
+
+    /// Save the scene, choosing the format from the file extension
+    pub fn save(&self, path: &Path) -> Result<(), SceneFileError> {
+        let format = Format::from_path(path)?;
+        let writer = BufWriter::new(File::create(path)?);
+        match format {
+            Format::Json => serde_json::to_writer_pretty(writer, self)?,
+            Format::Ron => ron::ser::to_writer_pretty(writer, self, Default::default())?,
+        }
+        Ok(())
+    }
+
+    /// Save the scene like `save`, along with the meshes it has loaded, which
+    /// are written as OBJ files next to it. For `scene.ron`, they're called
+    /// `scene-mesh-0.obj` and so on, and the scene refers to them instead of
+    /// any files it was loaded from.
+    pub fn save_with_meshes(&mut self, path: &Path) -> Result<(), SceneFileError> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut written: Vec<(&Arc<Mesh>, PathBuf)> = Vec::new();
+        self.meshes = Vec::with_capacity(self.loaded_meshes.len());
+        for instance in &self.loaded_meshes {
+            let known = written
+                .iter()
+                .find(|(mesh, _)| Arc::ptr_eq(mesh, &instance.mesh));
+            let file = match known {
+                Some((_, file)) => file.clone(),
+                None => {
+                    let file = PathBuf::from(format!("{}-mesh-{}.obj", stem, written.len()));
+                    instance
+                        .mesh
+                        .write_obj(BufWriter::new(File::create(dir.join(&file))?))?;
+                    written.push((&instance.mesh, file.clone()));
+                    file
+                }
+            };
+            self.meshes.push(MeshFile {
+                path: file,
+                material: instance.material,
+                transform: instance.transform,
+            });
+        }
+        self.save(path)
+    }
+
+    /// Check the scene for values which would make it impossible to render,
+    /// reporting the first problem found.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if !(self.camera.fov > 0.0 && self.camera.fov < 180.0) {
+            return Err(ValidationError::InvalidFov(self.camera.fov));
+        }
+        if self.camera.position == self.camera.target {
+            return Err(ValidationError::NoViewingDirection);
+        }
+        if Mat4::look_at(self.camera.position, self.camera.target, self.camera.up).is_none() {
+            return Err(ValidationError::ParallelUp);
+        }
+        if self.palette.as_ref().is_some_and(Vec::is_empty) {
+            return Err(ValidationError::EmptyPalette);
+        }
+        for (index, light) in self.lights.iter().enumerate() {
+            let invalid = |reason| Err(ValidationError::InvalidLight { index, reason });
+            if !(light.intensity >= 0.0 && light.intensity.is_finite()) {
+                return invalid("intensity must be a non-negative number");
+            }
+            if !(light.radius >= 0.0 && light.radius.is_finite()) {
+                return invalid("radius must be a non-negative number");
+            }
+            if let LightKind::Spot { angle, .. } = light.kind {
+                if !(angle > 0.0 && angle < 180.0) {
+                    return invalid("angle must be between 0 and 180 degrees");
+                }
+            }
+        }
+        if self.spheres.is_empty() && self.shapes.is_empty() && self.meshes.is_empty() {
+            return Err(ValidationError::Empty);
+        }
+        for (index, sphere) in self.spheres.iter().enumerate() {
+            let center = sphere.center;
+            if !(center.x.is_finite() && center.y.is_finite() && center.z.is_finite()) {
+                return Err(ValidationError::InvalidCenter { index, center });
+            }
+            // Written this way round so that NaN is rejected too
+            if !(sphere.radius > 0.0 && sphere.radius.is_finite()) {
+                return Err(ValidationError::InvalidRadius {
+                    index,
+                    radius: sphere.radius,
+                });
+            }
+            validate_material("spheres", index, &sphere.material)?;
+        }
+        for (index, shape) in self.shapes.iter().enumerate() {
+            if let Err(reason) = validate_shape(&shape.kind) {
+                return Err(ValidationError::InvalidShape { index, reason });
+            }
+            validate_material("shapes", index, &shape.material)?;
+        }
+        for (index, mesh) in self.meshes.iter().enumerate() {
+            validate_material("meshes", index, &mesh.material)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_material(
+    list: &'static str,
+    index: usize,
+    material: &Material,
+) -> Result<(), ValidationError> {
+    for (field, value) in [
+        ("reflectivity", material.reflectivity),
+        ("roughness", material.roughness),
+        ("transparency", material.transparency),
+    ] {
+        if !(0.0..=1.0).contains(&value) {
+            return Err(ValidationError::InvalidMaterial {
+                list,
+                index,
+                field,
+                value,
+            });
+        }
+    }
+    if !(material.ior.is_finite() && material.ior > 0.0) {
+        return Err(ValidationError::InvalidIor {
+            list,
+            index,
+            ior: material.ior,
+        });
+    }
+    Ok(())
+}
+
+/// Check that a shape has a size and a well defined orientation
+fn validate_shape(kind: &ShapeKind) -> Result<(), &'static str> {
+    let finite = |v: Vec3| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+    let (points, radius): (&[Vec3], _) = match kind {
+        ShapeKind::Plane { point, normal } => {
+            if normal.length() == 0.0 {
+                return Err("normal must not be zero");
+            }
+            (&[*point, *normal], None)
+        }
+        ShapeKind::Disc {
+            center,
+            normal,
+            radius,
+        } => {
+            if normal.length() == 0.0 {
+                return Err("normal must not be zero");
+            }
+            (&[*center, *normal], Some(*radius))
+        }
+        ShapeKind::AxisAlignedBox { min, max } => {
+            if !(min.x < max.x && min.y < max.y && min.z < max.z) {
+                return Err("min must be below max along every axis");
+            }
+            (&[*min, *max], None)
+        }
+        ShapeKind::OrientedBox {
+            center,
+            half_size,
+            x_axis,
+            y_axis,
+        } => {
+            if !(half_size.x > 0.0 && half_size.y > 0.0 && half_size.z > 0.0) {
+                return Err("half_size must be positive along every axis");
+            }
+            if x_axis.cross(y_axis).length() == 0.0 {
+                return Err("x_axis and y_axis must be non-zero and point in different directions");
+            }
+            (&[*center, *half_size, *x_axis, *y_axis], None)
+        }
+        ShapeKind::Triangle { a, b, c } => {
+            if (*b - *a).cross(&(*c - *a)).length() == 0.0 {
+                return Err("corners must not lie on a line");
+            }
+            (&[*a, *b, *c], None)
+        }
+        ShapeKind::Cylinder { base, top, radius } => {
+            if base == top {
+                return Err("base and top must be different");
+            }
+            (&[*base, *top], Some(*radius))
+        }
+    };
+    if !points.iter().all(|&point| finite(point)) {
+        return Err("coordinates must be finite");
+    }
+    // Written this way round so that NaN is rejected too
+    if radius.is_some_and(|radius| !(radius > 0.0 && radius.is_finite())) {
+        return Err("radius must be positive");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(result: Result<SceneFile, SceneFileError>) -> ValidationError {
+        match result {
+            Err(SceneFileError::Invalid(e)) => e,
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn example_scenes() {
+        let ron = SceneFile::from_ron(include_str!("../scenes/demo.ron")).unwrap();
+        let json = SceneFile::from_json(include_str!("../scenes/demo.json")).unwrap();
+        assert_eq!(ron.spheres.len(), json.spheres.len());
+        assert_eq!(ron.background, json.background);
+    }
+
+    #[test]
+    fn defaults() {
+        let scene = SceneFile::from_json(
+            r#"{ "spheres": [{ "center": { "x": 0, "y": 0, "z": 5 }, "radius": 1 }] }"#,
+        )
+        .unwrap();
+        assert_eq!(scene.camera.fov, Camera::default().fov);
+        assert_eq!(scene.background, None);
+    }
+
+    #[test]
+    fn unknown_field() {
+        let result = SceneFile::from_ron("(spheres: [], sphere: [])");
+        assert!(matches!(result, Err(SceneFileError::Ron(_))));
+    }
+
+    #[test]
+    fn empty_scene() {
+        assert_eq!(
+            invalid(SceneFile::from_ron("(spheres: [])")),
+            ValidationError::Empty
+        );
+        assert_eq!(invalid(SceneFile::from_ron("()")), ValidationError::Empty);
+    }
+
+    #[test]
+    fn shapes_only() {
+        let scene = SceneFile::from_ron(
+            "(shapes: [(kind: Plane(point: (x: 0.0, y: -1.0, z: 0.0), normal: (x: 0.0, y: 1.0, z: 0.0)))])",
+        )
+        .unwrap();
+        assert!(scene.spheres.is_empty());
+        assert_eq!(scene.scene(0).shapes, scene.shapes);
+    }
+
+    #[test]
+    fn bad_shape() {
+        let result = SceneFile::from_ron(
+            "(shapes: [
+                (kind: Plane(point: (x: 0.0, y: -1.0, z: 0.0), normal: (x: 0.0, y: 1.0, z: 0.0))),
+                (kind: Cylinder(base: (x: 0.0, y: 0.0, z: 5.0), top: (x: 0.0, y: 1.0, z: 5.0), radius: -1.0)),
+            ])",
+        );
+        assert_eq!(
+            invalid(result),
+            ValidationError::InvalidShape {
+                index: 1,
+                reason: "radius must be positive"
+            }
+        );
+
+        let result = SceneFile::from_ron(
+            "(shapes: [(
+                kind: Triangle(a: (x: 0.0, y: 0.0, z: 5.0), b: (x: 1.0, y: 0.0, z: 5.0), c: (x: 0.0, y: 1.0, z: 5.0)),
+                material: (reflectivity: 2.0),
+            )])",
+        );
+        assert_eq!(
+            invalid(result),
+            ValidationError::InvalidMaterial {
+                list: "shapes",
+                index: 0,
+                field: "reflectivity",
+                value: 2.0
+            }
+        );
+    }
+
+    #[test]
+    fn meshes() {
+        let dir = std::env::temp_dir().join(format!("rust-workshop-meshes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("models")).unwrap();
+        std::fs::write(
+            dir.join("models/triangle.obj"),
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n",
+        )
+        .unwrap();
+        let write_scene = |mesh: &str| {
+            let path = dir.join("scene.ron");
+            let scene = format!(
+                "(meshes: [
+                    (path: {:?}, material: (reflectivity: 0.0)),
+                    (path: {:?}, transform: (translation: (x: 0.0, y: 0.0, z: 5.0))),
+                ])",
+                mesh, mesh
+            );
+            std::fs::write(&path, scene).unwrap();
+            path
+        };
+
+        // Mesh paths are relative to the scene file, and each file is only loaded once
+        let scene = SceneFile::load(&write_scene("models/triangle.obj")).unwrap();
+        let meshes = scene.scene(0).meshes;
+        assert!(Arc::ptr_eq(&meshes[0].mesh, &meshes[1].mesh));
+        assert_eq!(meshes[0].mesh.positions()[1], Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(meshes[0].material.reflectivity, 0.0);
+        assert_eq!(meshes[1].transform.translation(), Vec3::new(0.0, 0.0, 5.0));
+
+        let result = SceneFile::load(&write_scene("models/missing.obj"));
+        assert!(
+            matches!(&result, Err(SceneFileError::Mesh { path, .. }) if path.ends_with("models/missing.obj")),
+            "{:?}",
+            result
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_transform() {
+        let result = SceneFile::from_ron(
+            "(spheres: [(
+                center: (x: 0.0, y: 0.0, z: 5.0),
+                radius: 1.0,
+                transform: (scale: (x: 1.0, y: 0.0, z: 1.0)),
+            )])",
+        );
+        assert!(
+            matches!(result, Err(SceneFileError::Ron(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn negative_radius() {
+        let result = SceneFile::from_ron(
+            "(spheres: [
+                (center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0),
+                (center: (x: 1.0, y: 0.0, z: 5.0), radius: -1.0),
+            ])",
+        );
+        assert_eq!(
+            invalid(result),
+            ValidationError::InvalidRadius {
+                index: 1,
+                radius: -1.0
+            }
+        );
+    }
+
+    #[test]
+    fn nan_center() {
+        let result =
+            SceneFile::from_ron("(spheres: [(center: (x: 0.0, y: NaN, z: 5.0), radius: 1.0)])");
+        assert!(matches!(
+            invalid(result),
+            ValidationError::InvalidCenter { index: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn bad_material() {
+        let result = SceneFile::from_ron(
+            "(spheres: [(
+                center: (x: 0.0, y: 0.0, z: 5.0),
+                radius: 1.0,
+                material: (roughness: 1.5),
+            )])",
+        );
+        assert_eq!(
+            invalid(result),
+            ValidationError::InvalidMaterial {
+                list: "spheres",
+                index: 0,
+                field: "roughness",
+                value: 1.5
+            }
+        );
+    }
+
+    #[test]
+    fn bad_ior() {
+        let result = SceneFile::from_ron(
+            "(spheres: [(
+                center: (x: 0.0, y: 0.0, z: 5.0),
+                radius: 1.0,
+                material: (transparency: 1.0, ior: 0.0),
+            )])",
+        );
+        assert_eq!(
+            invalid(result),
+            ValidationError::InvalidIor {
+                list: "spheres",
+                index: 0,
+                ior: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn bad_light() {
+        let result = SceneFile::from_ron(
+            "(
+                lights: [
+                    (kind: Point(position: (x: 0.0, y: 5.0, z: 0.0)), intensity: 10.0),
+                    (kind: Spot(
+                        position: (x: 0.0, y: 5.0, z: 0.0),
+                        direction: (x: 0.0, y: -1.0, z: 0.0),
+                        angle: 180.0,
+                    )),
+                ],
+                spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+            )",
+        );
+        assert_eq!(
+            invalid(result),
+            ValidationError::InvalidLight {
+                index: 1,
+                reason: "angle must be between 0 and 180 degrees"
+            }
+        );
+
+        // Lights without a direction don't even load
+        let result = SceneFile::from_ron(
+            "(
+                lights: [(kind: Directional(direction: (x: 0.0, y: 0.0, z: 0.0)))],
+                spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+            )",
+        );
+        assert!(
+            matches!(result, Err(SceneFileError::Ron(e)) if e.to_string().contains("must not be zero"))
+        );
+    }
+
+    #[test]
+    fn light_directions_need_not_be_unit_length() {
+        let scene = SceneFile::from_ron(
+            "(
+                lights: [
+                    (kind: Directional(direction: (x: 0.0, y: -2.0, z: 0.0))),
+                    (kind: Spot(
+                        position: (x: 0.0, y: 5.0, z: 0.0),
+                        direction: (x: 0.0, y: -3.0, z: 0.0),
+                        angle: 30.0,
+                    )),
+                ],
+                spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+            )",
+        )
+        .unwrap();
+        let sun = scene.lights[0]
+            .illuminate(Vec3::new(0.0, 0.0, 0.0))
+            .unwrap();
+        assert_eq!(sun.direction, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(sun.radiance, Vec3::new(1.0, 1.0, 1.0));
+
+        let spot = &scene.lights[1];
+        let lit = spot.illuminate(Vec3::new(0.0, 0.0, 0.0)).unwrap();
+        assert_eq!(lit.radiance, (1.0 / 25.0) * Vec3::new(1.0, 1.0, 1.0));
+        // 45 degrees from the spot's direction, well outside its cone
+        assert!(spot.illuminate(Vec3::new(5.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn bad_camera() {
+        let result = SceneFile::from_ron(
+            "(
+                camera: (fov: 180.0),
+                spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+            )",
+        );
+        assert_eq!(invalid(result), ValidationError::InvalidFov(180.0));
+
+        let result = SceneFile::from_ron(
+            "(
+                camera: (target: (x: 0.0, y: 0.0, z: 0.0)),
+                spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+            )",
+        );
+        assert_eq!(invalid(result), ValidationError::NoViewingDirection);
+
+        let result = SceneFile::from_ron(
+            "(
+                camera: (target: (x: 0.0, y: -3.0, z: 0.0)),
+                spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+            )",
+        );
+        assert_eq!(invalid(result), ValidationError::ParallelUp);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let scene = SceneFile::from_scene(&Scene::demo(3));
+        for ext in ["json", "ron"] {
+            let path = std::env::temp_dir().join(format!(
+                "rust-workshop-scene-{}.{}",
+                std::process::id(),
+                ext
+            ));
+            scene.save(&path).unwrap();
+            let loaded = SceneFile::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.spheres.len(), scene.spheres.len());
+            assert_eq!(loaded.spheres[2].center, scene.spheres[2].center);
+        }
+    }
+
+    #[test]
+    fn save_with_meshes() {
+        let dir =
+            std::env::temp_dir().join(format!("rust-workshop-save-meshes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let triangle = Arc::new(
+            Mesh::new(
+                vec![
+                    Vec3::new(0.0, 0.0, 5.0),
+                    Vec3::new(1.0, 0.0, 5.0),
+                    Vec3::new(0.0, 1.0, 5.0),
+                ],
+                Vec::new(),
+                vec![[0, 1, 2]],
+            )
+            .unwrap(),
+        );
+        let scene = Scene {
+            meshes: vec![
+                MeshInstance::new(triangle.clone()),
+                MeshInstance {
+                    material: Material {
+                        reflectivity: 0.0,
+                        ..Material::default()
+                    },
+                    transform: Transform::new(
+                        Vec3::new(2.0, 0.0, 0.0),
+                        Vec3::new(0.0, 0.0, 0.0),
+                        Vec3::new(1.0, 1.0, 1.0),
+                    )
+                    .unwrap(),
+                    ..MeshInstance::new(triangle)
+                },
+            ],
+            ..Scene::demo(3)
+        };
+
+        let path = dir.join("scene-00003.ron");
+        SceneFile::from_scene(&scene)
+            .save_with_meshes(&path)
+            .unwrap();
+        // Instances of the same mesh share a file
+        assert!(dir.join("scene-00003-mesh-0.obj").exists());
+        assert!(!dir.join("scene-00003-mesh-1.obj").exists());
+
+        let loaded = SceneFile::load(&path).unwrap();
+        assert_eq!(loaded.scene(3), scene);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,798 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::{SocketAddr, TcpListener, TcpStream},
+    num::NonZeroUsize,
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+use anyhow::bail;
+use byteorder::{ReadBytesExt, BE};
+use structopt::StructOpt;
+
+use rust_workshop::geom::Ray;
+use rust_workshop::protocol::{
+    feature_names, read_message, write_message, Capabilities, FrameLimits, Framing, Hello,
+    HelloResponse, MeshCache, Outcome, ProtocolError, Request, Response, Scene, Session,
+    FEATURE_CODECS, FEATURE_MATERIALS, FEATURE_MESHES, FEATURE_MESH_CACHE, FEATURE_SHAPES,
+    FEATURE_TRANSFORMS, HANDSHAKE, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use rust_workshop::vec::Vec3;
+
+use crate::camera::Camera;
+use crate::image::Image;
+use crate::scene_file::SceneFile;
+
+#[derive(StructOpt)]
+pub struct ServeOpt {
+    #[structopt(long, default_value = "0.0.0.0:5000")]
+    pub bind: SocketAddr,
+    #[structopt(long, default_value = "640")]
+    pub width: u32,
+    #[structopt(long, default_value = "480")]
+    pub height: u32,
+    /// Number of rays handed out in response to each reservation
+    #[structopt(long, default_value = "4096")]
+    pub batch_size: NonZeroUsize,
+    /// Stop after rendering this many frames
+    #[structopt(long)]
+    pub frames: Option<u64>,
+    /// Directory in which finished frames are saved
+    #[structopt(long, default_value = ".")]
+    pub output: PathBuf,
+    /// Scene file to render (.json or .ron). Renders a built-in demo scene if omitted.
+    #[structopt(long)]
+    pub scene: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub limits: FrameLimits,
+}
+
+/// A range of rays handed out to a worker for a particular frame
+struct Batch {
+    frame: u64,
+    range: Range<usize>,
+}
+
+#[derive(Default)]
+struct WorkerStats {
+    name: String,
+    rays_traced: u64,
+}
+
+/// Everything we know about the frame currently being rendered
+struct Frame {
+    scene: Scene,
+    rays: Vec<Ray>,
+    image: Image,
+    /// Which rays have had results submitted
+    done: Vec<bool>,
+    remaining: usize,
+    /// Batches which have never been handed out
+    pending: VecDeque<Range<usize>>,
+    /// Batches which have been handed out but not yet completed. When there
+    /// are no pending batches left, these are handed out again so that a slow
+    /// or disconnected worker cannot hold up the frame.
+    in_progress: VecDeque<Range<usize>>,
+    started: Instant,
+}
+
+impl Frame {
+    fn new(number: u64, opt: &ServeOpt, scene_file: Option<&SceneFile>) -> Self {
+        let (camera, scene) = match scene_file {
+            Some(scene_file) => (scene_file.camera, scene_file.scene(number)),
+            None => (Camera::default(), Scene::demo(number)),
+        };
+        let rays = camera.rays(opt.width, opt.height);
+        let batch_size = opt.batch_size.get();
+        let pending = (0..rays.len())
+            .step_by(batch_size)
+            .map(|start| start..(start + batch_size).min(rays.len()))
+            .collect();
+        Self {
+            scene,
+            image: Image::new(opt.width, opt.height),
+            done: vec![false; rays.len()],
+            remaining: rays.len(),
+            rays,
+            pending,
+            in_progress: VecDeque::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Choose the next batch of rays to hand out, if any. Batches larger than
+    /// `max_size` are split, and the rest is handed out later.
+    fn next_batch(&mut self, max_size: usize) -> Option<Range<usize>> {
+        let split = |range: Range<usize>| {
+            let middle = range.start + range.len().min(max_size);
+            (range.start..middle, middle..range.end)
+        };
+        if let Some(range) = self.pending.pop_front() {
+            let (range, rest) = split(range);
+            if !rest.is_empty() {
+                self.pending.push_front(rest);
+            }
+            self.in_progress.push_back(range.clone());
+            return Some(range);
+        }
+        // Re-issue the oldest incomplete batch, dropping any which have since completed
+        while let Some(range) = self.in_progress.pop_front() {
+            if range.clone().any(|i| !self.done[i]) {
+                let (range, rest) = split(range);
+                if !rest.is_empty() {
+                    self.in_progress.push_front(rest);
+                }
+                self.in_progress.push_back(range.clone());
+                return Some(range);
+            }
+        }
+        None
+    }
+}
+
+struct State {
+    frame_number: u64,
+    frame: Frame,
+    frames_completed: u64,
+    finished: bool,
+    /// Why rendering stopped early, if it did
+    error: Option<anyhow::Error>,
+    workers: BTreeMap<u64, WorkerStats>,
+}
+
+struct Shared {
+    opt: ServeOpt,
+    scene_file: Option<SceneFile>,
+    /// The features workers need to be sent the scene. Scene files have the
+    /// same objects in every frame, so this doesn't change.
+    required_features: u32,
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+impl Shared {
+    fn reserve(&self, max_batch_size: usize) -> Option<(Batch, Vec<Ray>, Scene)> {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return None;
+        }
+        let frame = state.frame_number;
+        let range = state.frame.next_batch(max_batch_size)?;
+        let rays = state.frame.rays[range.clone()].to_vec();
+        Some((Batch { frame, range }, rays, state.frame.scene.clone()))
+    }
+
+    fn submit(&self, worker_id: u64, batch: Batch, results: Vec<Outcome>) -> anyhow::Result<()> {
+        if results.len() != batch.range.len() {
+            bail!(
+                "Expected {} results but received {}",
+                batch.range.len(),
+                results.len()
+            );
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.workers.entry(worker_id).or_default().rays_traced += results.len() as u64;
+
+        // Results for an earlier frame are no longer useful
+        if batch.frame != state.frame_number || state.finished {
+            return Ok(());
+        }
+
+        let frame = &mut state.frame;
+        for (i, outcome) in batch.range.zip(results) {
+            if !frame.done[i] {
+                frame.done[i] = true;
+                frame.remaining -= 1;
+                frame.image.pixels[i] = outcome.color.unwrap_or(Vec3::new(0.0, 0.0, 0.0));
+            }
+        }
+
+        if frame.remaining == 0 {
+            self.finish_frame(&mut state);
+        }
+        Ok(())
+    }
+
+    fn finish_frame(&self, state: &mut State) {
+        let path = self
+            .opt
+            .output
+            .join(format!("frame-{:05}.ppm", state.frame_number));
+        if let Err(e) = state.frame.image.save(&path) {
+            // Stop rather than carry on with a gap in the frames. Workers see
+            // there's nothing left to render, and `run` reports the error.
+            state.error = Some(e.context(format!("Failed to save {}", path.display())));
+            state.finished = true;
+            self.finished.notify_all();
+            return;
+        }
+
+        println!(
+            "Frame {} completed in {:.2?}, saved to {}",
+            state.frame_number,
+            state.frame.started.elapsed(),
+            path.display()
+        );
+        for stats in state.workers.values() {
+            println!("  {:>20}: {} rays", stats.name, stats.rays_traced);
+        }
+
+        state.frames_completed += 1;
+        if Some(state.frames_completed) == self.opt.frames {
+            state.finished = true;
+            self.finished.notify_all();
+        } else {
+            state.frame_number += 1;
+            state.frame = Frame::new(state.frame_number, &self.opt, self.scene_file.as_ref());
+        }
+    }
+
+    fn set_name(&self, worker_id: u64, name: String) {
+        println!("Worker {} is called {:?}", worker_id, name);
+        self.state
+            .lock()
+            .unwrap()
+            .workers
+            .entry(worker_id)
+            .or_default()
+            .name = name;
+    }
+
+    fn handle_worker(&self, worker_id: u64, mut stream: TcpStream) -> anyhow::Result<()> {
+        stream.set_nodelay(true)?;
+
+        let session = match stream.read_u32::<BE>()? {
+            HANDSHAKE => {
+                let hello: Hello = read_message(&mut stream, Framing::Plain, &self.opt.limits)?;
+                // Workers pick the codec, so offer them all
+                let capabilities = Capabilities {
+                    features: FEATURE_CODECS
+                        | FEATURE_SHAPES
+                        | FEATURE_MESHES
+                        | FEATURE_TRANSFORMS
+                        | FEATURE_MATERIALS
+                        | FEATURE_MESH_CACHE,
+                    ..Capabilities::default()
+                };
+                let session = hello.negotiate(&capabilities).and_then(|session| {
+                    // Workers which can't decode the whole scene would render
+                    // tiles with objects missing
+                    let missing = self.required_features & !session.capabilities.features;
+                    if missing == 0 {
+                        Ok(session)
+                    } else {
+                        Err(format!(
+                            "The scene needs features the worker lacks: {}",
+                            feature_names(missing).join(", ")
+                        ))
+                    }
+                });
+                let response = match &session {
+                    Ok(session) => HelloResponse::Accepted(session.clone()),
+                    Err(reason) => HelloResponse::Rejected(reason.clone()),
+                };
+                write_message(&mut stream, &response, Framing::Plain)?;
+                session.map_err(ProtocolError::Rejected)?
+            }
+            // Workers which predate the handshake just tell us what version they speak
+            version @ MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION => {
+                if self.required_features != 0 {
+                    bail!("Workers which predate the handshake can't render this scene");
+                }
+                Session::legacy(version)
+            }
+            version => return Err(ProtocolError::VersionMismatch(version).into()),
+        };
+        let framing = session.framing();
+        let mut meshes =
+            (session.capabilities.features & FEATURE_MESH_CACHE != 0).then(MeshCache::default);
+        let max_batch_size = session
+            .capabilities
+            .max_batch_size
+            .map_or(usize::MAX, |size| size as usize);
+
+        // Batches handed out to this worker, in the order they were reserved.
+        // Workers submit results in the same order.
+        let mut outstanding = VecDeque::new();
+
+        loop {
+            let request = match read_message(&mut stream, framing, &self.opt.limits) {
+                Ok(request) => request,
+                Err(ProtocolError::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let response = match request {
+                Request::ReserveRays => match self.reserve(max_batch_size) {
+                    Some((batch, rays, scene)) => {
+                        outstanding.push_back(batch);
+                        Response::ReserveRays(rays, scene)
+                    }
+                    // We've rendered everything we were asked to
+                    None => return Ok(()),
+                },
+                Request::SubmitResults(results) => {
+                    let batch = match outstanding.pop_front() {
+                        Some(batch) => batch,
+                        None => bail!("Results submitted without reserving any rays"),
+                    };
+                    self.submit(worker_id, batch, results)?;
+                    Response::SubmitResults
+                }
+                Request::SetName(name) => {
+                    self.set_name(worker_id, name);
+                    Response::SetName
+                }
+            };
+            match &mut meshes {
+                Some(meshes) => write_message(&mut stream, &meshes.outgoing(&response), framing)?,
+                None => write_message(&mut stream, &response, framing)?,
+            }
+        }
+    }
+}
+
+/// Accept workers from the listener and hand out rays until the requested
+/// number of frames has been rendered (or forever, if no limit was given).
+pub fn run(listener: TcpListener, opt: ServeOpt) -> anyhow::Result<()> {
+    let scene_file = opt.scene.as_deref().map(SceneFile::load).transpose()?;
+    let required_features = scene_file
+        .as_ref()
+        .map_or(0, |scene_file| scene_file.scene(0).required_features());
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            frame_number: 0,
+            frame: Frame::new(0, &opt, scene_file.as_ref()),
+            frames_completed: 0,
+            finished: false,
+            error: None,
+            workers: BTreeMap::new(),
+        }),
+        finished: Condvar::new(),
+        scene_file,
+        required_features,
+        opt,
+    });
+
+    println!("Listening on {}", listener.local_addr()?);
+
+    {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            for (worker_id, stream) in (0..).zip(listener.incoming()) {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    if let Err(e) = shared.handle_worker(worker_id, stream) {
+                        eprintln!("Worker {} disconnected: {}", worker_id, e);
+                    }
+                });
+            }
+        });
+    }
+
+    let mut state = shared.state.lock().unwrap();
+    while !state.finished {
+        state = shared.finished.wait(state).unwrap();
+    }
+    match state.error.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+pub fn serve(opt: ServeOpt) -> anyhow::Result<()> {
+    run(TcpListener::bind(opt.bind)?, opt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::time::Duration;
+
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+    use rust_workshop::client::Connection;
+
+    /// Start a server which renders a single 8x6 frame into a new directory
+    fn start_server(name: &str) -> (SocketAddr, PathBuf, thread::JoinHandle<anyhow::Result<()>>) {
+        start_server_with_scene(name, None)
+    }
+
+    fn start_server_with_scene(
+        name: &str,
+        scene: Option<PathBuf>,
+    ) -> (SocketAddr, PathBuf, thread::JoinHandle<anyhow::Result<()>>) {
+        let output =
+            std::env::temp_dir().join(format!("rust-workshop-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&output).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opt = ServeOpt {
+            bind: addr,
+            width: 8,
+            height: 6,
+            batch_size: NonZeroUsize::new(5).unwrap(),
+            frames: Some(1),
+            output: output.clone(),
+            scene,
+            limits: FrameLimits::default(),
+        };
+        (addr, output, thread::spawn(move || run(listener, opt)))
+    }
+
+    fn white(rays: &[Ray]) -> Vec<Outcome> {
+        rays.iter()
+            .map(|_| Outcome {
+                hit: true,
+                color: Some(Vec3::new(1.0, 1.0, 1.0)),
+            })
+            .collect()
+    }
+
+    fn assert_white_frame(output: &Path) {
+        let data = std::fs::read(output.join("frame-00000.ppm")).unwrap();
+        assert!(data.starts_with(b"P6\n8 6\n255\n"));
+        assert!(data[11..].iter().all(|&c| c == 255));
+        std::fs::remove_dir_all(output).unwrap();
+    }
+
+    #[test]
+    fn renders_a_frame() {
+        let (addr, output, server) = start_server("serve");
+
+        let mut connection =
+            Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None)
+                .unwrap();
+        connection.set_name("test".into()).unwrap();
+        let mut rays_traced = 0;
+        // The server disconnects once it has the whole frame
+        let error = loop {
+            match connection.reserve_rays() {
+                Ok((rays, _)) => {
+                    rays_traced += rays.len();
+                    connection.submit_results(white(&rays)).unwrap();
+                }
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(error, ProtocolError::Eof));
+        server.join().unwrap().unwrap();
+
+        assert_eq!(rays_traced, 48);
+        assert_white_frame(&output);
+    }
+
+    #[test]
+    fn failing_to_save_stops_the_server() {
+        let (addr, output, server) = start_server("unsaved");
+        std::fs::remove_dir_all(&output).unwrap();
+
+        let mut connection =
+            Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None)
+                .unwrap();
+        while let Ok((rays, _)) = connection.reserve_rays() {
+            connection.submit_results(white(&rays)).unwrap();
+        }
+        let error = server.join().unwrap().unwrap_err();
+        assert!(error.to_string().contains("frame-00000.ppm"));
+    }
+
+    #[test]
+    fn pipelined_reservations() {
+        let (addr, output, server) = start_server("pipelined");
+
+        let mut connection =
+            Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None)
+                .unwrap();
+        // Keep three batches reserved at once, and don't wait for submissions to be
+        // acknowledged
+        for _ in 0..3 {
+            connection.send(&Request::ReserveRays).unwrap();
+        }
+        loop {
+            match connection.receive() {
+                Ok(Response::ReserveRays(rays, _)) => {
+                    let sent = connection
+                        .send(&Request::ReserveRays)
+                        .and_then(|_| connection.send(&Request::SubmitResults(white(&rays))));
+                    // The server may have already finished the frame and disconnected
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+                Ok(Response::SubmitResults) => {}
+                Ok(response) => panic!("Unexpected response {:?}", response),
+                // The server disconnects once it has the whole frame
+                Err(_) => break,
+            }
+        }
+        server.join().unwrap().unwrap();
+        assert_white_frame(&output);
+    }
+
+    #[test]
+    fn negotiated_batch_size() {
+        let (addr, output, server) = start_server("batch-size");
+
+        let capabilities = Capabilities {
+            max_batch_size: Some(3),
+            ..Capabilities::default()
+        };
+        let mut connection =
+            Connection::connect(addr, &capabilities, FrameLimits::default(), None).unwrap();
+        while let Ok((rays, _)) = connection.reserve_rays() {
+            // The server hands out batches of 5 unless asked otherwise
+            assert!(rays.len() <= 3);
+            connection.submit_results(white(&rays)).unwrap();
+        }
+        server.join().unwrap().unwrap();
+        assert_white_frame(&output);
+    }
+
+    #[test]
+    fn negotiated_codecs() {
+        for framing in [Framing::Lz4, Framing::Zstd, Framing::Json] {
+            let (addr, output, server) = start_server(&format!("codec-{:?}", framing));
+
+            let capabilities = Capabilities {
+                features: framing.feature(),
+                ..Capabilities::default()
+            };
+            let mut connection =
+                Connection::connect(addr, &capabilities, FrameLimits::default(), None).unwrap();
+            assert_eq!(connection.session().framing(), framing);
+            while let Ok((rays, _)) = connection.reserve_rays() {
+                connection.submit_results(white(&rays)).unwrap();
+            }
+            server.join().unwrap().unwrap();
+            assert_white_frame(&output);
+        }
+    }
+
+    #[test]
+    fn transforms_need_negotiating() {
+        let scene = std::env::temp_dir().join(format!(
+            "rust-workshop-transforms-{}.ron",
+            std::process::id()
+        ));
+        std::fs::write(
+            &scene,
+            "(spheres: [
+                (center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0),
+                (center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0, transform: (scale: (x: 2.0, y: 1.0, z: 1.0))),
+            ])",
+        )
+        .unwrap();
+        let (addr, output, server) = start_server_with_scene("transforms", Some(scene.clone()));
+
+        // Workers which can't place the stretched sphere are turned away, rather
+        // than rendering tiles without it
+        let result =
+            Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None);
+        assert!(
+            matches!(result, Err(ProtocolError::Rejected(reason)) if reason.contains("transforms"))
+        );
+
+        // As are workers which predate the handshake
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_u32::<BE>(2).unwrap();
+        let framing = Session::legacy(2).framing();
+        let _ = write_message(&mut stream, &Request::ReserveRays, framing);
+        let response: Result<Response, _> =
+            read_message(&mut stream, framing, &FrameLimits::default());
+        assert!(response.unwrap_err().is_disconnect());
+
+        let capabilities = Capabilities {
+            features: FEATURE_TRANSFORMS,
+            ..Capabilities::default()
+        };
+        let mut connection =
+            Connection::connect(addr, &capabilities, FrameLimits::default(), None).unwrap();
+        let (rays, sent) = connection.reserve_rays().unwrap();
+        assert_eq!(sent.spheres.len(), 2);
+        assert!(!sent.spheres[1].transform.is_identity());
+        connection.submit_results(white(&rays)).unwrap();
+        while let Ok((rays, _)) = connection.reserve_rays() {
+            connection.submit_results(white(&rays)).unwrap();
+        }
+        server.join().unwrap().unwrap();
+        assert_white_frame(&output);
+        std::fs::remove_file(&scene).unwrap();
+    }
+
+    #[test]
+    fn shapes_and_meshes_need_negotiating() {
+        let name = format!("rust-workshop-shapes-{}", std::process::id());
+        let scene = std::env::temp_dir().join(format!("{}.ron", name));
+        let mesh = std::env::temp_dir().join(format!("{}.obj", name));
+        std::fs::write(&mesh, "v 0 0 4\nv 1 0 4\nv 0 1 4\nf 1 2 3\n").unwrap();
+        std::fs::write(
+            &scene,
+            format!(
+                "(
+                    spheres: [(center: (x: 0.0, y: 0.0, z: 5.0), radius: 1.0)],
+                    shapes: [(kind: Plane(point: (x: 0.0, y: -1.0, z: 0.0), normal: (x: 0.0, y: 1.0, z: 0.0)))],
+                    meshes: [(path: \"{}.obj\")],
+                )",
+                name
+            ),
+        )
+        .unwrap();
+        let (addr, output, server) = start_server_with_scene("shapes", Some(scene.clone()));
+
+        // Workers which can't decode every object in the scene are turned away
+        let mut rejections = Vec::new();
+        for features in [FEATURE_SHAPES, FEATURE_MESHES, 0] {
+            let capabilities = Capabilities {
+                features,
+                ..Capabilities::default()
+            };
+            match Connection::connect(addr, &capabilities, FrameLimits::default(), None) {
+                Err(ProtocolError::Rejected(reason)) => rejections.push(reason),
+                result => panic!("Expected a rejection, got {:?}", result.map(|_| ())),
+            }
+        }
+        assert!(rejections[0].ends_with(": meshes"));
+        assert!(rejections[1].ends_with(": shapes"));
+        assert!(rejections[2].ends_with(": shapes, meshes"));
+
+        let capabilities = Capabilities {
+            features: FEATURE_SHAPES | FEATURE_MESHES | FEATURE_MESH_CACHE,
+            ..Capabilities::default()
+        };
+        let mut connection =
+            Connection::connect(addr, &capabilities, FrameLimits::default(), None).unwrap();
+        let (rays, sent) = connection.reserve_rays().unwrap();
+        assert_eq!(
+            (sent.spheres.len(), sent.shapes.len(), sent.meshes.len()),
+            (1, 1, 1)
+        );
+        connection.submit_results(white(&rays)).unwrap();
+        while let Ok((rays, scene)) = connection.reserve_rays() {
+            // The mesh was only sent with the first batch
+            assert!(Arc::ptr_eq(&scene.meshes[0].mesh, &sent.meshes[0].mesh));
+            connection.submit_results(white(&rays)).unwrap();
+        }
+        server.join().unwrap().unwrap();
+        assert_white_frame(&output);
+        std::fs::remove_file(&scene).unwrap();
+        std::fs::remove_file(&mesh).unwrap();
+    }
+
+    #[test]
+    fn legacy_workers() {
+        // Workers which predate the handshake just send the version they speak
+        for version in [1, 2] {
+            let (addr, output, server) = start_server(&format!("legacy-{}", version));
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            stream.write_u32::<BE>(version).unwrap();
+            let framing = Session::legacy(version).framing();
+            let limits = FrameLimits::default();
+            loop {
+                write_message(&mut stream, &Request::ReserveRays, framing).unwrap();
+                let rays = match read_message(&mut stream, framing, &limits) {
+                    Ok(Response::ReserveRays(rays, _)) => rays,
+                    _ => break,
+                };
+                let request = Request::SubmitResults(white(&rays));
+                write_message(&mut stream, &request, framing).unwrap();
+                let response = read_message(&mut stream, framing, &limits).unwrap();
+                assert!(matches!(response, Response::SubmitResults));
+            }
+            server.join().unwrap().unwrap();
+            assert_white_frame(&output);
+        }
+    }
+
+    /// Start a server which completes the handshake, then never answers
+    fn start_stalled_server() -> (SocketAddr, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), HANDSHAKE);
+            let limits = FrameLimits::default();
+            let hello: Hello = read_message(&mut stream, Framing::Plain, &limits).unwrap();
+            let session = hello.negotiate(&Capabilities::default()).unwrap();
+            let response = HelloResponse::Accepted(session);
+            write_message(&mut stream, &response, Framing::Plain).unwrap();
+            // Ignore everything until the worker gives up
+            std::io::copy(&mut stream, &mut std::io::sink()).unwrap();
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn stalled_server_times_out() {
+        let (addr, server) = start_stalled_server();
+
+        let timeout = Some(Duration::from_millis(50));
+        let mut connection = Connection::connect(
+            addr,
+            &Capabilities::default(),
+            FrameLimits::default(),
+            timeout,
+        )
+        .unwrap();
+        let result = connection.reserve_rays();
+        assert!(matches!(result, Err(ProtocolError::TimedOut)));
+        assert!(result.unwrap_err().is_disconnect());
+        // The response might still arrive part way through being read, so the
+        // connection can't be used again
+        let result = connection.reserve_rays();
+        assert!(matches!(result, Err(ProtocolError::Interrupted)));
+
+        drop(connection);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_legacy_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // A server which predates the handshake, and only speaks version 2
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), HANDSHAKE);
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), 2);
+            let request =
+                read_message(&mut stream, Framing::Snappy, &FrameLimits::default()).unwrap();
+            assert!(matches!(request, Request::SetName(name) if name == "old"));
+            write_message(&mut stream, &Response::SetName, Framing::Snappy).unwrap();
+        });
+
+        let mut connection =
+            Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None)
+                .unwrap();
+        connection.set_name("old".into()).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_version_1_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // A server which predates the handshake, and only speaks version 1
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), HANDSHAKE);
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), 2);
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(stream.read_u32::<BE>().unwrap(), 1);
+            let request =
+                read_message(&mut stream, Framing::Plain, &FrameLimits::default()).unwrap();
+            assert!(matches!(request, Request::SetName(name) if name == "older"));
+            write_message(&mut stream, &Response::SetName, Framing::Plain).unwrap();
+        });
+
+        let mut connection =
+            Connection::connect(addr, &Capabilities::default(), FrameLimits::default(), None)
+                .unwrap();
+        assert_eq!(connection.session(), &Session::legacy(1));
+        connection.set_name("older".into()).unwrap();
+        server.join().unwrap();
+    }
+}
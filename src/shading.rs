@@ -0,0 +1,594 @@
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use crate::bvh::Bvh;
+use crate::geom::{Intersection, Ray};
+use crate::light::{Illumination, Light};
+use crate::material::Material;
+use crate::protocol::{Outcome, Scene};
+use crate::vec::Vec3;
+
+/// Options controlling how a scene is coloured
+#[derive(Debug, Clone, StructOpt, Serialize, Deserialize)]
+pub struct ShadingOpt {
+    #[structopt(long, default_value = "1,1,1")]
+    pub fg: Vec<Vec3>,
+    #[structopt(long, default_value = "0,0,0")]
+    pub bg: Vec3,
+    /// Lights to use for scenes which don't specify their own. May be given
+    /// several times, as directional:<direction>, point:<position> or
+    /// spot:<position>:<direction>:<angle>, each optionally followed by
+    /// :<color> and :<intensity>.
+    #[structopt(long = "light", default_value = "directional:-0.4444,0.8889,0.1111")]
+    pub lights: Vec<Light>,
+    /// Light which reaches every surface, even when no light shines on it directly
+    #[structopt(long, default_value = "0.1,0.1,0.1")]
+    pub ambient: Vec3,
+    /// Maximum number of times to follow a ray as it is reflected or refracted.
+    /// Light passing through a transparent sphere uses two: one going in, and
+    /// one coming out.
+    #[structopt(long, default_value = "1")]
+    pub bounces: usize,
+    /// Number of shadow rays traced towards lights with a radius, to produce soft shadows
+    #[structopt(long, default_value = "16")]
+    pub shadow_samples: usize,
+}
+
+/// Number of reflected rays averaged together for rough materials
+const ROUGHNESS_SAMPLES: usize = 8;
+
+/// How far away from a surface secondary rays start, so that they don't hit
+/// the surface they start on due to rounding errors.
+const SURFACE_EPSILON: f32 = 1e-4;
+
+/// Add up the light arriving at the surface from every light source, using
+/// Lambert's cosine law: light hitting the surface at an angle is spread out
+/// over a larger area.
+fn lighting(intersection: &Intersection, scene: &Scene, bvh: &Bvh, opt: &ShadingOpt) -> Vec3 {
+    let lights = if scene.lights.is_empty() {
+        &opt.lights
+    } else {
+        &scene.lights
+    };
+    let direct: Vec3 = lights
+        .iter()
+        .filter_map(|light| {
+            let illumination = light.illuminate(intersection.position)?;
+            let cos_angle = illumination.direction.dot(&intersection.normal);
+            if cos_angle <= 0.0 {
+                // The light is behind the surface
+                return None;
+            }
+            let visibility = visibility(intersection, light, &illumination, scene, bvh, opt);
+            Some((cos_angle * visibility) * illumination.radiance)
+        })
+        .sum();
+    opt.ambient + direct
+}
+
+/// Work out how much of a light can be seen from a point on a surface, from 0
+/// (completely in shadow) to 1 (nothing in the way).
+fn visibility(
+    intersection: &Intersection,
+    light: &Light,
+    illumination: &Illumination,
+    scene: &Scene,
+    bvh: &Bvh,
+    opt: &ShadingOpt,
+) -> f32 {
+    // Start shadow rays slightly above the surface so they don't hit it
+    let origin = intersection.position + SURFACE_EPSILON * intersection.normal;
+    let unobstructed = |direction: Vec3, distance: f32| {
+        let shadow_ray = Ray { origin, direction };
+        !bvh.hits_any(&shadow_ray, &scene.spheres, distance)
+            && !shadow_ray.hits_any_shape(&scene.shapes, distance)
+            && !scene
+                .meshes
+                .iter()
+                .any(|mesh| mesh.hits_any(&shadow_ray, distance))
+    };
+
+    match light.position() {
+        // Lights with a size cast soft shadows: a point may be able to see part of the light
+        Some(position) if light.radius > 0.0 && opt.shadow_samples > 1 => {
+            let visible = disc_samples(illumination.direction, light.radius, opt.shadow_samples)
+                .filter(|&offset| {
+                    let offset = position + offset - origin;
+                    let distance = offset.length();
+                    unobstructed(offset / distance, distance)
+                })
+                .count();
+            visible as f32 / opt.shadow_samples as f32
+        }
+        _ => {
+            if unobstructed(illumination.direction, illumination.distance) {
+                1.0
+            } else {
+                0.0
+            }
+        }
+    }
+}
+
+/// Find the closest object hit by the ray. Objects are numbered with the
+/// spheres first, followed by the other shapes, and then the meshes.
+fn closest_hit(ray: &Ray, scene: &Scene, bvh: &Bvh) -> Option<(usize, Intersection)> {
+    let sphere = bvh.closest_hit(ray, &scene.spheres);
+    let shape = ray
+        .closest_shape_hit(&scene.shapes)
+        .map(|(i, intersection)| (scene.spheres.len() + i, intersection));
+    let first_mesh = scene.spheres.len() + scene.shapes.len();
+    let meshes = scene
+        .meshes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, mesh)| Some((first_mesh + i, mesh.intersect(ray)?)));
+    sphere
+        .into_iter()
+        .chain(shape)
+        .chain(meshes)
+        .min_by(|a, b| a.1.distance.total_cmp(&b.1.distance))
+}
+
+/// The material of an object, numbered as by `closest_hit`
+fn material(scene: &Scene, index: usize) -> &Material {
+    if let Some(sphere) = scene.spheres.get(index) {
+        return &sphere.material;
+    }
+    let index = index - scene.spheres.len();
+    match scene.shapes.get(index) {
+        Some(shape) => &shape.material,
+        None => &scene.meshes[index - scene.shapes.len()].material,
+    }
+}
+
+/// Pick a colour for an object without its own albedo, by spreading the
+/// objects in the scene evenly along the palette.
+fn palette_color(palette: &[Vec3], index: usize, count: usize) -> Vec3 {
+    // Compute a value from 0..[number of foreground colours - 1] that
+    // we can use as a position along the gradient.
+    let gradient = (index * (palette.len() - 1)) as f32 / count as f32;
+
+    // Since our position is not a whole number, find the foreground colour to
+    // the left of our position.
+    let fg1 = palette[gradient.floor() as usize];
+    // And the foreground colour to our right.
+    let fg2 = palette[gradient.ceil() as usize];
+
+    // And blend them, depending on where we are between those two colours.
+    fg1.lerp(&fg2, gradient.fract())
+}
+
+/// Schlick's approximation of the Fresnel equations: the fraction of light
+/// reflected at a surface between refractive indices `n1` and `n2`.
+fn schlick(cos_theta: f32, n1: f32, n2: f32) -> f32 {
+    let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+/// Spread `count` points evenly over a disc of the given radius, centered on the
+/// origin and perpendicular to `axis`.
+fn disc_samples(axis: Vec3, radius: f32, count: usize) -> impl Iterator<Item = Vec3> {
+    // Find two unit vectors perpendicular to the axis and to each other
+    let helper = if axis.x.abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let u = axis.cross(&helper).normalize();
+    let v = axis.cross(&u).normalize();
+
+    // Place the samples on a "sunflower" spiral, which covers a disc evenly
+    let golden_angle = std::f32::consts::PI * (3.0 - 5.0f32.sqrt());
+    (0..count).map(move |i| {
+        let r = radius * ((i as f32 + 0.5) / count as f32).sqrt();
+        let angle = i as f32 * golden_angle;
+        (r * angle.cos()) * u + (r * angle.sin()) * v
+    })
+}
+
+/// Spread a fixed number of directions around `direction`, in a cone whose
+/// width grows with `roughness`. The same directions are used every time so
+/// that renders are repeatable.
+fn glossy_directions(direction: Vec3, normal: Vec3, roughness: f32) -> impl Iterator<Item = Vec3> {
+    disc_samples(direction, roughness, ROUGHNESS_SAMPLES).map(move |offset| {
+        let perturbed = (direction + offset).normalize();
+        // Don't let the reflection point into the surface
+        if perturbed.dot(&normal) < 0.0 {
+            perturbed.reflection(&normal)
+        } else {
+            perturbed
+        }
+    })
+}
+
+/// Trace a secondary ray starting at the surface of an object, using up one bounce
+fn trace_from(
+    position: Vec3,
+    direction: Vec3,
+    scene: &Scene,
+    bvh: &Bvh,
+    opt: &ShadingOpt,
+    bounces: usize,
+) -> Vec3 {
+    // Advance the ray a small amount to avoid hitting the same sphere
+    compute_result(
+        Ray {
+            origin: position + SURFACE_EPSILON * direction,
+            direction,
+        },
+        scene,
+        bvh,
+        opt,
+        bounces - 1,
+    )
+    .color
+    .expect("Color to be returned")
+}
+
+pub fn compute_result(
+    ray: Ray,
+    scene: &Scene,
+    bvh: &Bvh,
+    opt: &ShadingOpt,
+    bounces: usize,
+) -> Outcome {
+    // Find the closest intersection (if any)
+    let maybe_intersection = closest_hit(&ray, scene, bvh);
+
+    if let Some((index, mut intersection)) = maybe_intersection {
+        // Rays which start inside an object hit the inside of its surface, so make the
+        // normal face the ray. Flat shapes may also be hit from behind.
+        let inside = intersection.normal.dot(&ray.direction) > 0.0;
+        if inside {
+            intersection.normal = -intersection.normal;
+        }
+
+        let material = material(scene, index);
+        let object_count = scene.spheres.len() + scene.shapes.len() + scene.meshes.len();
+        let albedo = material
+            .albedo
+            .unwrap_or_else(|| palette_color(&opt.fg, index, object_count));
+        let diffuse_color = albedo * lighting(&intersection, scene, bvh, opt);
+
+        let combined_color = if bounces > 0 && material.reflectivity > 0.0 {
+            // Materials tend to be more reflective as the angle of incidence increases
+            let reflectivity =
+                (1.0 - intersection.normal.dot(&ray.direction).powi(2)) * material.reflectivity;
+            let reflected_direction = ray.direction.reflection(&intersection.normal);
+            let reflected_color = if material.roughness > 0.0 {
+                // Rough surfaces scatter the reflection over a range of directions, so
+                // average the colour seen in a few of them.
+                let total: Vec3 =
+                    glossy_directions(reflected_direction, intersection.normal, material.roughness)
+                        .map(|direction| {
+                            trace_from(intersection.position, direction, scene, bvh, opt, bounces)
+                        })
+                        .sum();
+                total / ROUGHNESS_SAMPLES as f32
+            } else {
+                trace_from(
+                    intersection.position,
+                    reflected_direction,
+                    scene,
+                    bvh,
+                    opt,
+                    bounces,
+                )
+            };
+
+            (1.0 - reflectivity) * diffuse_color + reflectivity * reflected_color
+        } else {
+            diffuse_color
+        };
+
+        let color = if bounces > 0 && material.transparency > 0.0 {
+            // Light bends as it crosses between materials with different refractive indices
+            let (n1, n2) = if inside {
+                (material.ior, 1.0)
+            } else {
+                (1.0, material.ior)
+            };
+            let reflected_direction = ray.direction.reflection(&intersection.normal);
+            let transmitted_color = match ray.direction.refract(&intersection.normal, n1 / n2) {
+                Some(refracted_direction) => {
+                    // Some of the light is reflected rather than refracted, more so at
+                    // grazing angles. The angle on the optically thinner side decides how much.
+                    let cos_theta = if n1 > n2 {
+                        -intersection.normal.dot(&refracted_direction)
+                    } else {
+                        -intersection.normal.dot(&ray.direction)
+                    };
+                    let reflectance = schlick(cos_theta, n1, n2);
+                    let reflected_color = trace_from(
+                        intersection.position,
+                        reflected_direction,
+                        scene,
+                        bvh,
+                        opt,
+                        bounces,
+                    );
+                    let refracted_color = trace_from(
+                        intersection.position,
+                        refracted_direction,
+                        scene,
+                        bvh,
+                        opt,
+                        bounces,
+                    );
+                    reflectance * reflected_color + (1.0 - reflectance) * refracted_color
+                }
+                // The light can't escape, so it's all reflected back inside
+                None => trace_from(
+                    intersection.position,
+                    reflected_direction,
+                    scene,
+                    bvh,
+                    opt,
+                    bounces,
+                ),
+            };
+
+            (1.0 - material.transparency) * combined_color
+                + material.transparency * transmitted_color
+        } else {
+            combined_color
+        };
+
+        Outcome {
+            hit: true,
+            color: Some(color + material.emissive),
+        }
+    } else {
+        Outcome {
+            hit: false,
+            color: Some(opt.bg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{Shape, ShapeKind, Sphere, Transform};
+
+    fn opt() -> ShadingOpt {
+        ShadingOpt {
+            fg: vec![Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0)],
+            bg: Vec3::new(0.0, 0.0, 0.0),
+            lights: vec!["directional:0,0,1".parse().unwrap()],
+            ambient: Vec3::new(0.0, 0.0, 0.0),
+            shadow_samples: 16,
+            bounces: 1,
+        }
+    }
+
+    fn scene(material: Material) -> Scene {
+        Scene {
+            frame: 0,
+            spheres: vec![Sphere {
+                center: Vec3::new(0.0, 0.0, 5.0),
+                radius: 1.0,
+                material,
+                transform: Transform::default(),
+            }],
+            shapes: Vec::new(),
+            meshes: Vec::new(),
+            lights: Vec::new(),
+        }
+    }
+
+    /// Trace a ray through a scene, building a BVH just for it
+    fn trace(ray: Ray, scene: &Scene, opt: &ShadingOpt, bounces: usize) -> Outcome {
+        compute_result(ray, scene, &Bvh::new(&scene.spheres), opt, bounces)
+    }
+
+    const RAY: Ray = Ray {
+        origin: Vec3::new(0.0, 0.0, 0.0),
+        direction: Vec3::new(0.0, 0.0, 1.0),
+    };
+
+    #[test]
+    fn miss_is_background() {
+        let outcome = trace(RAY, &Scene::default(), &opt(), 1);
+        assert!(!outcome.hit);
+        assert_eq!(outcome.color, Some(opt().bg));
+    }
+
+    #[test]
+    fn albedo_overrides_palette() {
+        let green = Vec3::new(0.0, 1.0, 0.0);
+        let material = Material {
+            albedo: Some(green),
+            reflectivity: 0.0,
+            ..Material::default()
+        };
+        let color = trace(RAY, &scene(material), &opt(), 1).color.unwrap();
+        // The light shines straight at the point we hit
+        assert_eq!(color, green);
+    }
+
+    #[test]
+    fn lights_add_up() {
+        let material = Material {
+            albedo: Some(Vec3::new(1.0, 1.0, 1.0)),
+            reflectivity: 0.0,
+            ..Material::default()
+        };
+        let mut scene = scene(material);
+        scene.lights = vec![
+            // Hits the surface at 60 degrees, so contributes half its intensity
+            "directional:0,-0.8660254,0.5:1,0,0".parse().unwrap(),
+            // Behind the sphere, so contributes nothing
+            "directional:0,0,-1:0,1,0".parse().unwrap(),
+            "point:0,0,2:0,0,1:4".parse().unwrap(),
+        ];
+        let mut opt = opt();
+        opt.ambient = Vec3::new(0.25, 0.25, 0.25);
+        let color = trace(RAY, &scene, &opt, 1).color.unwrap();
+        assert!((color.x - 0.75).abs() < 1e-6);
+        assert_eq!(color.y, 0.25);
+        assert_eq!(color.z, 0.25 + 1.0);
+    }
+
+    /// A white sphere lit from above, and another sphere which may block the light
+    fn shadow_scene(light: &str, blocked: bool) -> (Scene, ShadingOpt) {
+        let mut scene = scene(Material {
+            albedo: Some(Vec3::new(1.0, 1.0, 1.0)),
+            reflectivity: 0.0,
+            ..Material::default()
+        });
+        scene.lights = vec![light.parse().unwrap()];
+        if blocked {
+            scene.spheres.push(Sphere {
+                center: Vec3::new(0.0, 2.0, 2.0),
+                radius: 0.5,
+                material: Material::default(),
+                transform: Transform::default(),
+            });
+        }
+        let mut opt = opt();
+        opt.ambient = Vec3::new(0.25, 0.25, 0.25);
+        (scene, opt)
+    }
+
+    #[test]
+    fn hard_shadow() {
+        let (scene, opt) = shadow_scene("point:0,4,0:1,1,1:32", false);
+        let lit = trace(RAY, &scene, &opt, 1).color.unwrap();
+        assert!(lit.x > 0.25);
+
+        let (scene, opt) = shadow_scene("point:0,4,0:1,1,1:32", true);
+        let shadowed = trace(RAY, &scene, &opt, 1).color.unwrap();
+        assert_eq!(shadowed, Vec3::new(0.25, 0.25, 0.25));
+    }
+
+    #[test]
+    fn soft_shadow() {
+        let (scene, opt) = shadow_scene("point:0,4,0:1,1,1:32:2", false);
+        let lit = trace(RAY, &scene, &opt, 1).color.unwrap();
+
+        // A large light is only partly hidden by the sphere in the way
+        let (scene, opt) = shadow_scene("point:0,4,0:1,1,1:32:2", true);
+        let penumbra = trace(RAY, &scene, &opt, 1).color.unwrap();
+        assert!(penumbra.x > 0.25 && penumbra.x < lit.x);
+    }
+
+    #[test]
+    fn shapes_cast_shadows() {
+        let (mut scene, opt) = shadow_scene("point:0,4,0:1,1,1:32", false);
+        scene.shapes.push(Shape {
+            kind: ShapeKind::Disc {
+                center: Vec3::new(0.0, 2.0, 2.0),
+                normal: Vec3::new(0.0, 1.0, -1.0),
+                radius: 0.5,
+            },
+            material: Material::default(),
+            transform: Transform::default(),
+        });
+        let shadowed = trace(RAY, &scene, &opt, 1).color.unwrap();
+        assert_eq!(shadowed, Vec3::new(0.25, 0.25, 0.25));
+    }
+
+    #[test]
+    fn nearest_object_is_shaded() {
+        let matte = Material {
+            reflectivity: 0.0,
+            ..Material::default()
+        };
+        let wall = |z| Shape {
+            kind: ShapeKind::Plane {
+                point: Vec3::new(0.0, 0.0, z),
+                normal: Vec3::new(0.0, 0.0, -1.0),
+            },
+            material: matte,
+            transform: Transform::default(),
+        };
+
+        // Shapes are coloured from the palette after the spheres
+        let mut scene = scene(matte);
+        scene.shapes.push(wall(3.0));
+        let color = trace(RAY, &scene, &opt(), 1).color.unwrap();
+        assert_eq!(color, Vec3::new(0.5, 0.0, 0.5));
+
+        scene.shapes[0] = wall(10.0);
+        let color = trace(RAY, &scene, &opt(), 1).color.unwrap();
+        assert_eq!(color, Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn schlick_reflectance() {
+        assert_eq!(schlick(1.0, 1.0, 1.0), 0.0);
+        assert!((schlick(1.0, 1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert_eq!(schlick(0.0, 1.0, 1.5), 1.0);
+    }
+
+    #[test]
+    fn glass_sphere_inverts_image() {
+        // Looking through a glass ball, things above appear below and vice versa
+        let glass = Material {
+            albedo: Some(Vec3::new(0.0, 0.0, 0.0)),
+            reflectivity: 0.0,
+            transparency: 1.0,
+            ior: 1.5,
+            ..Material::default()
+        };
+        let mut scene = scene(glass);
+        scene.spheres.push(Sphere {
+            center: Vec3::new(0.0, 100.0, 120.0),
+            radius: 100.0,
+            material: Material {
+                albedo: Some(Vec3::new(1.0, 1.0, 1.0)),
+                reflectivity: 0.0,
+                emissive: Vec3::new(1.0, 1.0, 1.0),
+                ..Material::default()
+            },
+            transform: Transform::default(),
+        });
+        let mut opt = opt();
+        opt.lights.clear();
+
+        // The ray above the middle is bent downwards and misses the backdrop
+        let up = Ray {
+            origin: Vec3::new(0.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 0.1, 1.0),
+        };
+        let up = Ray {
+            direction: up.direction.normalize(),
+            ..up
+        };
+        let color = trace(up, &scene, &opt, 3).color.unwrap();
+        assert!(color.x < 0.1);
+
+        // Without the glass, it would hit the backdrop
+        scene.spheres.remove(0);
+        let color = trace(up, &scene, &opt, 3).color.unwrap();
+        assert!(color.x > 0.9);
+    }
+
+    #[test]
+    fn emissive_is_added() {
+        let material = Material {
+            emissive: Vec3::new(0.5, 0.5, 0.5),
+            ..Material::default()
+        };
+        let plain = trace(RAY, &scene(Material::default()), &opt(), 1);
+        let glowing = trace(RAY, &scene(material), &opt(), 1);
+        assert_eq!(
+            glowing.color.unwrap(),
+            plain.color.unwrap() + Vec3::new(0.5, 0.5, 0.5)
+        );
+    }
+
+    #[test]
+    fn fully_transparent_shows_background() {
+        let material = Material {
+            transparency: 1.0,
+            ..Material::default()
+        };
+        // One bounce to enter the sphere, and another to leave
+        let outcome = trace(RAY, &scene(material), &opt(), 2);
+        assert!(outcome.hit);
+        assert_eq!(outcome.color, Some(opt().bg));
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    fmt,
+    iter::Sum,
     num::ParseFloatError,
-    ops::{Add, Mul, Sub},
+    ops::{Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, MulAssign, Neg, Sub, SubAssign},
     str::FromStr,
 };
 
@@ -40,6 +42,86 @@ impl Vec3 {
     pub fn reflection(&self, normal: &Self) -> Vec3 {
         *self - (normal.dot(self) * 2.0) * *normal
     }
+
+    /// Calculate the cross product of two vectors
+    /// The result is perpendicular to both inputs, with a length equal to
+    /// the area of the parallelogram they span.
+    pub fn cross(&self, rhs: &Self) -> Vec3 {
+        Vec3 {
+            x: self.y * rhs.z - self.z * rhs.y,
+            y: self.z * rhs.x - self.x * rhs.z,
+            z: self.x * rhs.y - self.y * rhs.x,
+        }
+    }
+
+    /// Scale the vector to length 1. Zero vectors have no direction, so come
+    /// out as NaNs; use `try_normalize` if the vector may be zero.
+    pub fn normalize(&self) -> Vec3 {
+        (1.0 / self.length()) * *self
+    }
+
+    /// Scale the vector to length 1, or return `None` if it's too short or too
+    /// long for that to give a finite result
+    pub fn try_normalize(&self) -> Option<Vec3> {
+        let length = self.length();
+        length.is_normal().then(|| (1.0 / length) * *self)
+    }
+
+    /// Interpolate linearly between two vectors, giving `self` when `t` is 0
+    /// and `rhs` when `t` is 1
+    pub fn lerp(&self, rhs: &Self, t: f32) -> Vec3 {
+        (1.0 - t) * *self + t * *rhs
+    }
+
+    /// The smaller of each pair of components
+    pub fn min(&self, rhs: &Self) -> Vec3 {
+        Vec3::new(self.x.min(rhs.x), self.y.min(rhs.y), self.z.min(rhs.z))
+    }
+
+    /// The larger of each pair of components
+    pub fn max(&self, rhs: &Self) -> Vec3 {
+        Vec3::new(self.x.max(rhs.x), self.y.max(rhs.y), self.z.max(rhs.z))
+    }
+
+    /// The absolute value of each component
+    pub fn abs(&self) -> Vec3 {
+        Vec3::new(self.x.abs(), self.y.abs(), self.z.abs())
+    }
+
+    /// Calculate the distance between two points
+    pub fn distance(&self, rhs: &Self) -> f32 {
+        (*self - *rhs).length()
+    }
+
+    /// Bend this direction as it crosses a surface, using Snell's law. The
+    /// normal must face against the direction, and `eta` is the ratio of the
+    /// refractive index being left to the one being entered. Returns `None`
+    /// when the direction is reflected back instead (total internal reflection).
+    pub fn refract(&self, normal: &Self, eta: f32) -> Option<Vec3> {
+        let cos_incident = -normal.dot(self);
+        let sin2_refracted = eta.powi(2) * (1.0 - cos_incident.powi(2));
+        if sin2_refracted > 1.0 {
+            return None;
+        }
+        let cos_refracted = (1.0 - sin2_refracted).sqrt();
+        Some(eta * *self + (eta * cos_incident - cos_refracted) * *normal)
+    }
+
+    /// Whether every component is within `epsilon` of the other vector's
+    pub fn approx_eq(&self, rhs: &Self, epsilon: f32) -> bool {
+        let d = (*self - *rhs).abs();
+        d.x <= epsilon && d.y <= epsilon && d.z <= epsilon
+    }
+
+    /// Add a fourth component: 1 for a point, or 0 for a direction
+    pub fn extend(&self, w: f32) -> Vec4 {
+        Vec4::new(self.x, self.y, self.z, w)
+    }
+
+    /// Drop the z component
+    pub fn truncate(&self) -> Vec2 {
+        Vec2::new(self.x, self.y)
+    }
 }
 
 impl Add for Vec3 {
@@ -82,6 +164,119 @@ impl Mul<Vec3> for f32 {
     }
 }
 
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        rhs * self
+    }
+}
+
+impl Mul for Vec3 {
+    /// Vectors are multiplied component-wise, which is how colours are
+    /// combined, for example to tint light by the colour of a surface
+    type Output = Vec3;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.x * rhs.x,
+            y: self.y * rhs.y,
+            z: self.z * rhs.z,
+        }
+    }
+}
+
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, rhs: f32) -> Self::Output {
+        Self {
+            x: self.x / rhs,
+            y: self.y / rhs,
+            z: self.z / rhs,
+        }
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+
+    fn neg(self) -> Self::Output {
+        Self {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+impl AddAssign for Vec3 {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Vec3 {
+    fn sub_assign(&mut self, rhs: Self) {
+        *self = *self - rhs;
+    }
+}
+
+impl MulAssign<f32> for Vec3 {
+    fn mul_assign(&mut self, rhs: f32) {
+        *self = rhs * *self;
+    }
+}
+
+impl MulAssign for Vec3 {
+    fn mul_assign(&mut self, rhs: Self) {
+        *self = *self * rhs;
+    }
+}
+
+impl DivAssign<f32> for Vec3 {
+    fn div_assign(&mut self, rhs: f32) {
+        *self = *self / rhs;
+    }
+}
+
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    /// The x, y and z components are numbered 0, 1 and 2
+    fn index(&self, index: usize) -> &f32 {
+        match index {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 has no component {}", index),
+        }
+    }
+}
+
+impl IndexMut<usize> for Vec3 {
+    fn index_mut(&mut self, index: usize) -> &mut f32 {
+        match index {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Vec3 has no component {}", index),
+        }
+    }
+}
+
+impl Sum for Vec3 {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Vec3::new(0.0, 0.0, 0.0), |a, b| a + b)
+    }
+}
+
+impl<'a> Sum<&'a Vec3> for Vec3 {
+    fn sum<I: Iterator<Item = &'a Self>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
 impl FromStr for Vec3 {
     type Err = ParseVecError;
 
@@ -99,6 +294,13 @@ impl FromStr for Vec3 {
     }
 }
 
+impl fmt::Display for Vec3 {
+    /// Writes the components separated by commas, as accepted by `FromStr`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{}", self.x, self.y, self.z)
+    }
+}
+
 #[derive(Debug, Error, PartialEq)]
 pub enum ParseVecError {
     #[error("Wrong number of components")]
@@ -107,8 +309,539 @@ pub enum ParseVecError {
     InvalidComponent(#[from] ParseFloatError),
 }
 
+/// A structure to represent 2D vectors, such as positions within a texture
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Vec2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vec2 {
+    pub const fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn dot(&self, rhs: &Self) -> f32 {
+        self.x * rhs.x + self.y * rhs.y
+    }
+
+    /// Add a z component
+    pub fn extend(&self, z: f32) -> Vec3 {
+        Vec3::new(self.x, self.y, z)
+    }
+}
+
+impl Add for Vec2 {
+    type Output = Vec2;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Vec2;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl Mul<Vec2> for f32 {
+    type Output = Vec2;
+
+    fn mul(self, rhs: Vec2) -> Self::Output {
+        Vec2::new(self * rhs.x, self * rhs.y)
+    }
+}
+
+/// A structure to represent 4D vectors, usually points or directions in
+/// homogeneous coordinates, as transformed by a `Mat4`
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Vec4 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Vec4 {
+    pub const fn new(x: f32, y: f32, z: f32, w: f32) -> Self {
+        Self { x, y, z, w }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn dot(&self, rhs: &Self) -> f32 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+    }
+
+    pub fn normalize(&self) -> Vec4 {
+        (1.0 / self.length()) * *self
+    }
+
+    /// Drop the w component
+    pub fn truncate(&self) -> Vec3 {
+        Vec3::new(self.x, self.y, self.z)
+    }
+
+    /// The point this represents in 3D, found by dividing by w. Directions,
+    /// whose w is 0, don't have one.
+    pub fn to_point(&self) -> Option<Vec3> {
+        (self.w != 0.0).then(|| (1.0 / self.w) * self.truncate())
+    }
+}
+
+impl Add for Vec4 {
+    type Output = Vec4;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self::new(
+            self.x + rhs.x,
+            self.y + rhs.y,
+            self.z + rhs.z,
+            self.w + rhs.w,
+        )
+    }
+}
+
+impl Sub for Vec4 {
+    type Output = Vec4;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self::new(
+            self.x - rhs.x,
+            self.y - rhs.y,
+            self.z - rhs.z,
+            self.w - rhs.w,
+        )
+    }
+}
+
+impl Mul<Vec4> for f32 {
+    type Output = Vec4;
+
+    fn mul(self, rhs: Vec4) -> Self::Output {
+        Vec4::new(self * rhs.x, self * rhs.y, self * rhs.z, self * rhs.w)
+    }
+}
+
+/// A 3x3 matrix, for transforming directions such as normals, which aren't
+/// affected by translations
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Mat3 {
+    /// The elements, a row at a time
+    pub rows: [[f32; 3]; 3],
+}
+
+impl Mat3 {
+    pub const IDENTITY: Self = Self {
+        rows: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    /// The matrix taking the x, y and z axes to the given vectors
+    pub fn from_columns(x: Vec3, y: Vec3, z: Vec3) -> Self {
+        Self {
+            rows: [[x.x, y.x, z.x], [x.y, y.y, z.y], [x.z, y.z, z.z]],
+        }
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut rows = [[0.0; 3]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, element) in row.iter_mut().enumerate() {
+                *element = self.rows[j][i];
+            }
+        }
+        Self { rows }
+    }
+
+    /// How much the matrix scales volumes by. It's negative if the matrix
+    /// mirrors space, and zero if it flattens it.
+    pub fn determinant(&self) -> f32 {
+        let [a, b, c] = self.rows.map(|[x, y, z]| Vec3::new(x, y, z));
+        a.dot(&b.cross(&c))
+    }
+
+    /// The matrix which undoes this one, or `None` if this one flattens space
+    /// so that it can't be undone
+    pub fn inverse(&self) -> Option<Self> {
+        let determinant = self.determinant();
+        if determinant == 0.0 {
+            return None;
+        }
+        // Each column of the inverse is perpendicular to two of the rows, so
+        // is their cross product, scaled by the determinant
+        let [a, b, c] = self.rows.map(|[x, y, z]| Vec3::new(x, y, z));
+        let scale = 1.0 / determinant;
+        Some(Self::from_columns(
+            scale * b.cross(&c),
+            scale * c.cross(&a),
+            scale * a.cross(&b),
+        ))
+    }
+}
+
+impl Mul for Mat3 {
+    /// The result transforms by `rhs`, followed by `self`
+    type Output = Mat3;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut rows = [[0.0; 3]; 3];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, element) in row.iter_mut().enumerate() {
+                *element = (0..3).map(|k| self.rows[i][k] * rhs.rows[k][j]).sum();
+            }
+        }
+        Self { rows }
+    }
+}
+
+impl Mul<Vec3> for Mat3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: Vec3) -> Self::Output {
+        let [x, y, z] = self
+            .rows
+            .map(|row| row[0] * rhs.x + row[1] * rhs.y + row[2] * rhs.z);
+        Vec3::new(x, y, z)
+    }
+}
+
+impl From<Quat> for Mat3 {
+    fn from(q: Quat) -> Self {
+        let Quat { x, y, z, w } = q.normalize();
+        Self {
+            rows: [
+                [
+                    1.0 - 2.0 * (y * y + z * z),
+                    2.0 * (x * y - w * z),
+                    2.0 * (x * z + w * y),
+                ],
+                [
+                    2.0 * (x * y + w * z),
+                    1.0 - 2.0 * (x * x + z * z),
+                    2.0 * (y * z - w * x),
+                ],
+                [
+                    2.0 * (x * z - w * y),
+                    2.0 * (y * z + w * x),
+                    1.0 - 2.0 * (x * x + y * y),
+                ],
+            ],
+        }
+    }
+}
+
+/// A 4x4 matrix, for transforming points and directions from one coordinate
+/// system to another. Points are treated as having a fourth component of 1, so
+/// they're moved by translations, while directions have a fourth component of
+/// 0, so they aren't.
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Mat4 {
+    /// The elements, a row at a time
+    pub rows: [[f32; 4]; 4],
+}
+
+impl Mat4 {
+    pub const IDENTITY: Self = Self {
+        rows: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ],
+    };
+
+    /// Move points by `offset`
+    pub fn translation(offset: Vec3) -> Self {
+        let mut matrix = Self::IDENTITY;
+        matrix.rows[0][3] = offset.x;
+        matrix.rows[1][3] = offset.y;
+        matrix.rows[2][3] = offset.z;
+        matrix
+    }
+
+    /// Stretch along each axis by the matching component of `factors`
+    pub fn scale(factors: Vec3) -> Self {
+        let mut matrix = Self::IDENTITY;
+        matrix.rows[0][0] = factors.x;
+        matrix.rows[1][1] = factors.y;
+        matrix.rows[2][2] = factors.z;
+        matrix
+    }
+
+    /// Rotate by `angle` radians about the x axis, turning the y axis towards the z axis
+    pub fn rotation_x(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let mut matrix = Self::IDENTITY;
+        matrix.rows[1][1] = cos;
+        matrix.rows[1][2] = -sin;
+        matrix.rows[2][1] = sin;
+        matrix.rows[2][2] = cos;
+        matrix
+    }
+
+    /// Rotate by `angle` radians about the y axis, turning the z axis towards the x axis
+    pub fn rotation_y(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let mut matrix = Self::IDENTITY;
+        matrix.rows[0][0] = cos;
+        matrix.rows[0][2] = sin;
+        matrix.rows[2][0] = -sin;
+        matrix.rows[2][2] = cos;
+        matrix
+    }
+
+    /// Rotate by `angle` radians about the z axis, turning the x axis towards the y axis
+    pub fn rotation_z(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let mut matrix = Self::IDENTITY;
+        matrix.rows[0][0] = cos;
+        matrix.rows[0][1] = -sin;
+        matrix.rows[1][0] = sin;
+        matrix.rows[1][1] = cos;
+        matrix
+    }
+
+    /// Rotate by `angle` radians about `axis`, which doesn't need to be a unit
+    /// vector, but mustn't be zero. Seen from the tip of the axis, the rotation
+    /// is anticlockwise.
+    pub fn rotation(axis: Vec3, angle: f32) -> Self {
+        Quat::from_axis_angle(axis, angle).into()
+    }
+
+    /// The matrix taking the coordinates seen by a camera at `position`,
+    /// looking towards `target`, to the scene's. The camera looks along its z
+    /// axis, with its y axis as close to `up` as possible, and its x axis to
+    /// the right. There's no such matrix if `position` and `target` are the
+    /// same, or `up` is parallel to the viewing direction.
+    pub fn look_at(position: Vec3, target: Vec3, up: Vec3) -> Option<Self> {
+        let forward = (target - position).try_normalize()?;
+        let right = up.cross(&forward).try_normalize()?;
+        let up = forward.cross(&right);
+        Some(Self::translation(position) * Self::from(Mat3::from_columns(right, up, forward)))
+    }
+
+    /// A perspective projection, taking a camera's coordinates, as used by
+    /// `look_at`, to clip coordinates. After dividing by w, the field of view
+    /// covers -1 to 1 in x and y, while z goes from 0 at `near` to 1 at `far`.
+    /// `fov` is the vertical field of view in radians, and `aspect` the width
+    /// divided by the height.
+    pub fn perspective(fov: f32, aspect: f32, near: f32, far: f32) -> Self {
+        let focal_length = 1.0 / (fov / 2.0).tan();
+        let depth = far / (far - near);
+        Self {
+            rows: [
+                [focal_length / aspect, 0.0, 0.0, 0.0],
+                [0.0, focal_length, 0.0, 0.0],
+                [0.0, 0.0, depth, -near * depth],
+                [0.0, 0.0, 1.0, 0.0],
+            ],
+        }
+    }
+
+    /// The top-left 3x3 of the matrix, which transforms directions
+    pub fn linear(&self) -> Mat3 {
+        Mat3 {
+            rows: [0, 1, 2].map(|i| [self.rows[i][0], self.rows[i][1], self.rows[i][2]]),
+        }
+    }
+
+    pub fn transform_point(&self, point: Vec3) -> Vec3 {
+        let [x, y, z, _] = self
+            .rows
+            .map(|row| row[0] * point.x + row[1] * point.y + row[2] * point.z + row[3]);
+        Vec3::new(x, y, z)
+    }
+
+    pub fn transform_vector(&self, vector: Vec3) -> Vec3 {
+        let [x, y, z, _] = self
+            .rows
+            .map(|row| row[0] * vector.x + row[1] * vector.y + row[2] * vector.z);
+        Vec3::new(x, y, z)
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut rows = [[0.0; 4]; 4];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, element) in row.iter_mut().enumerate() {
+                *element = self.rows[j][i];
+            }
+        }
+        Self { rows }
+    }
+
+    /// The matrix which undoes this one, or `None` if this one flattens space
+    /// so that it can't be undone
+    pub fn inverse(&self) -> Option<Self> {
+        // Gauss-Jordan elimination: reduce the matrix to the identity, doing
+        // the same to an identity matrix alongside it, which then becomes the
+        // inverse. Work in f64, so that rounding errors don't build up.
+        let mut left = self.rows.map(|row| row.map(f64::from));
+        let mut right = Self::IDENTITY.rows.map(|row| row.map(f64::from));
+        for column in 0..4 {
+            // Use the row with the largest value in this column, which keeps
+            // the division below as accurate as possible
+            let pivot = (column..4)
+                .max_by(|&a, &b| left[a][column].abs().total_cmp(&left[b][column].abs()))
+                .expect("Rows to remain");
+            if left[pivot][column] == 0.0 {
+                return None;
+            }
+            left.swap(column, pivot);
+            right.swap(column, pivot);
+
+            let scale = 1.0 / left[column][column];
+            for j in 0..4 {
+                left[column][j] *= scale;
+                right[column][j] *= scale;
+            }
+            for row in 0..4 {
+                let factor = left[row][column];
+                if row != column && factor != 0.0 {
+                    for j in 0..4 {
+                        left[row][j] -= factor * left[column][j];
+                        right[row][j] -= factor * right[column][j];
+                    }
+                }
+            }
+        }
+        Some(Self {
+            rows: right.map(|row| row.map(|element| element as f32)),
+        })
+    }
+}
+
+impl Mul for Mat4 {
+    /// The result transforms by `rhs`, followed by `self`
+    type Output = Mat4;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut rows = [[0.0; 4]; 4];
+        for (i, row) in rows.iter_mut().enumerate() {
+            for (j, element) in row.iter_mut().enumerate() {
+                *element = (0..4).map(|k| self.rows[i][k] * rhs.rows[k][j]).sum();
+            }
+        }
+        Self { rows }
+    }
+}
+
+impl Mul<Vec4> for Mat4 {
+    type Output = Vec4;
+
+    fn mul(self, rhs: Vec4) -> Self::Output {
+        let [x, y, z, w] = self
+            .rows
+            .map(|row| Vec4::new(row[0], row[1], row[2], row[3]).dot(&rhs));
+        Vec4::new(x, y, z, w)
+    }
+}
+
+impl From<Mat3> for Mat4 {
+    /// A matrix which transforms like `linear`, without any translation
+    fn from(linear: Mat3) -> Self {
+        let mut matrix = Self::IDENTITY;
+        for (row, linear_row) in matrix.rows.iter_mut().zip(linear.rows) {
+            row[..3].copy_from_slice(&linear_row);
+        }
+        matrix
+    }
+}
+
+impl From<Quat> for Mat4 {
+    fn from(q: Quat) -> Self {
+        Mat3::from(q).into()
+    }
+}
+
+/// A quaternion, representing a rotation. Unlike a matrix, rotations can be
+/// combined any number of times without rounding errors skewing the result.
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Quat {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32,
+}
+
+impl Quat {
+    pub const IDENTITY: Self = Self {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+        w: 1.0,
+    };
+
+    /// Rotate by `angle` radians about `axis`, like `Mat4::rotation`
+    pub fn from_axis_angle(axis: Vec3, angle: f32) -> Self {
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        let axis = (sin / axis.length()) * axis;
+        Self {
+            x: axis.x,
+            y: axis.y,
+            z: axis.z,
+            w: cos,
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        Vec4::new(self.x, self.y, self.z, self.w).length()
+    }
+
+    /// Scale to unit length, which every rotation has
+    pub fn normalize(&self) -> Self {
+        let Vec4 { x, y, z, w } = Vec4::new(self.x, self.y, self.z, self.w).normalize();
+        Self { x, y, z, w }
+    }
+
+    /// The opposite rotation
+    pub fn inverse(&self) -> Self {
+        Self {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+            w: self.w,
+        }
+        .normalize()
+    }
+
+    pub fn rotate(&self, v: Vec3) -> Vec3 {
+        let Quat { x, y, z, w } = self.normalize();
+        let axis = Vec3::new(x, y, z);
+        let t = 2.0 * axis.cross(&v);
+        v + w * t + axis.cross(&t)
+    }
+}
+
+impl Mul for Quat {
+    /// The result rotates by `rhs`, followed by `self`
+    type Output = Quat;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        let a = Vec3::new(self.x, self.y, self.z);
+        let b = Vec3::new(rhs.x, rhs.y, rhs.z);
+        let v = self.w * b + rhs.w * a + a.cross(&b);
+        Quat {
+            x: v.x,
+            y: v.y,
+            z: v.z,
+            w: self.w * rhs.w - a.dot(&b),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use quickcheck::{quickcheck, Arbitrary, Gen, TestResult};
+
     use super::*;
 
     #[test]
@@ -138,8 +871,368 @@ mod tests {
         assert_eq!(2.0 * Vec3::new(1.0, 2.0, 3.0), Vec3::new(2.0, 4.0, 6.0));
     }
 
+    #[test]
+    fn a_cross_product() {
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        assert_eq!(x.cross(&y), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(y.cross(&x), Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn rotations() {
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let quarter = std::f32::consts::FRAC_PI_2;
+        for (rotation, from, to) in [
+            (Mat4::rotation_x(quarter), y, z),
+            (Mat4::rotation_y(quarter), z, x),
+            (Mat4::rotation_z(quarter), x, y),
+        ] {
+            assert!((rotation.transform_vector(from) - to).length() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn points_and_vectors() {
+        let matrix = Mat4::translation(Vec3::new(1.0, 2.0, 3.0)) * Mat4::scale(B);
+        // Points are scaled, then moved, while vectors are only scaled
+        assert_eq!(matrix.transform_point(A), Vec3::new(6.0, 8.0, 31.0));
+        assert_eq!(matrix.transform_vector(A), Vec3::new(5.0, 6.0, 28.0));
+    }
+
+    #[test]
+    fn inverse() {
+        let matrix = Mat4::translation(A) * Mat4::rotation_y(0.5) * Mat4::scale(B);
+        let inverse = matrix.inverse().unwrap();
+        let point = inverse.transform_point(matrix.transform_point(C));
+        assert!(point.approx_eq(&C, 1e-5 * C.length()));
+        assert_eq!(Mat4::IDENTITY.inverse(), Some(Mat4::IDENTITY));
+        assert_eq!(Mat4::scale(Vec3::new(1.0, 0.0, 1.0)).inverse(), None);
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(-A, Vec3::new(-1.0, -2.0, -4.0));
+        assert_eq!(A * 2.0, 2.0 * A);
+        assert_eq!(A * B, Vec3::new(5.0, 6.0, 28.0));
+        assert_eq!(Vec3::new(2.0, 4.0, 8.0) / 2.0, A);
+        let mut v = A;
+        v += B;
+        assert_eq!(v, C);
+        v -= A;
+        assert_eq!(v, B);
+        v *= 2.0;
+        v /= 2.0;
+        v *= A;
+        assert_eq!(v, A * B);
+        v[2] = 0.0;
+        assert_eq!((v[0], v[1], v[2]), (5.0, 6.0, 0.0));
+        assert_eq!([A, B].iter().sum::<Vec3>(), C);
+        assert_eq!(
+            Vec::<Vec3>::new().into_iter().sum::<Vec3>(),
+            Vec3::new(0.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "no component 3")]
+    fn index_out_of_range() {
+        let _ = A[3];
+    }
+
+    #[test]
+    fn component_wise() {
+        assert_eq!(A.min(&Vec3::new(3.0, 0.0, 4.0)), Vec3::new(1.0, 0.0, 4.0));
+        assert_eq!(A.max(&Vec3::new(3.0, 0.0, 4.0)), Vec3::new(3.0, 2.0, 4.0));
+        assert_eq!((-A).abs(), A);
+        assert_eq!(A.lerp(&C, 0.0), A);
+        assert_eq!(A.lerp(&C, 0.5), Vec3::new(3.5, 3.5, 7.5));
+        assert_eq!(A.lerp(&C, 1.0), C);
+        assert_eq!(C.distance(&B), A.length());
+        assert!(A.approx_eq(&Vec3::new(1.05, 1.95, 4.0), 0.1));
+        assert!(!A.approx_eq(&Vec3::new(1.0, 2.0, 4.2), 0.1));
+    }
+
+    #[test]
+    fn normalize() {
+        assert!(Vec3::new(0.0, 3.0, 4.0)
+            .normalize()
+            .approx_eq(&Vec3::new(0.0, 0.6, 0.8), 1e-6));
+        assert_eq!(Vec3::new(0.0, 0.0, 0.0).try_normalize(), None);
+        assert_eq!(Vec3::new(f32::MAX, f32::MAX, 0.0).try_normalize(), None);
+        assert_eq!(
+            Vec3::new(0.0, 0.0, 2.0).try_normalize(),
+            Some(Vec3::new(0.0, 0.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn refraction() {
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        // Light hitting a surface head-on isn't bent
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        assert_eq!(down.refract(&normal, 1.0 / 1.5), Some(down));
+
+        // Entering a denser material bends light towards the normal
+        let diagonal = Vec3::new(0.6, -0.8, 0.0);
+        let refracted = diagonal.refract(&normal, 1.0 / 1.5).unwrap();
+        assert!((refracted.length() - 1.0).abs() < 1e-6);
+        assert!((refracted.x - 0.4).abs() < 1e-6);
+
+        // Leaving it at a shallow enough angle reflects all the light
+        let shallow = Vec3::new(0.8, -0.6, 0.0);
+        assert!(diagonal.refract(&normal, 1.5).is_some());
+        assert_eq!(shallow.refract(&normal, 1.5), None);
+    }
+
+    #[test]
+    fn extend_and_truncate() {
+        assert_eq!(Vec2::new(1.0, 2.0).extend(4.0), A);
+        assert_eq!(A.truncate(), Vec2::new(1.0, 2.0));
+        assert_eq!(A.extend(0.0).truncate(), A);
+        assert_eq!(Vec4::new(2.0, 4.0, 8.0, 2.0).to_point(), Some(A));
+        assert_eq!(A.extend(0.0).to_point(), None);
+    }
+
+    #[test]
+    fn mat3_inverse() {
+        let matrix = Mat4::rotation_x(0.3).linear() * Mat4::scale(B).linear();
+        assert!((matrix.determinant() - 105.0).abs() < 1e-3);
+        let inverse = matrix.inverse().unwrap();
+        assert!((inverse * (matrix * C)).approx_eq(&C, 1e-5 * C.length()));
+        let flat = Mat3::from_columns(A, B, A + B);
+        assert_eq!(flat.inverse(), None);
+    }
+
+    #[test]
+    fn axis_angle() {
+        let quarter = std::f32::consts::FRAC_PI_2;
+        let axis = Vec3::new(1.0, 1.0, 0.0);
+        let matrix = Mat4::rotation(axis, quarter);
+        // Points on the axis stay put, while the rest turn around it
+        assert!(matrix.transform_vector(axis).approx_eq(&axis, 1e-5));
+        let turned = matrix.transform_vector(Vec3::new(1.0, -1.0, 0.0));
+        assert!(turned.approx_eq(&Vec3::new(0.0, 0.0, -2f32.sqrt()), 1e-5));
+        for (matrix, expected) in [
+            (
+                Mat4::rotation(Vec3::new(1.0, 0.0, 0.0), 0.7),
+                Mat4::rotation_x(0.7),
+            ),
+            (
+                Mat4::rotation(Vec3::new(0.0, 2.0, 0.0), 0.7),
+                Mat4::rotation_y(0.7),
+            ),
+            (
+                Mat4::rotation(Vec3::new(0.0, 0.0, 1.0), 0.7),
+                Mat4::rotation_z(0.7),
+            ),
+        ] {
+            assert!(matrix
+                .transform_vector(C)
+                .approx_eq(&expected.transform_vector(C), 1e-5 * C.length()));
+        }
+    }
+
+    #[test]
+    fn look_at() {
+        let position = Vec3::new(1.0, 2.0, 3.0);
+        let matrix = Mat4::look_at(
+            position,
+            Vec3::new(1.0, 2.0, -7.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        )
+        .unwrap();
+        // Turned around to face along -z, so the camera's right is -x
+        assert_eq!(matrix.transform_point(Vec3::new(0.0, 0.0, 0.0)), position);
+        assert!(matrix
+            .transform_vector(Vec3::new(0.0, 0.0, 1.0))
+            .approx_eq(&Vec3::new(0.0, 0.0, -1.0), 1e-5));
+        assert!(matrix
+            .transform_vector(Vec3::new(1.0, 0.0, 0.0))
+            .approx_eq(&Vec3::new(-1.0, 0.0, 0.0), 1e-5));
+        assert!(matrix
+            .transform_vector(Vec3::new(0.0, 1.0, 0.0))
+            .approx_eq(&Vec3::new(0.0, 1.0, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn look_at_needs_a_viewing_direction() {
+        let position = Vec3::new(1.0, 2.0, 3.0);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert_eq!(Mat4::look_at(position, position, up), None);
+        assert_eq!(Mat4::look_at(position, position + 2.0 * up, up), None);
+        assert_eq!(Mat4::look_at(position, position - up, up), None);
+    }
+
+    #[test]
+    fn perspective() {
+        let fov = 90f32.to_radians();
+        let matrix = Mat4::perspective(fov, 2.0, 1.0, 10.0);
+        let project = |point: Vec3| (matrix * point.extend(1.0)).to_point().unwrap();
+        // The top-right corner of the view, halfway to the far plane
+        let corner = project(Vec3::new(10.0, 5.0, 5.0));
+        assert!(corner
+            .truncate()
+            .extend(0.0)
+            .approx_eq(&Vec3::new(1.0, 1.0, 0.0), 1e-5));
+        assert!(project(Vec3::new(0.0, 0.0, 1.0)).z.abs() < 1e-6);
+        assert!((project(Vec3::new(0.0, 0.0, 10.0)).z - 1.0).abs() < 1e-6);
+        // Undoing the projection gives the direction seen through a point of the image
+        let inverse = matrix.inverse().unwrap();
+        let seen = (inverse * Vec4::new(-1.0, 0.0, 0.0, 1.0))
+            .to_point()
+            .unwrap();
+        assert!(((1.0 / seen.z) * seen).approx_eq(&Vec3::new(-2.0, 0.0, 1.0), 1e-5));
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let matrix = Mat4::translation(A) * Mat4::rotation_y(0.5);
+        let json = serde_json::to_string(&matrix).unwrap();
+        assert_eq!(serde_json::from_str::<Mat4>(&json).unwrap(), matrix);
+        let q = Quat::from_axis_angle(B, 0.5);
+        let ron = ron::to_string(&q).unwrap();
+        assert_eq!(ron::from_str::<Quat>(&ron).unwrap(), q);
+    }
+
     #[test]
     fn from_str() {
         assert_eq!("1,0,2".parse(), Ok(Vec3::new(1.0, 0.0, 2.0)));
     }
+
+    /// A vector with components between -100 and 100, so that sums and
+    /// products stay well within the range of an f32
+    #[derive(Debug, Copy, Clone)]
+    struct Small(Vec3);
+
+    /// A vector of length 1
+    #[derive(Debug, Copy, Clone)]
+    struct Unit(Vec3);
+
+    fn component(g: &mut Gen) -> f32 {
+        (i32::arbitrary(g) % 100_000) as f32 / 1000.0
+    }
+
+    impl Arbitrary for Small {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Small(Vec3::new(component(g), component(g), component(g)))
+        }
+    }
+
+    impl Arbitrary for Unit {
+        fn arbitrary(g: &mut Gen) -> Self {
+            loop {
+                let Small(v) = Small::arbitrary(g);
+                // Very short vectors can't be normalized accurately
+                if v.length() > 1e-3 {
+                    return Unit((1.0 / v.length()) * v);
+                }
+            }
+        }
+    }
+
+    quickcheck! {
+        fn addition_commutes(a: Small, b: Small) -> bool {
+            a.0 + b.0 == b.0 + a.0
+        }
+
+        fn addition_is_associative(a: Small, b: Small, c: Small) -> bool {
+            let scale = a.0.length() + b.0.length() + c.0.length();
+            ((a.0 + b.0) + c.0).approx_eq(&(a.0 + (b.0 + c.0)), 1e-5 * scale.max(1.0))
+        }
+
+        fn dot_is_symmetric(a: Small, b: Small) -> bool {
+            a.0.dot(&b.0) == b.0.dot(&a.0)
+        }
+
+        fn cross_is_perpendicular(a: Unit, b: Unit) -> bool {
+            let cross = a.0.cross(&b.0);
+            cross.dot(&a.0).abs() <= 1e-5 && cross.dot(&b.0).abs() <= 1e-5
+        }
+
+        fn reflection_preserves_length(v: Small, normal: Unit) -> bool {
+            let reflected = v.0.reflection(&normal.0);
+            (reflected.length() - v.0.length()).abs() <= 1e-5 * v.0.length().max(1.0)
+        }
+
+        fn reflecting_twice_is_identity(v: Small, normal: Unit) -> bool {
+            let twice = v.0.reflection(&normal.0).reflection(&normal.0);
+            twice.approx_eq(&v.0, 1e-5 * v.0.length().max(1.0))
+        }
+
+        fn inverse_undoes_transforms(translation: Small, angles: Small, point: Small) -> bool {
+            let matrix = Mat4::translation(translation.0)
+                * Mat4::rotation_z(angles.0.z)
+                * Mat4::rotation_y(angles.0.y)
+                * Mat4::rotation_x(angles.0.x);
+            let inverse = matrix.inverse().unwrap();
+            let moved = matrix.transform_point(point.0);
+            let tolerance = 1e-5 * (10.0 * moved.length()).max(1.0);
+            inverse.transform_point(moved).approx_eq(&point.0, tolerance)
+        }
+
+        fn quaternions_match_matrices(axis: Unit, angle: Small, v: Small) -> bool {
+            let q = Quat::from_axis_angle(axis.0, angle.0.x);
+            let matrix = Mat3::from(q);
+            let tolerance = 1e-5 * (10.0 * v.0.length()).max(1.0);
+            q.rotate(v.0).approx_eq(&(matrix * v.0), tolerance)
+                && q.inverse().rotate(q.rotate(v.0)).approx_eq(&v.0, tolerance)
+                && (matrix.determinant() - 1.0).abs() < 1e-4
+        }
+
+        fn quaternions_compose_like_matrices(a: Unit, b: Unit, angles: Small, v: Small) -> bool {
+            let p = Quat::from_axis_angle(a.0, angles.0.x);
+            let q = Quat::from_axis_angle(b.0, angles.0.y);
+            let matrix = Mat3::from(p) * Mat3::from(q);
+            let tolerance = 1e-5 * (10.0 * v.0.length()).max(1.0);
+            (p * q).rotate(v.0).approx_eq(&(matrix * v.0), tolerance)
+        }
+
+        fn mat3_inverse_matches_mat4(translation: Small, angles: Small, scale: Unit) -> TestResult {
+            if scale.0.x.abs() < 0.1 || scale.0.y.abs() < 0.1 || scale.0.z.abs() < 0.1 {
+                return TestResult::discard();
+            }
+            let matrix = Mat4::translation(translation.0)
+                * Mat4::rotation_y(angles.0.y)
+                * Mat4::rotation_x(angles.0.x)
+                * Mat4::scale(scale.0);
+            let expected = matrix.inverse().unwrap().linear();
+            let actual = matrix.linear().inverse().unwrap();
+            let close = expected
+                .rows
+                .iter()
+                .flatten()
+                .zip(actual.rows.iter().flatten())
+                .all(|(a, b)| (a - b).abs() <= 1e-3 * a.abs().max(1.0));
+            TestResult::from_bool(close)
+        }
+
+        fn normalized_vectors_have_unit_length(v: Small) -> TestResult {
+            match v.0.try_normalize() {
+                Some(unit) => TestResult::from_bool((unit.length() - 1.0).abs() <= 1e-5),
+                None => TestResult::from_bool(v.0.length() == 0.0),
+            }
+        }
+
+        fn refraction_preserves_length(v: Unit, normal: Unit, eta: u8) -> TestResult {
+            if v.0.dot(&normal.0) >= 0.0 {
+                return TestResult::discard();
+            }
+            let eta = 0.5 + eta as f32 / 128.0;
+            TestResult::from_bool(
+                v.0.refract(&normal.0, eta)
+                    .is_none_or(|refracted| (refracted.length() - 1.0).abs() <= 1e-4),
+            )
+        }
+
+        fn display_round_trips(x: f32, y: f32, z: f32) -> TestResult {
+            if x.is_nan() || y.is_nan() || z.is_nan() {
+                return TestResult::discard();
+            }
+            let v = Vec3::new(x, y, z);
+            TestResult::from_bool(v.to_string().parse() == Ok(v))
+        }
+    }
 }
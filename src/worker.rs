@@ -0,0 +1,277 @@
+use std::{
+    collections::VecDeque, net::SocketAddr, path::PathBuf, str::FromStr, thread, time::Duration,
+};
+
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use structopt::StructOpt;
+
+use rust_workshop::bvh::Bvh;
+use rust_workshop::client::{unexpected, Connection};
+use rust_workshop::geom::Ray;
+use rust_workshop::protocol::{
+    Capabilities, FrameLimits, Framing, Outcome, ProtocolError, Request, Response, Scene,
+    FEATURE_MATERIALS, FEATURE_MESHES, FEATURE_MESH_CACHE, FEATURE_SHAPES, FEATURE_TRANSFORMS,
+};
+use rust_workshop::recording::Recorder;
+use rust_workshop::shading::{compute_result, ShadingOpt};
+
+use crate::scene_file::SceneFile;
+
+#[derive(StructOpt)]
+pub struct WorkOpt {
+    addr: SocketAddr,
+    #[structopt(flatten)]
+    shading: ShadingOpt,
+    #[structopt(long, default_value = "Unnamed")]
+    name: String,
+    /// Number of batches of rays to reserve ahead of the one being traced, so
+    /// that they're already on their way while it is. 0 waits for the response to
+    /// each request before sending the next.
+    #[structopt(long, default_value = "2")]
+    depth: usize,
+    /// Ask the server not to compress messages, which saves CPU time on fast networks
+    #[structopt(long)]
+    no_compression: bool,
+    /// Ask the server to encode messages with this codec: "plain", "snappy",
+    /// "lz4", "zstd" or "json". Servers which don't support it use snappy instead,
+    /// or plain with --no-compression.
+    #[structopt(long)]
+    codec: Option<Framing>,
+    /// Ask the server not to hand out more than this many rays at once
+    #[structopt(long)]
+    max_batch_size: Option<u32>,
+    #[structopt(flatten)]
+    limits: FrameLimits,
+    /// Give up on a request if the server hasn't answered within this many
+    /// milliseconds, and reconnect. Waits forever if omitted.
+    #[structopt(long)]
+    timeout: Option<u64>,
+    /// Save each new scene received from the server into this directory, so
+    /// that it can be rendered locally later. Its meshes are saved alongside
+    /// it as OBJ files.
+    #[structopt(long)]
+    save_scenes: Option<PathBuf>,
+    /// Record every message exchanged with the server into this file, along
+    /// with the shading options, so that the session can be checked later with
+    /// the replay subcommand
+    #[structopt(long)]
+    record: Option<PathBuf>,
+    /// How long to wait before reconnecting after losing the connection to the
+    /// server, in milliseconds. Doubles after each failed attempt.
+    #[structopt(long, default_value = "250")]
+    reconnect_delay: u64,
+    /// Longest to wait between attempts to reconnect, in milliseconds
+    #[structopt(long, default_value = "30000")]
+    max_reconnect_delay: u64,
+    /// Give up after failing to connect this many times in a row. Retries forever if omitted.
+    #[structopt(long)]
+    max_retries: Option<u32>,
+    /// What to do with results the server hadn't acknowledged when the connection was
+    /// lost: "resubmit" them if the server hands out the same rays again, or "discard" them
+    #[structopt(long, default_value = "resubmit")]
+    in_flight: InFlightPolicy,
+}
+
+/// What to do with traced batches which the server hadn't acknowledged when
+/// the connection to it was lost
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum InFlightPolicy {
+    /// Keep the results, and submit them if the server hands out the same rays again
+    Resubmit,
+    /// Throw the results away
+    Discard,
+}
+
+impl FromStr for InFlightPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resubmit" => Ok(Self::Resubmit),
+            "discard" => Ok(Self::Discard),
+            _ => Err(format!("Expected resubmit or discard, but got {:?}", s)),
+        }
+    }
+}
+
+/// A batch of rays which has been traced, along with the results
+struct TracedBatch {
+    rays: Vec<Ray>,
+    scene: Scene,
+    results: Vec<Outcome>,
+}
+
+/// Progress which is kept when reconnecting to the server
+#[derive(Default)]
+struct WorkState {
+    last_frame: Option<u64>,
+    /// Batches submitted on the current connection which the server hasn't
+    /// acknowledged yet, oldest first
+    unacknowledged: VecDeque<TracedBatch>,
+    /// Batches which were never acknowledged before a previous connection was lost
+    carried_over: Vec<TracedBatch>,
+}
+
+/// Check whether an error was caused by the connection to the server failing,
+/// rather than by the server sending something we don't understand.
+fn is_connection_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ProtocolError>()
+        .is_some_and(ProtocolError::is_disconnect)
+}
+
+/// Connect to the server and tell it who we are. Failed attempts are retried
+/// with exponential backoff.
+fn connect(opt: &WorkOpt, recorder: Option<&Recorder>) -> Result<Connection, ProtocolError> {
+    let mut delay = Duration::from_millis(opt.reconnect_delay);
+    let mut retries = 0;
+    loop {
+        let capabilities = Capabilities {
+            compression: !opt.no_compression && opt.codec != Some(Framing::Plain),
+            max_batch_size: opt.max_batch_size,
+            features: opt.codec.map_or(0, Framing::feature)
+                | FEATURE_SHAPES
+                | FEATURE_MESHES
+                | FEATURE_TRANSFORMS
+                | FEATURE_MATERIALS
+                | FEATURE_MESH_CACHE,
+        };
+        let timeout = opt.timeout.map(Duration::from_millis);
+        let connection = Connection::connect(opt.addr, &capabilities, opt.limits, timeout)
+            .and_then(|mut connection| {
+                if let Some(recorder) = recorder {
+                    connection.record(recorder.clone())?;
+                }
+                connection.set_name(opt.name.clone())?;
+                Ok(connection)
+            });
+        match connection {
+            Ok(connection) => return Ok(connection),
+            Err(e) if e.is_disconnect() && opt.max_retries.is_none_or(|max| retries < max) => {
+                eprintln!(
+                    "Failed to connect to {}: {}. Retrying in {:?}",
+                    opt.addr, e, delay
+                );
+                thread::sleep(delay);
+                delay = (2 * delay).min(Duration::from_millis(opt.max_reconnect_delay));
+                retries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn work(opt: WorkOpt) -> anyhow::Result<()> {
+    let mut state = WorkState::default();
+    let recorder = opt.record.as_deref().map(Recorder::create).transpose()?;
+    if let Some(recorder) = &recorder {
+        recorder.shading(&opt.shading)?;
+    }
+    loop {
+        let mut connection = connect(&opt, recorder.as_ref())?;
+        let e = match trace_batches(&mut connection, &opt, &mut state) {
+            Ok(()) => continue,
+            Err(e) if is_connection_error(&e) => e,
+            Err(e) => return Err(e),
+        };
+        eprintln!("Lost connection to {}: {}", opt.addr, e);
+
+        let unacknowledged = state.unacknowledged.drain(..);
+        match opt.in_flight {
+            InFlightPolicy::Resubmit => state.carried_over.extend(unacknowledged),
+            InFlightPolicy::Discard => drop(unacknowledged),
+        }
+    }
+}
+
+/// Trace batches of rays from the server until something goes wrong
+fn trace_batches(
+    connection: &mut Connection,
+    opt: &WorkOpt,
+    state: &mut WorkState,
+) -> anyhow::Result<()> {
+    // Number of batches requested from the server which haven't arrived yet
+    let mut reserved = 0;
+    // The spheres only change from one frame to the next, so the BVH over them
+    // is built once per frame rather than for every batch
+    let mut bvh = Bvh::default();
+    let mut bvh_frame = None;
+    loop {
+        // Pull some rays and a scene from the server
+        let (rays, scene) = if opt.depth == 0 {
+            connection.reserve_rays()?
+        } else {
+            // Keep the pipeline full, so that more batches are on their way while we
+            // trace this one
+            while reserved <= opt.depth {
+                connection.send(&Request::ReserveRays)?;
+                reserved += 1;
+            }
+
+            // Responses arrive in the order requests were sent, so acknowledgements
+            // of earlier submissions come first.
+            let batch = loop {
+                match connection.receive()? {
+                    Response::ReserveRays(rays, scene) => break (rays, scene),
+                    Response::SubmitResults => {
+                        state.unacknowledged.pop_front();
+                    }
+                    response => return Err(unexpected("ReserveRays", &response).into()),
+                }
+            };
+            reserved -= 1;
+            batch
+        };
+
+        if let Some(dir) = &opt.save_scenes {
+            if state.last_frame != Some(scene.frame) {
+                let path = dir.join(format!("scene-{:05}.ron", scene.frame));
+                SceneFile::from_scene(&scene).save_with_meshes(&path)?;
+                state.last_frame = Some(scene.frame);
+            }
+        }
+
+        // Batches carried over from an earlier connection are only useful until
+        // the server moves on to another frame.
+        state
+            .carried_over
+            .retain(|batch| batch.scene.frame == scene.frame);
+        let carried_over = state
+            .carried_over
+            .iter()
+            .position(|batch| batch.rays == rays && batch.scene == scene);
+
+        let results = match carried_over {
+            // We traced these rays before losing the connection, so don't do it again
+            Some(i) => state.carried_over.swap_remove(i).results,
+            None => {
+                if bvh_frame != Some(scene.frame) {
+                    bvh = Bvh::new(&scene.spheres);
+                    bvh_frame = Some(scene.frame);
+                }
+                // Compute whether each ray intersects the scene
+                // Use rayon to checks the rays in parallel, sharing one BVH between them.
+                rays.par_iter()
+                    .map(|&ray| {
+                        compute_result(ray, &scene, &bvh, &opt.shading, opt.shading.bounces)
+                    })
+                    .collect()
+            }
+        };
+
+        // Submit the results, keeping hold of them until the server acknowledges them
+        let submitted = results.clone();
+        state.unacknowledged.push_back(TracedBatch {
+            rays,
+            scene,
+            results,
+        });
+        if opt.depth == 0 {
+            connection.submit_results(submitted)?;
+            state.unacknowledged.pop_front();
+        } else {
+            // There's no need to wait for the acknowledgement before starting on the
+            // next batch, since it arrives before the rays we've already reserved.
+            connection.send(&Request::SubmitResults(submitted))?;
+        }
+    }
+}